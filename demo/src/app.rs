@@ -14,6 +14,8 @@ use log::info;
 use mcaux_indicators::IndicatorController;
 use momentary::MomentaryController;
 
+use crate::log_plot::LogPlot;
+
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(default)] // if we add new fields, give them default values when deserializing old state
 pub struct TemplateApp {
@@ -41,6 +43,10 @@ pub struct TemplateApp {
 
     #[serde(skip)]
     indicators: IndicatorController, // duty cycles for all indicators
+
+    show_log_plot: bool,
+    #[serde(skip)]
+    log_plot: LogPlot,
 }
 
 impl Default for TemplateApp {
@@ -56,6 +62,8 @@ impl Default for TemplateApp {
             rgb_duty: [90, 100, 110],
             controller: Default::default(),
             indicators: Default::default(),
+            show_log_plot: false,
+            log_plot: Default::default(),
         }
     }
 }
@@ -140,10 +148,22 @@ impl eframe::App for TemplateApp {
                     ui.add_space(16.0);
                 }
 
+                ui.menu_button("View", |ui| {
+                    ui.checkbox(&mut self.show_log_plot, "Datalogger");
+                });
+                ui.add_space(16.0);
+
                 egui::widgets::global_theme_preference_buttons(ui);
             });
         });
 
+        if self.show_log_plot {
+            egui::SidePanel::right("log_plot_panel")
+                .resizable(true)
+                .default_width(480.0)
+                .show(ctx, |ui| self.log_plot.ui(ui));
+        }
+
         egui::CentralPanel::default().show(ctx, |ui| {
             // The central panel the region left after adding TopPanel's and SidePanel's
             ui.heading("MCAux CT");
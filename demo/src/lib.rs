@@ -1,4 +1,5 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
 mod app;
+mod log_plot;
 pub use app::TemplateApp;
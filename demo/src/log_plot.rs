@@ -0,0 +1,351 @@
+// demo/src/log_plot.rs
+
+//! Datalogger review: load a log, pick a ride, plot its channels over time.
+
+use std::collections::BTreeMap;
+use std::ops::{Range, RangeInclusive};
+
+use egui_plot::{AxisHints, HPlacement, Legend, Line, Plot, PlotPoint, VLine};
+
+use mcaux_datalogger::channel::RPM;
+use mcaux_datalogger::compress::COMPRESSED_MAGIC;
+use mcaux_datalogger::event::is_event;
+use mcaux_datalogger::image::IMAGE_MAGIC;
+use mcaux_datalogger::record::{Record, Records, encode_stream};
+use mcaux_datalogger::retention::Stat;
+use mcaux_datalogger::session::split_sessions;
+use mcaux_datalogger::sim::{self, RideConfig};
+use mcaux_datalogger::storage::log_file_name;
+use mcaux_datalogger::time::{TimeBase, format_utc, split_boots};
+use mcaux_datalogger::{
+    Aggregation, ChannelId, ChannelRegistry, MemFs, QueryItem, RotatingLog, RotationPolicy,
+    decode_image, decompress,
+};
+
+/// RPM shares the plot with temperatures: drawn at 1/100 scale against a
+/// right-hand axis that reads in RPM.
+const RPM_SCALE: f64 = 100.0;
+
+/// Longer rides are plotted as bucket means, about this many per channel.
+const MAX_POINTS: u32 = 2_000;
+
+struct Session {
+    /// Log files and ms since boot it spans
+    files: RangeInclusive<u32>,
+    span: Range<u32>,
+    label: String,
+    time_base: TimeBase,
+}
+
+struct Series {
+    name: String,
+    points: Vec<PlotPoint>,
+}
+
+#[derive(Default)]
+pub struct LogPlot {
+    #[cfg(not(target_arch = "wasm32"))]
+    path: String,
+    status: String,
+    log: Option<RotatingLog<MemFs>>,
+    sessions: Vec<Session>,
+    selected: usize,
+    // for the selected session, rebuilt when it changes
+    series: Vec<Series>,
+    events: Vec<f64>,
+    reset_view: bool,
+}
+
+/// A log image, or a plain or compressed log file split into one file
+/// per boot.
+fn decode(bytes: &[u8]) -> Result<MemFs, String> {
+    if bytes.starts_with(&IMAGE_MAGIC) {
+        decode_image(bytes).map_err(|e| e.to_string())
+    } else if bytes.starts_with(&COMPRESSED_MAGIC) {
+        let records = decompress(bytes).map_err(|e| e.to_string())?;
+        Ok(per_boot(&records))
+    } else {
+        let records: Vec<Record> = Records::new(bytes).map_err(|e| e.to_string())?.collect();
+        Ok(per_boot(&records))
+    }
+}
+
+/// Each boot in a file of its own, as the bike writes them. Rides are
+/// plotted by file and time since boot, and every boot's clock starts
+/// at zero, so sharing a file would mix rides from different boots.
+fn per_boot(records: &[Record]) -> MemFs {
+    MemFs::from_files(
+        split_boots(records)
+            .into_iter()
+            .enumerate()
+            .map(|(i, boot)| (log_file_name(i as u32), encode_stream(&records[boot]))),
+    )
+}
+
+fn seconds(ms: u32) -> String {
+    format!("{}.{:03}", ms / 1000, ms % 1000)
+}
+
+impl LogPlot {
+    fn load(&mut self, name: &str, bytes: &[u8]) {
+        if let Err(e) = decode(bytes).and_then(|fs| self.show(name, fs)) {
+            self.status = format!("{name}: {e}");
+        }
+    }
+
+    /// An hour with the 280 W stator, starting now.
+    fn simulate(&mut self) {
+        let now = web_time::SystemTime::now()
+            .duration_since(web_time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+        let config = RideConfig {
+            start_utc_ms: Some(now),
+            ..Default::default()
+        };
+        let stream = encode_stream(&sim::ride(config, 60 * 60 * 1000));
+        let fs = MemFs::from_files([(log_file_name(0), stream)]);
+        if let Err(e) = self.show("simulated ride", fs) {
+            self.status = e;
+        }
+    }
+
+    /// Find the rides in a log; their records are queried when picked.
+    fn show(&mut self, name: &str, fs: MemFs) -> Result<(), String> {
+        let mut log =
+            RotatingLog::open(fs, RotationPolicy::default()).map_err(|e| e.to_string())?;
+        let mut files = Vec::new();
+        let mut records = Vec::new();
+        for item in log
+            .query(&[], 0..u32::MAX, Aggregation::None)
+            .map_err(|e| e.to_string())?
+        {
+            if let QueryItem::Record { file, record } = item.map_err(|e| e.to_string())? {
+                files.push(file);
+                records.push(record);
+            }
+        }
+        let boots = split_boots(&records);
+        self.sessions = split_sessions(&records)
+            .into_iter()
+            .enumerate()
+            .map(|(i, range)| {
+                let boot = boots
+                    .iter()
+                    .find(|b| b.contains(&range.start))
+                    .cloned()
+                    .unwrap_or_default();
+                let time_base = TimeBase::from_records(&records[boot]);
+                let last = range.end - 1;
+                let (start, end) = (records[range.start].timestamp, records[last].timestamp);
+                let when = match time_base.to_utc(start) {
+                    Some(utc) => format_utc(utc)[..16].replace('T', " "),
+                    None => format!("{} s after boot", seconds(start)),
+                };
+                Session {
+                    label: format!("Ride {}: {} min, {when}", i + 1, (end - start) / 60_000),
+                    files: files[range.start]..=files[last],
+                    span: start..end + 1,
+                    time_base,
+                }
+            })
+            .collect();
+        self.status = format!(
+            "{name}: {} records, {} rides",
+            records.len(),
+            self.sessions.len()
+        );
+        self.log = Some(log);
+        self.select(0);
+        Ok(())
+    }
+
+    fn select(&mut self, session: usize) {
+        self.selected = session;
+        self.series.clear();
+        self.events.clear();
+        self.reset_view = true;
+        let (Some(session), Some(log)) = (self.sessions.get(session), self.log.as_mut()) else {
+            return;
+        };
+        // long rides as bucket means, so there's no more to draw than pixels
+        let width = (session.span.len() as u32).div_ceil(MAX_POINTS);
+        let aggregation = if width > 1_000 {
+            Aggregation::Buckets(width)
+        } else {
+            Aggregation::None
+        };
+        let query = match log.query(&[], session.span.clone(), aggregation) {
+            Ok(q) => q.in_files(session.files.clone()),
+            Err(e) => {
+                self.status = e.to_string();
+                return;
+            }
+        };
+        let mut points: BTreeMap<ChannelId, Vec<PlotPoint>> = BTreeMap::new();
+        let registry = ChannelRegistry::standard();
+        for item in query {
+            // a bucket plots at its middle, at its mean
+            let (channel, t, value) = match item {
+                Ok(QueryItem::Record { record: r, .. }) if is_event(r.channel) => {
+                    self.events.push(r.timestamp as f64 / 1000.0);
+                    continue;
+                }
+                Ok(QueryItem::Record { record: r, .. }) => match Stat::parse(r.channel) {
+                    // downsampled minutes plot at their means
+                    Some((source, Stat::Mean)) => (source, r.timestamp as f64, r.value as f64),
+                    Some(_) => continue,
+                    None => (r.channel, r.timestamp as f64, r.value as f64),
+                },
+                Ok(QueryItem::Bucket { bucket: b, .. }) => {
+                    (b.channel, b.start_ms as f64 + width as f64 / 2.0, b.mean)
+                }
+                Err(e) => {
+                    self.status = e.to_string();
+                    break;
+                }
+            };
+            let Some(c) = registry.get(channel) else {
+                continue;
+            };
+            // ignition just bounds the rides; no need to draw it
+            if c.unit.is_empty() {
+                continue;
+            }
+            let scale = if channel == RPM { RPM_SCALE } else { 1.0 };
+            let point = PlotPoint::new(
+                t / 1000.0,
+                (value * c.scale as f64 + c.offset as f64) / scale,
+            );
+            points.entry(channel).or_default().push(point);
+        }
+        // registry order, so colours stay put from ride to ride
+        for c in registry.iter() {
+            if let Some(p) = points.remove(&c.id) {
+                self.series.push(Series {
+                    name: format!("{} ({})", c.name, c.unit),
+                    points: p,
+                });
+            }
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Datalogger");
+        ui.label("Drop a log image, .mcl or .mcz file here.");
+
+        ui.horizontal(|ui| {
+            #[cfg(not(target_arch = "wasm32"))]
+            ui.text_edit_singleline(&mut self.path);
+            #[cfg(not(target_arch = "wasm32"))]
+            if ui.button("Load").clicked() {
+                let path = self.path.clone();
+                match std::fs::read(&path) {
+                    Ok(bytes) => self.load(&path, &bytes),
+                    Err(e) => self.status = format!("{path}: {e}"),
+                }
+            }
+            if ui.button("Simulate ride").clicked() {
+                self.simulate();
+            }
+        });
+
+        // on the web a dropped file arrives as bytes; natively, as a path
+        let dropped = ui.ctx().input(|i| i.raw.dropped_files.clone());
+        for file in dropped {
+            if let Some(bytes) = &file.bytes {
+                self.load(&file.name, bytes);
+            } else if let Some(path) = &file.path {
+                let name = path.display().to_string();
+                match std::fs::read(path) {
+                    Ok(bytes) => self.load(&name, &bytes),
+                    Err(e) => self.status = format!("{name}: {e}"),
+                }
+            }
+        }
+
+        if !self.status.is_empty() {
+            ui.label(&self.status);
+        }
+        if self.sessions.is_empty() {
+            return;
+        }
+
+        let mut selected = self.selected;
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_salt("log_plot_session")
+                .selected_text(&self.sessions[selected].label)
+                .show_ui(ui, |ui| {
+                    for (i, s) in self.sessions.iter().enumerate() {
+                        ui.selectable_value(&mut selected, i, &s.label);
+                    }
+                });
+            if ui.button("Reset view").clicked() {
+                self.reset_view = true;
+            }
+        });
+        if selected != self.selected {
+            self.select(selected);
+        }
+
+        let time_base = &self.sessions[self.selected].time_base;
+        let mut plot = Plot::new("log_plot")
+            .legend(Legend::default())
+            .custom_y_axes(vec![
+                AxisHints::new_y().label("°C, V"),
+                AxisHints::new_y()
+                    .label("rpm")
+                    .placement(HPlacement::Right)
+                    .formatter(|mark, _| format!("{:.0}", mark.value * RPM_SCALE)),
+            ])
+            // time of day once the bike knew it, else seconds since boot
+            .x_axis_formatter(move |mark, _| {
+                match time_base.to_utc((mark.value.max(0.0) * 1000.0) as u32) {
+                    Some(utc) => format_utc(utc)[11..19].to_owned(),
+                    None => format!("{:.0} s", mark.value),
+                }
+            });
+        if std::mem::take(&mut self.reset_view) {
+            plot = plot.reset();
+        }
+        plot.show(ui, |plot_ui| {
+            for s in &self.series {
+                plot_ui.line(Line::new(s.name.clone(), &s.points[..]));
+            }
+            for &t in &self.events {
+                plot_ui.vline(VLine::new("events", t));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mcaux_datalogger::channel::IGNITION;
+
+    /// Two boots in one stream, with rides at the same ticks: the first
+    /// sampled every second, the second every two.
+    fn two_boots() -> Vec<u8> {
+        let mut records = Vec::new();
+        for step in [1_000, 2_000] {
+            records.push(Record::new(0, IGNITION, 1));
+            for t in (step..=20_000).step_by(step as usize) {
+                records.push(Record::new(t, RPM, 3_000));
+            }
+            records.push(Record::new(21_000, IGNITION, 0));
+        }
+        encode_stream(&records)
+    }
+
+    #[test]
+    fn rides_keep_to_their_boot() {
+        let mut plot = LogPlot::default();
+        plot.load("two.mcl", &two_boots());
+        assert_eq!(plot.sessions.len(), 2, "{}", plot.status);
+        for (ride, points) in [(0, 20), (1, 10)] {
+            plot.select(ride);
+            assert_eq!(plot.series.len(), 1);
+            assert_eq!(plot.series[0].points.len(), points);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+// mcaux-charging/src/lib.rs
+
+//! Is the charging system doing its job? Battery voltage sorted into not
+//! charging, charging, overcharging and low. An aftermarket stator puts
+//! out more than the stock regulator was sized for, and a shunt regulator
+//! that can't keep up lets the voltage climb until the battery boils.
+//!
+//! A state has to hold for a while, and the voltage has to come back past
+//! the threshold by some hysteresis, before the classification changes.
+//! Otherwise a starter-motor sag would read as a low battery and a reading
+//! hovering at a threshold would chatter.
+//!
+//! Plain logic on readings from anywhere: the datalogger reads and logs
+//! the voltage, and the indicators show the states worth a warning.
+
+/// Where the battery voltage sits. The discriminants are what the
+/// datalogger logs on its `charge_state` channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChargeState {
+    /// Between low and charging: the battery alone is holding the
+    /// voltage up. Normal with the engine off or at idle.
+    NotCharging = 0,
+    Charging = 1,
+    Overcharging = 2,
+    Low = 3,
+}
+
+impl ChargeState {
+    pub fn from_raw(raw: i32) -> Option<ChargeState> {
+        Some(match raw {
+            0 => ChargeState::NotCharging,
+            1 => ChargeState::Charging,
+            2 => ChargeState::Overcharging,
+            3 => ChargeState::Low,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ChargeState::NotCharging => "not charging",
+            ChargeState::Charging => "charging",
+            ChargeState::Overcharging => "overcharging",
+            ChargeState::Low => "low battery",
+        }
+    }
+
+    /// Position in voltage order, low to overcharging.
+    fn band(self) -> usize {
+        match self {
+            ChargeState::Low => 0,
+            ChargeState::NotCharging => 1,
+            ChargeState::Charging => 2,
+            ChargeState::Overcharging => 3,
+        }
+    }
+}
+
+/// Thresholds for a 12 V lead-acid battery, by default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChargeLimits {
+    /// Below this the battery is low.
+    pub low_v: f32,
+    /// At or above this something is charging it.
+    pub charging_v: f32,
+    /// At or above this it's charging too hard.
+    pub overcharge_v: f32,
+    /// How far back past a threshold the voltage must come to leave a
+    /// state.
+    pub hysteresis_v: f32,
+    /// How long a new state must hold before it's believed.
+    pub hold_ms: u32,
+    /// Not charging is only worth an alert above this engine speed; at
+    /// idle the stator can't keep up with the load.
+    pub charging_rpm: f32,
+}
+
+impl Default for ChargeLimits {
+    fn default() -> Self {
+        ChargeLimits {
+            low_v: 12.0,
+            charging_v: 13.2,
+            overcharge_v: 14.8,
+            hysteresis_v: 0.15,
+            hold_ms: 3_000,
+            charging_rpm: 3_000.0,
+        }
+    }
+}
+
+/// The classification alone, for voltages from anywhere.
+#[derive(Clone, Debug)]
+pub struct ChargeClassifier {
+    limits: ChargeLimits,
+    state: ChargeState,
+    /// A different state the voltage has been in since the given time
+    pending: Option<(ChargeState, u32)>,
+    rpm: f32,
+}
+
+impl ChargeClassifier {
+    pub fn new(limits: ChargeLimits) -> Self {
+        if !(limits.low_v < limits.charging_v && limits.charging_v < limits.overcharge_v) {
+            panic!("Charge thresholds must rise from low to charging to overcharging");
+        }
+        ChargeClassifier {
+            limits,
+            state: ChargeState::NotCharging,
+            pending: None,
+            rpm: 0.0,
+        }
+    }
+
+    /// Which state `volts` reads as, from the current one: thresholds on
+    /// the way out of it are pushed back by the hysteresis.
+    fn classify(&self, volts: f32) -> ChargeState {
+        let l = &self.limits;
+        let current = self.state.band();
+        let passed = [l.low_v, l.charging_v, l.overcharge_v]
+            .iter()
+            .enumerate()
+            .filter(|&(i, &threshold)| {
+                let h = if i < current { -1.0 } else { 1.0 } * l.hysteresis_v;
+                volts >= threshold + h
+            })
+            .count();
+        [
+            ChargeState::Low,
+            ChargeState::NotCharging,
+            ChargeState::Charging,
+            ChargeState::Overcharging,
+        ][passed]
+    }
+
+    /// A battery voltage reading and the engine speed at `now` ms. Returns
+    /// the state, changed or not.
+    pub fn update(&mut self, now: u32, volts: f32, rpm: f32) -> ChargeState {
+        self.rpm = rpm;
+        let reading = self.classify(volts);
+        if reading == self.state {
+            self.pending = None;
+            return self.state;
+        }
+        let since = match self.pending {
+            Some((state, since)) if state == reading => since,
+            _ => now,
+        };
+        if now.saturating_sub(since) >= self.limits.hold_ms {
+            self.state = reading;
+            self.pending = None;
+        } else {
+            self.pending = Some((reading, since));
+        }
+        self.state
+    }
+
+    pub fn state(&self) -> ChargeState {
+        self.state
+    }
+
+    /// The state, if it's worth warning the rider about: low,
+    /// overcharging, or not charging at riding speed.
+    pub fn warning(&self) -> Option<ChargeState> {
+        match self.state {
+            ChargeState::Low | ChargeState::Overcharging => Some(self.state),
+            ChargeState::NotCharging if self.rpm >= self.limits.charging_rpm => Some(self.state),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hysteresis_and_hold_time() {
+        let mut c = ChargeClassifier::new(ChargeLimits::default());
+        let mut states = Vec::new();
+        for (t, volts) in [
+            (0, 12.6),
+            // cranking sag, over before the hold time
+            (1_000, 10.5),
+            (2_000, 12.4),
+            // running and charging
+            (3_000, 14.2),
+            (6_000, 14.2),
+            // hovering just under the charging threshold stays charging...
+            (9_000, 13.1),
+            (13_000, 13.1),
+            // ...until it's past it by the hysteresis
+            (14_000, 12.9),
+            (17_000, 12.9),
+        ] {
+            states.push(c.update(t, volts, 3_500.0));
+        }
+        use ChargeState::*;
+        assert_eq!(
+            states,
+            [
+                NotCharging,
+                NotCharging,
+                NotCharging,
+                NotCharging,
+                Charging,
+                Charging,
+                Charging,
+                Charging,
+                NotCharging
+            ]
+        );
+        assert_eq!(c.warning(), Some(NotCharging));
+        c.update(18_000, 12.9, 1_200.0);
+        assert_eq!(c.warning(), None);
+    }
+}
@@ -0,0 +1,64 @@
+// mcaux-datalogger/benches/compression.rs
+
+//! Bytes per hour of riding, plain records vs compressed blocks, plus
+//! rough encode/decode speed. `cargo bench -p mcaux-datalogger`
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use mcaux_datalogger::record::encode_stream;
+use mcaux_datalogger::{
+    Channel, ChannelRegistry, Record, RideConfig, RideSim, Scheduler, compress, decompress,
+};
+
+const HOUR_MS: u32 = 60 * 60 * 1000;
+
+/// An hour of town and highway riding. With `scheduled`, samples pass
+/// through the standard deadbands as on the bike; without, every sample
+/// is kept.
+fn ride(scheduled: bool) -> Vec<Record> {
+    let registry = ChannelRegistry::standard();
+    let registry = if scheduled {
+        registry
+    } else {
+        let mut every = ChannelRegistry::new();
+        for c in registry.iter() {
+            every.register(Channel { deadband: 0, ..*c });
+        }
+        every
+    };
+    let mut records = Vec::new();
+    RideSim::new(RideConfig::default()).run(HOUR_MS, &mut Scheduler::new(registry), &mut records);
+    records
+}
+
+fn time<T>(iterations: u32, mut f: impl FnMut() -> T) -> f64 {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        black_box(f());
+    }
+    start.elapsed().as_secs_f64() / iterations as f64
+}
+
+fn main() {
+    for (label, scheduled) in [("deadbanded", true), ("every sample", false)] {
+        let records = ride(scheduled);
+        let plain = encode_stream(&records).len();
+        println!("{label}: {} records/hour", records.len());
+        println!("  {:<22} {:>10} bytes/hour", "plain", plain);
+        for block in [64, 256, 1024] {
+            let packed = compress(&records, block);
+            assert_eq!(decompress(&packed).unwrap(), records);
+            let encode = time(20, || compress(&records, block));
+            let decode = time(20, || decompress(&packed).unwrap());
+            println!(
+                "  {:<22} {:>10} bytes/hour  {:>5.1}x  encode {:>6.2} ms  decode {:>6.2} ms",
+                format!("compressed, {block}/block"),
+                packed.len(),
+                plain as f64 / packed.len() as f64,
+                encode * 1e3,
+                decode * 1e3
+            );
+        }
+    }
+}
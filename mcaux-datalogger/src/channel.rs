@@ -0,0 +1,303 @@
+// mcaux-datalogger/src/channel.rs
+
+use crate::record::{ChannelId, Record, RecordSink};
+
+pub const REGULATOR_TEMP_C: ChannelId = ChannelId(1);
+pub const RPM: ChannelId = ChannelId(2);
+pub const AMBIENT_TEMP_C: ChannelId = ChannelId(3);
+pub const BATTERY_V: ChannelId = ChannelId(4);
+/// 1 at ignition on, 0 at ignition off; these bound ride sessions.
+pub const IGNITION: ChannelId = ChannelId(5);
+/// A `charging::ChargeState`, as its number.
+pub const CHARGE_STATE: ChannelId = ChannelId(6);
+
+/// A named measurement, how its raw integer values map to physical units,
+/// and how often it is worth logging.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Channel {
+    pub id: ChannelId,
+    pub name: &'static str,
+    pub unit: &'static str,
+    /// physical = raw * scale + offset
+    pub scale: f32,
+    pub offset: f32,
+    /// How often to sample.
+    pub period_ms: u32,
+    /// Don't log a sample within this many raw units of the last one logged...
+    pub deadband: i32,
+    /// ...unless it's been this long since the last one logged.
+    pub heartbeat_ms: u32,
+}
+
+impl Channel {
+    pub fn to_raw(&self, physical: f32) -> i32 {
+        ((physical - self.offset) / self.scale).round() as i32
+    }
+
+    pub fn to_physical(&self, raw: i32) -> f32 {
+        raw as f32 * self.scale + self.offset
+    }
+}
+
+/// The channels a logger knows about.
+#[derive(Clone, Debug, Default)]
+pub struct ChannelRegistry {
+    channels: Vec<Channel>,
+}
+
+impl ChannelRegistry {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// What we log on the bike: regulator, RPM, ambient, battery, ignition
+    /// and charging state.
+    pub fn standard() -> Self {
+        let mut reg = ChannelRegistry::new();
+        reg.register(Channel {
+            id: REGULATOR_TEMP_C,
+            name: "regulator_temp_c",
+            unit: "°C",
+            scale: 0.01,
+            offset: 0.0,
+            period_ms: 1_000,
+            deadband: 25,
+            heartbeat_ms: 60_000,
+        });
+        reg.register(Channel {
+            id: RPM,
+            name: "rpm",
+            unit: "rpm",
+            scale: 1.0,
+            offset: 0.0,
+            period_ms: 100,
+            deadband: 50,
+            heartbeat_ms: 10_000,
+        });
+        reg.register(Channel {
+            id: AMBIENT_TEMP_C,
+            name: "ambient_temp_c",
+            unit: "°C",
+            scale: 0.01,
+            offset: 0.0,
+            period_ms: 10_000,
+            deadband: 50,
+            heartbeat_ms: 300_000,
+        });
+        reg.register(Channel {
+            id: BATTERY_V,
+            name: "battery_v",
+            unit: "V",
+            scale: 0.001,
+            offset: 0.0,
+            period_ms: 500,
+            deadband: 50,
+            heartbeat_ms: 60_000,
+        });
+        reg.register(Channel {
+            id: IGNITION,
+            name: "ignition",
+            unit: "",
+            scale: 1.0,
+            offset: 0.0,
+            period_ms: 1_000,
+            deadband: 1,
+            heartbeat_ms: 60_000,
+        });
+        reg.register(Channel {
+            id: CHARGE_STATE,
+            name: "charge_state",
+            unit: "",
+            scale: 1.0,
+            offset: 0.0,
+            period_ms: 500,
+            deadband: 1,
+            heartbeat_ms: 60_000,
+        });
+        reg
+    }
+
+    pub fn register(&mut self, channel: Channel) -> ChannelId {
+        if self.get(channel.id).is_some() {
+            panic!("Channel id {} registered twice", channel.id.0);
+        }
+        if self.by_name(channel.name).is_some() {
+            panic!("Channel name {} registered twice", channel.name);
+        }
+        if channel.period_ms == 0 || channel.scale == 0.0 {
+            panic!("Channel {} needs a nonzero period and scale", channel.name);
+        }
+        self.channels.push(channel);
+        channel.id
+    }
+
+    pub fn get(&self, id: ChannelId) -> Option<&Channel> {
+        self.channels.iter().find(|c| c.id == id)
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&Channel> {
+        self.channels.iter().find(|c| c.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Channel> + '_ {
+        self.channels.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct ChannelState {
+    next_due: u32,
+    /// (timestamp, raw value) of the last sample logged
+    last_logged: Option<(u32, i32)>,
+}
+
+/// Decides when each channel is due for sampling, and which samples are
+/// worth logging, so fast RPM and slow ambient temperature can share
+/// storage without the slow channel drowning in duplicates.
+pub struct Scheduler {
+    registry: ChannelRegistry,
+    /// Parallel to `registry`
+    state: Vec<ChannelState>,
+}
+
+impl Scheduler {
+    pub fn new(registry: ChannelRegistry) -> Self {
+        let state = vec![ChannelState::default(); registry.len()];
+        Scheduler { registry, state }
+    }
+
+    pub fn registry(&self) -> &ChannelRegistry {
+        &self.registry
+    }
+
+    /// Channels that should be sampled at `now` (milliseconds since boot).
+    pub fn due(&self, now: u32) -> impl Iterator<Item = ChannelId> + '_ {
+        self.registry
+            .iter()
+            .zip(self.state.iter())
+            .filter(move |(_, s)| s.next_due <= now)
+            .map(|(c, _)| c.id)
+    }
+
+    /// When the next channel comes due; sleep until then.
+    pub fn next_due(&self) -> Option<u32> {
+        self.state.iter().map(|s| s.next_due).min()
+    }
+
+    /// Hand over a sample in physical units. Returns the record to log, or
+    /// None if it fell within the deadband (or the channel is unknown).
+    pub fn offer(&mut self, now: u32, id: ChannelId, physical: f32) -> Option<Record> {
+        let idx = self.registry.iter().position(|c| c.id == id)?;
+        let channel = self.registry.channels[idx];
+        let state = &mut self.state[idx];
+
+        // Stay on the period grid unless we've fallen a whole period behind.
+        state.next_due = match state.next_due.checked_add(channel.period_ms) {
+            Some(next) if next > now => next,
+            _ => now.saturating_add(channel.period_ms),
+        };
+
+        let raw = channel.to_raw(physical);
+        if let Some((t, last)) = state.last_logged
+            && raw.abs_diff(last) < channel.deadband.max(0) as u32
+            && now.saturating_sub(t) < channel.heartbeat_ms
+        {
+            return None;
+        }
+        state.last_logged = Some((now, raw));
+        Some(Record::new(now, id, raw))
+    }
+
+    /// `offer`, passing anything worth logging on to `sink`.
+    pub fn offer_to<S: RecordSink>(
+        &mut self,
+        sink: &mut S,
+        now: u32,
+        id: ChannelId,
+        physical: f32,
+    ) -> bool {
+        match self.offer(now, id, physical) {
+            Some(r) => {
+                sink.push_record(r);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scaling_round_trip() {
+        let reg = ChannelRegistry::standard();
+        let c = reg.by_name("regulator_temp_c").unwrap();
+        assert_eq!(c.to_raw(81.37), 8137);
+        assert!((c.to_physical(8137) - 81.37).abs() < 1e-3);
+        assert_eq!(reg.get(BATTERY_V).unwrap().to_raw(13.8), 13_800);
+    }
+
+    #[test]
+    #[should_panic]
+    fn duplicate_names_rejected() {
+        let mut reg = ChannelRegistry::standard();
+        let mut c = *reg.get(RPM).unwrap();
+        c.id = ChannelId(99);
+        reg.register(c);
+    }
+
+    #[test]
+    fn channels_come_due_at_their_own_rates() {
+        let mut s = Scheduler::new(ChannelRegistry::standard());
+        assert_eq!(s.due(0).count(), 6);
+        for id in [
+            REGULATOR_TEMP_C,
+            RPM,
+            AMBIENT_TEMP_C,
+            BATTERY_V,
+            IGNITION,
+            CHARGE_STATE,
+        ] {
+            s.offer(0, id, 1.0);
+        }
+        assert_eq!(s.due(50).count(), 0);
+        assert_eq!(s.next_due(), Some(100));
+        assert_eq!(s.due(100).collect::<Vec<_>>(), [RPM]);
+        assert_eq!(s.due(1000).count(), 5);
+        assert_eq!(s.due(10_000).count(), 6);
+    }
+
+    #[test]
+    fn deadband_and_heartbeat() {
+        let mut s = Scheduler::new(ChannelRegistry::standard());
+        let mut log: Vec<Record> = Vec::new();
+        assert!(s.offer_to(&mut log, 0, AMBIENT_TEMP_C, 20.0));
+        assert!(!s.offer_to(&mut log, 10_000, AMBIENT_TEMP_C, 20.3));
+        assert!(s.offer_to(&mut log, 20_000, AMBIENT_TEMP_C, 20.6));
+        // steady, but the heartbeat says log it anyway
+        assert!(!s.offer_to(&mut log, 310_000, AMBIENT_TEMP_C, 20.6));
+        assert!(s.offer_to(&mut log, 320_000, AMBIENT_TEMP_C, 20.6));
+        assert_eq!(
+            log.iter().map(|r| r.value).collect::<Vec<_>>(),
+            [2000, 2060, 2060]
+        );
+        assert_eq!(s.offer(0, ChannelId(77), 1.0), None);
+    }
+
+    #[test]
+    fn deadband_spans_the_whole_raw_range() {
+        let mut s = Scheduler::new(ChannelRegistry::standard());
+        assert_eq!(s.offer(0, RPM, -1e12).unwrap().value, i32::MIN);
+        assert_eq!(s.offer(100, RPM, 1e12).unwrap().value, i32::MAX);
+    }
+}
@@ -0,0 +1,124 @@
+// mcaux-datalogger/src/charging.rs
+
+//! Is the charging system doing its job? Battery voltage, read through a
+//! divider on an ADC and sorted by `mcaux_charging`'s `ChargeClassifier`
+//! into not charging, charging, overcharging and low. Both are logged, the
+//! state on `CHARGE_STATE`.
+
+pub use mcaux_charging::{ChargeClassifier, ChargeLimits, ChargeState};
+
+use crate::channel::{BATTERY_V, CHARGE_STATE, Scheduler};
+use crate::record::RecordSink;
+use crate::sensor::{AdcChannel, SensorError};
+
+/// Battery voltage through a resistor divider into an ADC: battery, `top`,
+/// ADC input, `bottom`, ground.
+pub struct VoltageDivider<A> {
+    adc: A,
+    /// ADC input voltage at `max_code()`
+    vref: f32,
+    top_ohms: f32,
+    bottom_ohms: f32,
+}
+
+impl<A: AdcChannel> VoltageDivider<A> {
+    pub fn new(adc: A, vref: f32, top_ohms: f32, bottom_ohms: f32) -> Self {
+        VoltageDivider {
+            adc,
+            vref,
+            top_ohms,
+            bottom_ohms,
+        }
+    }
+
+    pub fn release(self) -> A {
+        self.adc
+    }
+
+    /// Volts at the top of the divider.
+    pub fn read_volts(&mut self) -> Result<f32, SensorError<A::Error>> {
+        let code = self.adc.read().map_err(SensorError::Bus)?;
+        let input = code as f32 / self.adc.max_code() as f32 * self.vref;
+        Ok(input * (self.top_ohms + self.bottom_ohms) / self.bottom_ohms)
+    }
+}
+
+/// A divider and a classifier: read, classify, log both.
+pub struct ChargingMonitor<A> {
+    divider: VoltageDivider<A>,
+    classifier: ChargeClassifier,
+}
+
+impl<A: AdcChannel> ChargingMonitor<A> {
+    pub fn new(divider: VoltageDivider<A>, limits: ChargeLimits) -> Self {
+        ChargingMonitor {
+            divider,
+            classifier: ChargeClassifier::new(limits),
+        }
+    }
+
+    pub fn classifier(&self) -> &ChargeClassifier {
+        &self.classifier
+    }
+
+    /// Read the battery at `now` ms, returning volts and the state.
+    pub fn sample(
+        &mut self,
+        now: u32,
+        rpm: f32,
+    ) -> Result<(f32, ChargeState), SensorError<A::Error>> {
+        let volts = self.divider.read_volts()?;
+        Ok((volts, self.classifier.update(now, volts, rpm)))
+    }
+
+    /// Sample and offer both on `BATTERY_V` and `CHARGE_STATE`, passing
+    /// what's worth logging on to `sink`.
+    pub fn log_to<S: RecordSink>(
+        &mut self,
+        scheduler: &mut Scheduler,
+        sink: &mut S,
+        now: u32,
+        rpm: f32,
+    ) -> Result<ChargeState, SensorError<A::Error>> {
+        let (volts, state) = self.sample(now, rpm)?;
+        scheduler.offer_to(sink, now, BATTERY_V, volts);
+        scheduler.offer_to(sink, now, CHARGE_STATE, state as i32 as f32);
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::ChannelRegistry;
+    use crate::record::Record;
+    use crate::sensor::mock::MockAdc;
+
+    #[test]
+    fn overcharging_logs_and_warns() {
+        // 47k over 10k into a 3.3 V ADC: 15.0 V reads 2.63 V
+        let divider = VoltageDivider::new(MockAdc { code: 3266 }, 3.3, 47_000.0, 10_000.0);
+        let mut monitor = ChargingMonitor::new(divider, ChargeLimits::default());
+        let mut scheduler = Scheduler::new(ChannelRegistry::standard());
+        let mut records: Vec<Record> = Vec::new();
+        for t in (0..=4_000).step_by(500) {
+            monitor
+                .log_to(&mut scheduler, &mut records, t, 5_000.0)
+                .unwrap();
+        }
+        assert_eq!(monitor.classifier().state(), ChargeState::Overcharging);
+        assert_eq!(
+            monitor.classifier().warning(),
+            Some(ChargeState::Overcharging)
+        );
+
+        let volts = records.iter().find(|r| r.channel == BATTERY_V).unwrap();
+        assert!((volts.value - 15_000).abs() < 10, "{}", volts.value);
+        let states: Vec<(u32, i32)> = records
+            .iter()
+            .filter(|r| r.channel == CHARGE_STATE)
+            .map(|r| (r.timestamp, r.value))
+            .collect();
+        assert_eq!(states, [(0, 0), (3_000, 2)]);
+    }
+}
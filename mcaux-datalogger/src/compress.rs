@@ -0,0 +1,396 @@
+// mcaux-datalogger/src/compress.rs
+
+//! A compact alternative to the plain 10-byte records, for when flash is
+//! tight: channels sample on a fixed period and mostly change slowly, so
+//! per channel we store the change in timestamp step (usually zero) and
+//! the change in value, as zigzag varints.
+//!
+//! A stream is `MCZ` and a version byte, then blocks. Each block stands
+//! alone, with channel state starting afresh, so one bad block costs only
+//! its own records:
+//!
+//! | bytes | field                                         |
+//! |-------|-----------------------------------------------|
+//! | 2     | sync, `B1 0C`                                 |
+//! | 2     | record count                                  |
+//! | 2     | payload length                                |
+//! | 4     | earliest timestamp in the block               |
+//! | 4     | latest timestamp in the block                 |
+//! | n     | payload                                       |
+//! | 4     | CRC-32 of everything from count to payload    |
+//!
+//! In the payload each record is its channel id as a varint, then the
+//! first time a channel appears in the block its timestamp (varint) and
+//! value (zigzag varint), and after that the delta-of-delta of its
+//! timestamp and delta of its value (both zigzag varints).
+
+use crate::crc::crc32;
+use crate::record::{ChannelId, DecodeError, Record, resync};
+
+pub const COMPRESSED_MAGIC: [u8; 3] = *b"MCZ";
+pub const COMPRESSED_VERSION: u8 = 1;
+pub const BLOCK_SYNC: [u8; 2] = [0xb1, 0x0c];
+pub const BLOCK_HEADER_LEN: usize = 14;
+pub const BLOCK_TRAILER_LEN: usize = 4;
+
+/// Few enough that a block's payload always fits its u16 length: a record
+/// takes at most 3 + 10 + 10 bytes.
+pub const MAX_BLOCK_RECORDS: usize = 2048;
+
+pub fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+pub fn unzigzag(v: u64) -> i64 {
+    (v >> 1) as i64 ^ -((v & 1) as i64)
+}
+
+pub fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+/// Read a varint from the front of `buf`, advancing past it.
+pub fn get_varint(buf: &mut &[u8]) -> Result<u64, DecodeError> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first().ok_or(DecodeError::Short)?;
+        *buf = rest;
+        v |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(DecodeError::Malformed)
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ChannelState {
+    channel: ChannelId,
+    timestamp: u32,
+    step: i64,
+    value: i32,
+}
+
+/// Builds blocks a record at a time, handing each over once it's full.
+#[derive(Clone, Debug)]
+pub struct BlockEncoder {
+    max_records: usize,
+    payload: Vec<u8>,
+    count: usize,
+    first: u32,
+    last: u32,
+    channels: Vec<ChannelState>,
+}
+
+impl BlockEncoder {
+    pub fn new(max_records: usize) -> Self {
+        if max_records == 0 || max_records > MAX_BLOCK_RECORDS {
+            panic!("Blocks hold between 1 and {MAX_BLOCK_RECORDS} records");
+        }
+        BlockEncoder {
+            max_records,
+            payload: Vec::new(),
+            count: 0,
+            first: u32::MAX,
+            last: 0,
+            channels: Vec::new(),
+        }
+    }
+
+    /// Add a record, returning a finished block if this one filled it.
+    pub fn push(&mut self, r: &Record) -> Option<Vec<u8>> {
+        put_varint(&mut self.payload, r.channel.0 as u64);
+        match self.channels.iter_mut().find(|s| s.channel == r.channel) {
+            None => {
+                put_varint(&mut self.payload, r.timestamp as u64);
+                put_varint(&mut self.payload, zigzag(r.value as i64));
+                self.channels.push(ChannelState {
+                    channel: r.channel,
+                    timestamp: r.timestamp,
+                    step: 0,
+                    value: r.value,
+                });
+            }
+            Some(s) => {
+                let step = r.timestamp as i64 - s.timestamp as i64;
+                put_varint(&mut self.payload, zigzag(step - s.step));
+                put_varint(&mut self.payload, zigzag(r.value as i64 - s.value as i64));
+                s.timestamp = r.timestamp;
+                s.step = step;
+                s.value = r.value;
+            }
+        }
+        self.count += 1;
+        self.first = self.first.min(r.timestamp);
+        self.last = self.last.max(r.timestamp);
+        if self.count == self.max_records {
+            self.finish()
+        } else {
+            None
+        }
+    }
+
+    /// Records waiting for their block to be finished.
+    pub fn pending(&self) -> usize {
+        self.count
+    }
+
+    /// Close off the block under way, if it has anything in it.
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        if self.count == 0 {
+            return None;
+        }
+        let mut block = BLOCK_SYNC.to_vec();
+        block.extend_from_slice(&(self.count as u16).to_le_bytes());
+        block.extend_from_slice(&(self.payload.len() as u16).to_le_bytes());
+        block.extend_from_slice(&self.first.to_le_bytes());
+        block.extend_from_slice(&self.last.to_le_bytes());
+        block.extend_from_slice(&self.payload);
+        let crc = crc32(&block[BLOCK_SYNC.len()..]);
+        block.extend_from_slice(&crc.to_le_bytes());
+
+        self.payload.clear();
+        self.count = 0;
+        self.first = u32::MAX;
+        self.last = 0;
+        self.channels.clear();
+        Some(block)
+    }
+}
+
+/// One decoded block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub first_ms: u32,
+    pub last_ms: u32,
+    pub records: Vec<Record>,
+}
+
+/// Decode the block at the front of `buf`, returning it and the bytes it
+/// took.
+pub fn decode_block(buf: &[u8]) -> Result<(Block, usize), DecodeError> {
+    if buf.len() < BLOCK_HEADER_LEN {
+        return Err(DecodeError::Short);
+    }
+    if buf[..2] != BLOCK_SYNC {
+        return Err(DecodeError::BadMagic);
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]) as usize;
+    let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+    let (count, len) = (u16_at(2), u16_at(4));
+    let total = BLOCK_HEADER_LEN + len + BLOCK_TRAILER_LEN;
+    if buf.len() < total {
+        return Err(DecodeError::Short);
+    }
+    if crc32(&buf[2..BLOCK_HEADER_LEN + len]) != u32_at(BLOCK_HEADER_LEN + len) {
+        return Err(DecodeError::BadCrc);
+    }
+
+    let mut payload = &buf[BLOCK_HEADER_LEN..BLOCK_HEADER_LEN + len];
+    let mut channels: Vec<ChannelState> = Vec::new();
+    let mut records = Vec::with_capacity(count);
+    // Past the CRC, running out of payload means the encoder was wrong,
+    // not that the data was cut short.
+    let malformed = |_| DecodeError::Malformed;
+    for _ in 0..count {
+        let channel = u16::try_from(get_varint(&mut payload).map_err(malformed)?)
+            .map_err(|_| DecodeError::Malformed)?;
+        let channel = ChannelId(channel);
+        let record = match channels.iter_mut().find(|s| s.channel == channel) {
+            None => {
+                let timestamp = get_varint(&mut payload).map_err(malformed)?;
+                let value = unzigzag(get_varint(&mut payload).map_err(malformed)?);
+                let s = ChannelState {
+                    channel,
+                    timestamp: u32::try_from(timestamp).map_err(|_| DecodeError::Malformed)?,
+                    step: 0,
+                    value: i32::try_from(value).map_err(|_| DecodeError::Malformed)?,
+                };
+                channels.push(s);
+                s
+            }
+            Some(s) => {
+                s.step += unzigzag(get_varint(&mut payload).map_err(malformed)?);
+                let value = s.value as i64 + unzigzag(get_varint(&mut payload).map_err(malformed)?);
+                s.timestamp = u32::try_from(s.timestamp as i64 + s.step)
+                    .map_err(|_| DecodeError::Malformed)?;
+                s.value = i32::try_from(value).map_err(|_| DecodeError::Malformed)?;
+                *s
+            }
+        };
+        records.push(Record::new(record.timestamp, channel, record.value));
+    }
+    if !payload.is_empty() {
+        return Err(DecodeError::Malformed);
+    }
+    let block = Block {
+        first_ms: u32_at(6),
+        last_ms: u32_at(10),
+        records,
+    };
+    Ok((block, total))
+}
+
+pub fn encode_compressed_header() -> [u8; 4] {
+    [
+        COMPRESSED_MAGIC[0],
+        COMPRESSED_MAGIC[1],
+        COMPRESSED_MAGIC[2],
+        COMPRESSED_VERSION,
+    ]
+}
+
+/// Header and blocks of up to `block_records` records each.
+pub fn compress(records: &[Record], block_records: usize) -> Vec<u8> {
+    let mut out = encode_compressed_header().to_vec();
+    let mut encoder = BlockEncoder::new(block_records);
+    for r in records {
+        if let Some(block) = encoder.push(r) {
+            out.extend_from_slice(&block);
+        }
+    }
+    if let Some(block) = encoder.finish() {
+        out.extend_from_slice(&block);
+    }
+    out
+}
+
+/// Iterate the blocks of a compressed stream. A block cut short at the
+/// end (power lost mid-write) ends the stream quietly; a corrupt one is
+/// reported, then skipped.
+pub struct Blocks<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Blocks<'a> {
+    pub fn new(stream: &'a [u8]) -> Result<Self, DecodeError> {
+        if stream.len() < COMPRESSED_MAGIC.len() + 1 {
+            return Err(DecodeError::Short);
+        }
+        if stream[..3] != COMPRESSED_MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        if stream[3] != COMPRESSED_VERSION {
+            return Err(DecodeError::UnsupportedVersion(stream[3]));
+        }
+        Ok(Blocks { rest: &stream[4..] })
+    }
+}
+
+impl Iterator for Blocks<'_> {
+    type Item = Result<Block, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        match decode_block(self.rest) {
+            Ok((block, used)) => {
+                self.rest = &self.rest[used..];
+                Some(Ok(block))
+            }
+            Err(e) => match resync(self.rest, BLOCK_SYNC, |b| decode_block(b).is_ok()) {
+                Some(skip) => {
+                    self.rest = &self.rest[skip..];
+                    Some(Err(e))
+                }
+                None => {
+                    self.rest = &[];
+                    (e != DecodeError::Short).then_some(Err(e))
+                }
+            },
+        }
+    }
+}
+
+/// Every record in a compressed stream.
+pub fn decompress(stream: &[u8]) -> Result<Vec<Record>, DecodeError> {
+    let mut out = Vec::new();
+    for block in Blocks::new(stream)? {
+        out.extend(block?.records);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::record::RECORD_LEN;
+
+    fn ride() -> Vec<Record> {
+        let mut out = Vec::new();
+        for i in 0..600u32 {
+            out.push(Record::new(
+                i * 100,
+                ChannelId(2),
+                3000 + (i as i32 % 40) * 5,
+            ));
+            if i % 10 == 0 {
+                out.push(Record::new(i * 100, ChannelId(1), 6000 + i as i32));
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn varints_and_zigzag() {
+        for v in [
+            0i64,
+            1,
+            -1,
+            63,
+            -64,
+            64,
+            i32::MAX as i64,
+            i32::MIN as i64,
+            i64::MIN,
+        ] {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, zigzag(v));
+            let mut rest = &buf[..];
+            assert_eq!(unzigzag(get_varint(&mut rest).unwrap()), v);
+            assert!(rest.is_empty());
+        }
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(get_varint(&mut &[0x80, 0x80][..]), Err(DecodeError::Short));
+        assert_eq!(
+            get_varint(&mut &[0xff; 11][..]),
+            Err(DecodeError::Malformed)
+        );
+    }
+
+    #[test]
+    fn round_trip_and_smaller() {
+        let mut records = ride();
+        // extremes and time going backwards must survive too
+        records.push(Record::new(5, ChannelId(2), i32::MIN));
+        records.push(Record::new(u32::MAX, ChannelId(2), i32::MAX));
+        records.push(Record::new(0, ChannelId(u16::MAX), -7));
+
+        let stream = compress(&records, 256);
+        assert_eq!(decompress(&stream), Ok(records.clone()));
+        assert!(stream.len() * 3 < records.len() * RECORD_LEN);
+
+        let blocks: Vec<Block> = Blocks::new(&stream).unwrap().map(Result::unwrap).collect();
+        assert_eq!(blocks.len(), records.len().div_ceil(256));
+        assert_eq!((blocks[0].first_ms, blocks[0].last_ms), (0, 23_100));
+    }
+
+    #[test]
+    fn detects_corruption_and_forgives_torn_tail() {
+        let records = ride();
+        let stream = compress(&records, 100);
+
+        let torn = &stream[..stream.len() - 3];
+        let survived = decompress(torn).unwrap();
+        assert_eq!(survived, records[..records.len() / 100 * 100]);
+
+        let mut flipped = stream.clone();
+        flipped[4 + BLOCK_HEADER_LEN + 5] ^= 0x10;
+        assert_eq!(decompress(&flipped), Err(DecodeError::BadCrc));
+    }
+}
@@ -0,0 +1,137 @@
+// mcaux-datalogger/src/controls.rs
+
+//! What the rider switched, as events in the log, so regulator temperature
+//! can be read against the load that was on (aux lights, heated grips).
+//!
+//! `ControllerLog` sits beside a `MomentaryController` and compares each
+//! report with the last. Every switch closed, opened or held into a long
+//! press becomes a `SwitchChanged` event and every output that moved an
+//! `OutputChanged` event with its new level. Both start from everything
+//! open and off, as the controller does, so a fresh log only hears about
+//! what differs.
+
+use momentary::{MomentaryController, OUTPUTS, SWITCHES, SwitchState};
+
+use crate::event::{Event, SWITCH_CLOSED, SWITCH_HELD, SWITCH_OPENED};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ControllerLog {
+    switches: [bool; SWITCHES],
+    outputs: [u8; OUTPUTS],
+    long: bool,
+}
+
+impl ControllerLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report `incoming` to the controller, telling `on_event` what
+    /// changed. Returns what the controller did.
+    pub fn report(
+        &mut self,
+        controller: &mut MomentaryController,
+        timestamp: u32,
+        incoming: [bool; SWITCHES],
+        on_event: impl FnMut(Event),
+    ) -> ([u8; OUTPUTS], SwitchState) {
+        let (outputs, state) = controller.report(incoming);
+        self.observe(timestamp, incoming, &outputs, state, on_event);
+        (outputs, state)
+    }
+
+    /// For callers that report to the controller themselves: the switches
+    /// they reported and what came back. Switch events come before the
+    /// output changes they caused.
+    pub fn observe(
+        &mut self,
+        timestamp: u32,
+        incoming: [bool; SWITCHES],
+        outputs: &[u8; OUTPUTS],
+        state: SwitchState,
+        mut on_event: impl FnMut(Event),
+    ) {
+        for (i, (&now, before)) in incoming.iter().zip(self.switches.iter_mut()).enumerate() {
+            if now != *before {
+                let value = if now { SWITCH_CLOSED } else { SWITCH_OPENED };
+                on_event(Event::switch_changed(timestamp, i as u8, value));
+                *before = now;
+            }
+        }
+        let long = matches!(state, SwitchState::Long);
+        if long && !self.long {
+            for (i, _) in incoming.iter().enumerate().filter(|(_, closed)| **closed) {
+                on_event(Event::switch_changed(timestamp, i as u8, SWITCH_HELD));
+            }
+        }
+        self.long = long;
+        for (i, (&now, before)) in outputs.iter().zip(self.outputs.iter_mut()).enumerate() {
+            if now != *before {
+                on_event(Event::output_changed(timestamp, i as u8, now as i32));
+                *before = now;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::EventKind;
+
+    fn summary(events: &[Event]) -> Vec<(u32, EventKind, i32)> {
+        events
+            .iter()
+            .map(|e| (e.timestamp, e.kind, e.value))
+            .collect()
+    }
+
+    #[test]
+    fn press_and_release_toggle_an_output() {
+        let mut controller = MomentaryController::default();
+        let (grips, _) = controller.add_switch(2);
+        let mut log = ControllerLog::new();
+        let mut events = Vec::new();
+        let mut switches = [false; SWITCHES];
+        for (t, closed) in [(0, false), (100, true), (150, true), (300, false)] {
+            switches[grips] = closed;
+            log.report(&mut controller, t, switches, |e| events.push(e));
+        }
+        assert_eq!(
+            summary(&events),
+            [
+                (100, EventKind::SwitchChanged(0), SWITCH_CLOSED),
+                (300, EventKind::SwitchChanged(0), SWITCH_OPENED),
+                (300, EventKind::OutputChanged(0), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn long_press_is_logged_once() {
+        let mut log = ControllerLog::new();
+        let mut events = Vec::new();
+        let mut switches = [false; SWITCHES];
+        let mut outputs = [0; OUTPUTS];
+        // held past the long-press time, which bumps output 2 once
+        for (t, closed, level, state) in [
+            (0, true, 0, SwitchState::One),
+            (1600, true, 1, SwitchState::Long),
+            (1700, true, 1, SwitchState::Long),
+            (1800, false, 1, SwitchState::None),
+        ] {
+            switches[0] = closed;
+            outputs[2] = level;
+            log.observe(t, switches, &outputs, state, |e| events.push(e));
+        }
+        assert_eq!(
+            summary(&events),
+            [
+                (0, EventKind::SwitchChanged(0), SWITCH_CLOSED),
+                (1600, EventKind::SwitchChanged(0), SWITCH_HELD),
+                (1600, EventKind::OutputChanged(2), 1),
+                (1800, EventKind::SwitchChanged(0), SWITCH_OPENED),
+            ]
+        );
+    }
+}
@@ -0,0 +1,354 @@
+// mcaux-datalogger/src/ds18b20.rs
+
+//! The DS18B20 digital thermometer, over 1-Wire.
+//!
+//! embedded-hal has no 1-Wire trait. `OneWire` is the reset, write and read
+//! a driver needs. `BitBangOneWire` provides them on an open-drain GPIO
+//! with a delay, and a UART or DS2484 bridge could too. One sensor per bus
+//! can skip addressing. With several, give each its ROM code.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin};
+
+use crate::sensor::{SensorError, TemperatureSensor};
+
+const SKIP_ROM: u8 = 0xcc;
+const MATCH_ROM: u8 = 0x55;
+const CONVERT_T: u8 = 0x44;
+const READ_SCRATCHPAD: u8 = 0xbe;
+
+/// 12-bit conversion time, the power-on resolution
+const CONVERSION_MS: u32 = 750;
+
+pub trait OneWire {
+    type Error;
+
+    /// Reset pulse; true if any device answered with a presence pulse.
+    fn reset(&mut self) -> Result<bool, Self::Error>;
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), Self::Error>;
+
+    fn read_byte(&mut self) -> Result<u8, Self::Error>;
+}
+
+/// 1-Wire on a GPIO configured open-drain with a pull-up (4.7k typical):
+/// driving low pulls the bus down, driving high lets it float up. Standard
+/// speed timings. Interrupts landing mid-slot corrupt it, so mask them
+/// around calls if they can run long.
+pub struct BitBangOneWire<P, D> {
+    pin: P,
+    delay: D,
+}
+
+impl<P: InputPin + OutputPin, D: DelayNs> BitBangOneWire<P, D> {
+    pub fn new(pin: P, delay: D) -> Self {
+        BitBangOneWire { pin, delay }
+    }
+
+    pub fn release(self) -> (P, D) {
+        (self.pin, self.delay)
+    }
+
+    fn write_bit(&mut self, bit: bool) -> Result<(), P::Error> {
+        self.pin.set_low()?;
+        let (low, high) = if bit { (6, 64) } else { (60, 10) };
+        self.delay.delay_us(low);
+        self.pin.set_high()?;
+        self.delay.delay_us(high);
+        Ok(())
+    }
+
+    fn read_bit(&mut self) -> Result<bool, P::Error> {
+        self.pin.set_low()?;
+        self.delay.delay_us(6);
+        self.pin.set_high()?;
+        self.delay.delay_us(9);
+        let bit = self.pin.is_high()?;
+        self.delay.delay_us(55);
+        Ok(bit)
+    }
+}
+
+impl<P: InputPin + OutputPin, D: DelayNs> OneWire for BitBangOneWire<P, D> {
+    type Error = P::Error;
+
+    fn reset(&mut self) -> Result<bool, Self::Error> {
+        self.pin.set_low()?;
+        self.delay.delay_us(480);
+        self.pin.set_high()?;
+        self.delay.delay_us(70);
+        let present = self.pin.is_low()?;
+        self.delay.delay_us(410);
+        Ok(present)
+    }
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), Self::Error> {
+        for i in 0..8 {
+            self.write_bit(byte >> i & 1 != 0)?;
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Self::Error> {
+        let mut byte = 0;
+        for i in 0..8 {
+            byte |= (self.read_bit()? as u8) << i;
+        }
+        Ok(byte)
+    }
+}
+
+/// Dallas/Maxim CRC-8 (x^8 + x^5 + x^4 + 1), over ROM codes and the
+/// scratchpad.
+pub fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |mut crc, &byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0x8c
+            } else {
+                crc >> 1
+            };
+        }
+        crc
+    })
+}
+
+pub struct Ds18b20<B, D> {
+    bus: B,
+    delay: D,
+    /// None to skip ROM addressing, with the sensor alone on the bus
+    rom: Option<[u8; 8]>,
+    /// Until a conversion is started the scratchpad holds 85 °C, a real
+    /// enough regulator temperature that only this can tell them apart.
+    /// A failed read clears it. A sensor that browns out and comes back
+    /// without the bus failing meanwhile reads 85 °C as if it were real.
+    converted: bool,
+}
+
+impl<B: OneWire, D: DelayNs> Ds18b20<B, D> {
+    pub fn new(bus: B, delay: D, rom: Option<[u8; 8]>) -> Self {
+        Ds18b20 {
+            bus,
+            delay,
+            rom,
+            converted: false,
+        }
+    }
+
+    pub fn release(self) -> (B, D) {
+        (self.bus, self.delay)
+    }
+
+    /// Reset and address the sensor, ready for a function command.
+    fn select(&mut self) -> Result<(), SensorError<B::Error>> {
+        if !self.bus.reset().map_err(SensorError::Bus)? {
+            return Err(SensorError::Missing);
+        }
+        match self.rom {
+            None => self.bus.write_byte(SKIP_ROM),
+            Some(rom) => std::iter::once(&MATCH_ROM)
+                .chain(&rom)
+                .try_for_each(|&b| self.bus.write_byte(b)),
+        }
+        .map_err(SensorError::Bus)
+    }
+
+    /// Start a conversion, to read `CONVERSION_MS` later with `read`.
+    /// Lets the caller get on with other things meanwhile.
+    pub fn start_conversion(&mut self) -> Result<(), SensorError<B::Error>> {
+        self.select()?;
+        self.bus.write_byte(CONVERT_T).map_err(SensorError::Bus)?;
+        self.converted = true;
+        Ok(())
+    }
+
+    /// The last conversion, in °C. After an error, start another
+    /// conversion before reading again: the sensor may have reset.
+    pub fn read(&mut self) -> Result<f32, SensorError<B::Error>> {
+        if !self.converted {
+            return Err(SensorError::NotReady);
+        }
+        let celsius = self.read_scratchpad();
+        if celsius.is_err() {
+            self.converted = false;
+        }
+        celsius
+    }
+
+    fn read_scratchpad(&mut self) -> Result<f32, SensorError<B::Error>> {
+        self.select()?;
+        self.bus
+            .write_byte(READ_SCRATCHPAD)
+            .map_err(SensorError::Bus)?;
+        let mut pad = [0; 9];
+        for b in &mut pad {
+            *b = self.bus.read_byte().map_err(SensorError::Bus)?;
+        }
+        // a missing sensor floats the bus high, which passes no CRC
+        if crc8(&pad[..8]) != pad[8] {
+            return Err(SensorError::Crc);
+        }
+        // but a bus held low reads all zeros, which does; the reserved byte
+        // and the config register's fixed bits give it away
+        if pad[5] != 0xff || pad[4] & 0x9f != 0x1f {
+            return Err(SensorError::Fault);
+        }
+        Ok(i16::from_le_bytes([pad[0], pad[1]]) as f32 / 16.0)
+    }
+}
+
+impl<B: OneWire, D: DelayNs> TemperatureSensor for Ds18b20<B, D> {
+    type Error = SensorError<B::Error>;
+
+    /// Convert and read, blocking for the conversion time.
+    fn read_celsius(&mut self) -> Result<f32, Self::Error> {
+        self.start_conversion()?;
+        self.delay.delay_ms(CONVERSION_MS);
+        self.read()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::convert::Infallible;
+
+    /// The scratchpad's temperature at power-on, before any conversion
+    const POWER_ON_RAW: i16 = 0x0550;
+
+    #[derive(Default)]
+    struct NoDelay;
+
+    impl DelayNs for NoDelay {
+        fn delay_ns(&mut self, _ns: u32) {}
+    }
+
+    /// A DS18B20 on the other end of the bus, at the byte level.
+    #[derive(Default)]
+    struct MockBus {
+        present: bool,
+        rom: [u8; 8],
+        raw: i16,
+        converted: bool,
+        /// Shorted data line: presence always, and every bit reads 0
+        held_low: bool,
+        /// Bytes written since the last reset
+        written: Vec<u8>,
+        /// What the sensor will answer with
+        reply: Vec<u8>,
+    }
+
+    impl MockBus {
+        fn sensor(raw: i16) -> Self {
+            let mut rom = [0x28, 1, 2, 3, 4, 5, 6, 0];
+            rom[7] = crc8(&rom[..7]);
+            MockBus {
+                present: true,
+                rom,
+                raw,
+                ..Default::default()
+            }
+        }
+    }
+
+    impl OneWire for MockBus {
+        type Error = Infallible;
+
+        fn reset(&mut self) -> Result<bool, Self::Error> {
+            self.written.clear();
+            self.reply.clear();
+            Ok(self.present || self.held_low)
+        }
+
+        fn write_byte(&mut self, byte: u8) -> Result<(), Self::Error> {
+            self.written.push(byte);
+            let command = match self.written[..] {
+                [SKIP_ROM, c] => c,
+                [MATCH_ROM, ref rest @ ..] if rest.len() == 9 && rest[..8] == self.rom => rest[8],
+                _ => return Ok(()),
+            };
+            match command {
+                CONVERT_T => self.converted = true,
+                READ_SCRATCHPAD => {
+                    let raw = if self.converted {
+                        self.raw
+                    } else {
+                        POWER_ON_RAW
+                    };
+                    let [lo, hi] = raw.to_le_bytes();
+                    let mut pad = vec![lo, hi, 0x4b, 0x46, 0x7f, 0xff, 0x0c, 0x10];
+                    pad.push(crc8(&pad));
+                    pad.reverse();
+                    self.reply = pad;
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+
+        fn read_byte(&mut self) -> Result<u8, Self::Error> {
+            if self.held_low {
+                return Ok(0);
+            }
+            // nobody driving the bus reads as ones
+            Ok(self.reply.pop().unwrap_or(0xff))
+        }
+    }
+
+    #[test]
+    fn crc_of_a_rom_code() {
+        // worked example from Maxim's application note 27
+        assert_eq!(crc8(&[0x02, 0x1c, 0xb8, 0x01, 0, 0, 0]), 0xa2);
+    }
+
+    #[test]
+    fn converts_and_reads_signed_sixteenths() {
+        // -10.125 °C, from the datasheet's table
+        let mut sensor = Ds18b20::new(MockBus::sensor(-162), NoDelay, None);
+        assert_eq!(sensor.read(), Err(SensorError::NotReady));
+        assert_eq!(sensor.read_celsius(), Ok(-10.125));
+
+        let bus = MockBus::sensor(0x0191);
+        let rom = bus.rom;
+        let mut sensor = Ds18b20::new(bus, NoDelay, Some(rom));
+        assert_eq!(sensor.read_celsius(), Ok(25.0625));
+    }
+
+    #[test]
+    fn a_real_85_degrees_is_a_reading() {
+        let mut sensor = Ds18b20::new(MockBus::sensor(POWER_ON_RAW), NoDelay, None);
+        assert_eq!(sensor.read(), Err(SensorError::NotReady));
+        sensor.start_conversion().unwrap();
+        assert_eq!(sensor.read(), Ok(85.0));
+    }
+
+    #[test]
+    fn absent_or_wrong_sensor() {
+        let mut bus = MockBus::sensor(0x0191);
+        bus.present = false;
+        let mut sensor = Ds18b20::new(bus, NoDelay, None);
+        assert_eq!(sensor.read_celsius(), Err(SensorError::Missing));
+
+        let mut sensor = Ds18b20::new(MockBus::sensor(0x0191), NoDelay, Some([0; 8]));
+        assert_eq!(sensor.read_celsius(), Err(SensorError::Crc));
+
+        let mut bus = MockBus::sensor(0x0191);
+        bus.held_low = true;
+        let mut sensor = Ds18b20::new(bus, NoDelay, None);
+        assert_eq!(sensor.read_celsius(), Err(SensorError::Fault));
+    }
+
+    #[test]
+    fn a_failed_read_needs_a_new_conversion() {
+        let mut sensor = Ds18b20::new(MockBus::sensor(0x0191), NoDelay, None);
+        assert_eq!(sensor.read_celsius(), Ok(25.0625));
+        sensor.bus.present = false;
+        assert_eq!(sensor.read(), Err(SensorError::Missing));
+        // back after a brown-out, holding its power-on 85 °C
+        sensor.bus.present = true;
+        sensor.bus.converted = false;
+        assert_eq!(sensor.read(), Err(SensorError::NotReady));
+        assert_eq!(sensor.read_celsius(), Ok(25.0625));
+    }
+}
@@ -0,0 +1,464 @@
+// mcaux-datalogger/src/event.rs
+
+//! Discrete events alongside the continuous samples: a limit crossed, a
+//! voltage sag, an output switched.
+//!
+//! Events travel as ordinary records so storage, compression and images
+//! need not know about them. Their channel id has `EVENT_TAG` in the top
+//! four bits, then two bits of severity, two of kind and eight of
+//! argument (which rule, output or switch); the record's value carries the
+//! reading or new state behind the event.
+
+use crate::record::{ChannelId, Record, encode_stream};
+use crate::ring::RingBuffer;
+use crate::storage::LogFs;
+
+/// Top four bits of every event channel id.
+pub const EVENT_TAG: u16 = 0xf000;
+
+pub fn is_event(channel: ChannelId) -> bool {
+    channel.0 & 0xf000 == EVENT_TAG
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    /// Trigger rule with this index started to hold; value is the reading.
+    Triggered(u8),
+    /// ...and stopped holding.
+    Cleared(u8),
+    /// Controller output with this index changed; value is its new state.
+    OutputChanged(u8),
+    /// Controller switch with this index changed; value is a `SWITCH_*`.
+    SwitchChanged(u8),
+}
+
+/// `SwitchChanged` values
+pub const SWITCH_OPENED: i32 = 0;
+pub const SWITCH_CLOSED: i32 = 1;
+/// Still closed, now long enough to count as a long press
+pub const SWITCH_HELD: i32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub timestamp: u32,
+    pub severity: Severity,
+    pub kind: EventKind,
+    pub value: i32,
+}
+
+impl Event {
+    pub fn output_changed(timestamp: u32, output: u8, value: i32) -> Self {
+        Event {
+            timestamp,
+            severity: Severity::Info,
+            kind: EventKind::OutputChanged(output),
+            value,
+        }
+    }
+
+    pub fn switch_changed(timestamp: u32, switch: u8, value: i32) -> Self {
+        Event {
+            timestamp,
+            severity: Severity::Info,
+            kind: EventKind::SwitchChanged(switch),
+            value,
+        }
+    }
+
+    pub fn to_record(&self) -> Record {
+        let severity = match self.severity {
+            Severity::Info => 0,
+            Severity::Warning => 1,
+            Severity::Critical => 2,
+        };
+        let (kind, arg) = match self.kind {
+            EventKind::Triggered(a) => (0, a),
+            EventKind::Cleared(a) => (1, a),
+            EventKind::OutputChanged(a) => (2, a),
+            EventKind::SwitchChanged(a) => (3, a),
+        };
+        let channel = EVENT_TAG | severity << 10 | kind << 8 | arg as u16;
+        Record::new(self.timestamp, ChannelId(channel), self.value)
+    }
+
+    /// None if the record isn't an event, or is one from a newer firmware.
+    pub fn from_record(r: &Record) -> Option<Event> {
+        if !is_event(r.channel) {
+            return None;
+        }
+        let severity = match (r.channel.0 >> 10) & 3 {
+            0 => Severity::Info,
+            1 => Severity::Warning,
+            2 => Severity::Critical,
+            _ => return None,
+        };
+        let arg = r.channel.0 as u8;
+        let kind = match (r.channel.0 >> 8) & 3 {
+            0 => EventKind::Triggered(arg),
+            1 => EventKind::Cleared(arg),
+            2 => EventKind::OutputChanged(arg),
+            _ => EventKind::SwitchChanged(arg),
+        };
+        Some(Event {
+            timestamp: r.timestamp,
+            severity,
+            kind,
+            value: r.value,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition {
+    /// Strictly above, in raw units
+    Above(i32),
+    Below(i32),
+}
+
+impl Condition {
+    fn holds(&self, v: i32) -> bool {
+        match *self {
+            Condition::Above(limit) => v > limit,
+            Condition::Below(limit) => v < limit,
+        }
+    }
+
+    /// Whether a held condition has let go, allowing `hysteresis` of slack
+    /// so a reading hovering at the limit doesn't chatter.
+    fn released(&self, v: i32, hysteresis: i32) -> bool {
+        match *self {
+            Condition::Above(limit) => v <= limit - hysteresis,
+            Condition::Below(limit) => v >= limit + hysteresis,
+        }
+    }
+}
+
+/// Fire an event when a channel meets a condition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub channel: ChannelId,
+    pub condition: Condition,
+    /// Raw units the reading must come back past the limit to clear.
+    pub hysteresis: i32,
+    /// The condition must hold this long before firing.
+    pub hold_ms: u32,
+    pub severity: Severity,
+    /// Only while this other channel's latest reading meets its condition,
+    /// e.g. voltage sag only counts above some RPM.
+    pub when: Option<(ChannelId, Condition)>,
+}
+
+impl Rule {
+    pub fn new(channel: ChannelId, condition: Condition, severity: Severity) -> Self {
+        Rule {
+            channel,
+            condition,
+            hysteresis: 0,
+            hold_ms: 0,
+            severity,
+            when: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct RuleState {
+    holding_since: Option<u32>,
+    fired: bool,
+}
+
+/// Evaluates trigger rules over incoming samples.
+#[derive(Clone, Debug)]
+pub struct Triggers {
+    rules: Vec<Rule>,
+    state: Vec<RuleState>,
+    /// Latest reading of each channel seen, for `Rule::when`
+    latest: Vec<(ChannelId, i32)>,
+}
+
+impl Triggers {
+    pub fn new(rules: &[Rule]) -> Self {
+        if rules.len() > 256 {
+            panic!("Event records only have room to name 256 rules");
+        }
+        Triggers {
+            rules: rules.to_vec(),
+            state: vec![RuleState::default(); rules.len()],
+            latest: Vec::new(),
+        }
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Whether rule `index` has fired and not yet cleared.
+    pub fn is_active(&self, index: usize) -> bool {
+        self.state[index].fired
+    }
+
+    /// Feed a sample; `on_event` hears about any rule firing or clearing.
+    pub fn observe(&mut self, r: &Record, mut on_event: impl FnMut(Event)) {
+        match self.latest.iter_mut().find(|(c, _)| *c == r.channel) {
+            Some(latest) => latest.1 = r.value,
+            None => self.latest.push((r.channel, r.value)),
+        }
+
+        for (i, (rule, state)) in self.rules.iter().zip(self.state.iter_mut()).enumerate() {
+            if rule.channel != r.channel {
+                continue;
+            }
+            let gated = rule.when.is_some_and(|(channel, condition)| {
+                !self
+                    .latest
+                    .iter()
+                    .any(|&(c, v)| c == channel && condition.holds(v))
+            });
+            if !state.fired {
+                if gated || !rule.condition.holds(r.value) {
+                    state.holding_since = None;
+                    continue;
+                }
+                let since = *state.holding_since.get_or_insert(r.timestamp);
+                if r.timestamp.saturating_sub(since) >= rule.hold_ms {
+                    state.fired = true;
+                    on_event(Event {
+                        timestamp: r.timestamp,
+                        severity: rule.severity,
+                        kind: EventKind::Triggered(i as u8),
+                        value: r.value,
+                    });
+                }
+            } else if rule.condition.released(r.value, rule.hysteresis) {
+                *state = RuleState::default();
+                on_event(Event {
+                    timestamp: r.timestamp,
+                    severity: Severity::Info,
+                    kind: EventKind::Cleared(i as u8),
+                    value: r.value,
+                });
+            }
+        }
+    }
+}
+
+/// Samples from just before to just after an event, frozen.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaptureWindow {
+    pub event: Event,
+    pub records: Vec<Record>,
+}
+
+impl CaptureWindow {
+    /// As a plain log stream: the event record, then the samples.
+    pub fn encode(&self) -> Vec<u8> {
+        let event = self.event.to_record();
+        encode_stream(std::iter::once(&event).chain(&self.records))
+    }
+
+    /// Store as the next free `{n:08}.mcc`, returning its name.
+    pub fn save<F: LogFs>(&self, fs: &mut F) -> Result<String, F::Error> {
+        let next = fs
+            .list()?
+            .iter()
+            .filter_map(|n| parse_capture_file_name(n))
+            .max()
+            .map_or(0, |n| n + 1);
+        let name = capture_file_name(next);
+        fs.append(&name, &self.encode())?;
+        Ok(name)
+    }
+}
+
+pub fn capture_file_name(n: u32) -> String {
+    format!("{n:08}.mcc")
+}
+
+pub fn parse_capture_file_name(name: &str) -> Option<u32> {
+    let digits = name.strip_suffix(".mcc")?;
+    if digits.len() != 8 {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+/// Name and contents of every capture window saved on `fs`, oldest first.
+pub fn read_captures<F: LogFs>(fs: &mut F) -> Result<Vec<(String, Vec<u8>)>, F::Error> {
+    let mut names: Vec<String> = fs
+        .list()?
+        .into_iter()
+        .filter(|n| parse_capture_file_name(n).is_some())
+        .collect();
+    names.sort();
+    names
+        .into_iter()
+        .map(|n| fs.read(&n).map(|data| (n, data)))
+        .collect()
+}
+
+/// Keeps the last `N` high-rate samples in RAM, so that when an event
+/// fires, the lead-up can be frozen along with what follows. One window at
+/// a time: events during an open window don't start another.
+pub struct Capture<const N: usize> {
+    pre_ms: u32,
+    post_ms: u32,
+    recent: RingBuffer<N>,
+    open: Option<CaptureWindow>,
+}
+
+impl<const N: usize> Capture<N> {
+    pub fn new(pre_ms: u32, post_ms: u32) -> Self {
+        Capture {
+            pre_ms,
+            post_ms,
+            recent: RingBuffer::new(),
+            open: None,
+        }
+    }
+
+    /// Feed every sample, not just the ones worth logging. Returns a window
+    /// once its post-trigger time has passed.
+    pub fn push(&mut self, r: Record) -> Option<CaptureWindow> {
+        let Some(window) = self.open.as_mut() else {
+            self.recent.push(r);
+            return None;
+        };
+        if r.timestamp.saturating_sub(window.event.timestamp) <= self.post_ms {
+            window.records.push(r);
+            return None;
+        }
+        self.recent.push(r);
+        self.open.take()
+    }
+
+    /// Freeze the lead-up to `event`. Ignored if a window is already open.
+    pub fn trigger(&mut self, event: Event) {
+        if self.open.is_some() {
+            return;
+        }
+        let start = event.timestamp.saturating_sub(self.pre_ms);
+        let records = self
+            .recent
+            .iter()
+            .filter(|r| r.timestamp >= start)
+            .copied()
+            .collect();
+        self.recent.clear();
+        self.open = Some(CaptureWindow { event, records });
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.open.is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::{BATTERY_V, REGULATOR_TEMP_C, RPM};
+    use crate::memfs::MemFs;
+    use crate::record::Records;
+
+    #[test]
+    fn events_round_trip_through_records() {
+        for event in [
+            Event {
+                timestamp: 12,
+                severity: Severity::Critical,
+                kind: EventKind::Triggered(255),
+                value: -1,
+            },
+            Event::output_changed(99, 3, 255),
+            Event::switch_changed(100, 15, SWITCH_HELD),
+            Event {
+                timestamp: 0,
+                severity: Severity::Info,
+                kind: EventKind::Cleared(0),
+                value: 8000,
+            },
+        ] {
+            let r = event.to_record();
+            assert!(is_event(r.channel));
+            assert_eq!(Event::from_record(&r), Some(event));
+        }
+        assert_eq!(Event::from_record(&Record::new(0, RPM, 1)), None);
+    }
+
+    #[test]
+    fn hysteresis_hold_and_gating() {
+        let overheat = Rule {
+            hysteresis: 200,
+            hold_ms: 2000,
+            ..Rule::new(REGULATOR_TEMP_C, Condition::Above(8000), Severity::Warning)
+        };
+        let sag = Rule {
+            when: Some((RPM, Condition::Above(3000))),
+            ..Rule::new(BATTERY_V, Condition::Below(12_500), Severity::Critical)
+        };
+        let mut triggers = Triggers::new(&[overheat, sag]);
+        let mut events = Vec::new();
+        let mut feed = |t: &mut Triggers, r: Record| t.observe(&r, |e| events.push(e));
+
+        for (t, v) in [
+            (0, 8100),
+            (1000, 7900),
+            (2000, 8100),
+            (4000, 8050),
+            (5000, 7850),
+            (6000, 7700),
+        ] {
+            feed(&mut triggers, Record::new(t, REGULATOR_TEMP_C, v));
+        }
+        // sagging at idle is expected; under load it isn't
+        feed(&mut triggers, Record::new(7000, RPM, 1200));
+        feed(&mut triggers, Record::new(7100, BATTERY_V, 12_000));
+        feed(&mut triggers, Record::new(8000, RPM, 5000));
+        feed(&mut triggers, Record::new(8100, BATTERY_V, 12_000));
+        assert!(triggers.is_active(1));
+
+        let summary: Vec<(u32, EventKind)> = events.iter().map(|e| (e.timestamp, e.kind)).collect();
+        assert_eq!(
+            summary,
+            [
+                (4000, EventKind::Triggered(0)),
+                (6000, EventKind::Cleared(0)),
+                (8100, EventKind::Triggered(1))
+            ]
+        );
+        assert_eq!(events[2].severity, Severity::Critical);
+    }
+
+    #[test]
+    fn capture_freezes_pre_and_post_windows() {
+        let mut capture: Capture<64> = Capture::new(300, 200);
+        let rec = |t| Record::new(t, RPM, t as i32);
+        let mut windows = Vec::new();
+        for t in (0..1000).step_by(100) {
+            if t == 500 {
+                let event = Event::output_changed(t, 0, 1);
+                capture.trigger(event);
+                capture.trigger(Event::output_changed(t, 1, 1));
+            }
+            windows.extend(capture.push(rec(t)));
+        }
+        assert_eq!(windows.len(), 1);
+        let times: Vec<u32> = windows[0].records.iter().map(|r| r.timestamp).collect();
+        assert_eq!(times, [200, 300, 400, 500, 600, 700]);
+        assert_eq!(windows[0].event.kind, EventKind::OutputChanged(0));
+
+        let mut fs = MemFs::new(4096);
+        assert_eq!(windows[0].save(&mut fs), Ok("00000000.mcc".to_owned()));
+        assert_eq!(windows[0].save(&mut fs), Ok("00000001.mcc".to_owned()));
+        let saved = read_captures(&mut fs).unwrap();
+        let records: Vec<Record> = Records::new(&saved[0].1).unwrap().collect();
+        assert_eq!(Event::from_record(&records[0]), Some(windows[0].event));
+        assert_eq!(records.len(), 7);
+    }
+}
@@ -0,0 +1,508 @@
+// mcaux-datalogger/src/export.rs
+
+//! Decoded records reshaped into a table, one column per channel, and
+//! written out as CSV, JSON Lines or Parquet for spreadsheets and notebooks.
+//! Rows can carry wall-clock time as well as time since boot.
+
+use std::io::{self, Write};
+
+use crate::channel::ChannelRegistry;
+use crate::event::{Event, EventKind, is_event};
+use crate::record::{ChannelId, Record};
+use crate::retention::Stat;
+use crate::time::{TimeBase, format_utc, is_time_sync, split_boots};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExportOptions {
+    /// Keep records at or after this many ms since boot.
+    pub start_ms: Option<u32>,
+    /// Keep records before this many ms since boot.
+    pub end_ms: Option<u32>,
+    /// Instead of a row per distinct timestamp, a row every this many ms.
+    pub resample_ms: Option<u32>,
+    /// Place each boot on the calendar by its sync points, adding UTC to
+    /// every row and ordering boots by it. Boots without sync points are
+    /// left out.
+    pub wall_clock: bool,
+}
+
+impl ExportOptions {
+    fn in_range(&self, t: u32) -> bool {
+        self.start_ms.is_none_or(|s| t >= s) && self.end_ms.is_none_or(|e| t < e)
+    }
+}
+
+/// One column of a `Table`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Column {
+    pub channel: ChannelId,
+    pub name: String,
+    pub unit: &'static str,
+}
+
+/// Records in wide form: a time column and one column per channel seen.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Table {
+    pub columns: Vec<Column>,
+    /// (ms since boot, value per column). A cell is None where the channel
+    /// has nothing to say at that time.
+    pub rows: Vec<(u32, Vec<Option<f64>>)>,
+    /// UTC ms of each row, when built with `wall_clock`; otherwise empty.
+    pub utc: Vec<i64>,
+}
+
+impl Table {
+    /// Scale `records` (in logged order) to physical units via `registry`.
+    /// Channels it doesn't know keep their raw values, in a column named
+    /// after their id; downsampled ones get `_min`, `_mean` and `_max`
+    /// columns. Controller outputs get an `output_N` column of their level,
+    /// from their change events; other events and sync points are left
+    /// out.
+    ///
+    /// Without resampling, each distinct timestamp gets a row with cells
+    /// only for the channels logged then. Resampling holds each channel's
+    /// last value until its next record, as the logger's deadband implies.
+    pub fn build(records: &[Record], registry: &ChannelRegistry, opts: &ExportOptions) -> Table {
+        let samples = |records: &[Record]| -> Vec<Record> {
+            let mut samples: Vec<Record> = records
+                .iter()
+                .filter(|r| {
+                    output(r.channel).is_some() || !is_event(r.channel) && !is_time_sync(r.channel)
+                })
+                .copied()
+                .collect();
+            samples.sort_by_key(|r| r.timestamp);
+            samples
+        };
+        let all = samples(records);
+
+        let mut ids: Vec<ChannelId> = all.iter().map(|r| r.channel).collect();
+        ids.sort();
+        ids.dedup();
+        // registry order first, so the usual channels sit in the usual places
+        ids.sort_by_key(|id| {
+            let source = Stat::parse(*id).map_or(*id, |(c, _)| c);
+            registry
+                .iter()
+                .position(|c| c.id == source)
+                .unwrap_or(usize::MAX)
+        });
+        // downsampled records get a column per statistic of their channel
+        let source = |id: ChannelId| {
+            let (source, stat) = Stat::parse(id).map_or((id, None), |(c, s)| (c, Some(s)));
+            (registry.get(source), stat)
+        };
+        let columns: Vec<Column> = ids
+            .iter()
+            .map(|&id| match (source(id), output(id)) {
+                (_, Some(n)) => Column {
+                    channel: id,
+                    name: format!("output_{n}"),
+                    unit: "",
+                },
+                ((Some(c), stat), None) => Column {
+                    channel: id,
+                    name: match stat {
+                        Some(stat) => format!("{}_{}", c.name, stat.name()),
+                        None => c.name.to_owned(),
+                    },
+                    unit: c.unit,
+                },
+                ((None, _), None) => Column {
+                    channel: id,
+                    name: format!("ch{}", id.0),
+                    unit: "raw",
+                },
+            })
+            .collect();
+        let physical = |r: &Record| -> f64 {
+            match source(r.channel).0 {
+                Some(c) => {
+                    let places = decimals(c.scale).max(decimals(c.offset));
+                    let v = r.value as f64 * c.scale as f64 + c.offset as f64;
+                    (v * 10f64.powi(places)).round() / 10f64.powi(places)
+                }
+                None => r.value as f64,
+            }
+        };
+        if !opts.wall_clock {
+            let rows = rows(&all, &ids, opts, &physical);
+            return Table {
+                columns,
+                rows,
+                utc: Vec::new(),
+            };
+        }
+
+        let mut boots: Vec<(Vec<_>, Vec<i64>)> = split_boots(records)
+            .into_iter()
+            .filter_map(|boot| {
+                let base = TimeBase::from_records(&records[boot.clone()]);
+                base.is_synced().then(|| {
+                    let rows = rows(&samples(&records[boot]), &ids, opts, &physical);
+                    let utc = rows.iter().map(|(t, _)| base.to_utc(*t).unwrap()).collect();
+                    (rows, utc)
+                })
+            })
+            .filter(|(rows, _)| !rows.is_empty())
+            .collect();
+        boots.sort_by_key(|(_, utc)| utc[0]);
+        let (rows, utc) =
+            boots
+                .into_iter()
+                .fold((Vec::new(), Vec::new()), |(mut rows, mut utc), (r, u)| {
+                    rows.extend(r);
+                    utc.extend(u);
+                    (rows, utc)
+                });
+        Table { columns, rows, utc }
+    }
+
+    /// Header `time_s,[time_utc,]<channel>,...`; empty cells where there's
+    /// no value.
+    pub fn write_csv<W: Write>(&self, mut w: W) -> io::Result<()> {
+        write!(w, "time_s")?;
+        if !self.utc.is_empty() {
+            write!(w, ",time_utc")?;
+        }
+        for c in &self.columns {
+            write!(w, ",{}", c.name)?;
+        }
+        writeln!(w)?;
+        for (i, (t, cells)) in self.rows.iter().enumerate() {
+            write!(w, "{}", seconds(*t))?;
+            if let Some(utc) = self.utc.get(i) {
+                write!(w, ",{}", format_utc(*utc))?;
+            }
+            for cell in cells {
+                match cell {
+                    Some(v) => write!(w, ",{v}")?,
+                    None => write!(w, ",")?,
+                }
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+
+    /// One object per row, `{"time_s":1.5,"rpm":3200}`, leaving out
+    /// channels with no value.
+    pub fn write_json_lines<W: Write>(&self, mut w: W) -> io::Result<()> {
+        for (i, (t, cells)) in self.rows.iter().enumerate() {
+            write!(w, "{{\"time_s\":{}", seconds(*t))?;
+            if let Some(utc) = self.utc.get(i) {
+                write!(w, ",\"time_utc\":\"{}\"", format_utc(*utc))?;
+            }
+            for (c, cell) in self.columns.iter().zip(cells) {
+                if let Some(v) = cell
+                    && v.is_finite()
+                {
+                    write!(w, ",\"{}\":{v}", c.name)?;
+                }
+            }
+            writeln!(w, "}}")?;
+        }
+        Ok(())
+    }
+
+    /// A single row group: required double `time_s`, a UTC timestamp
+    /// `time_utc` if there is one, then an optional double per channel.
+    #[cfg(feature = "parquet")]
+    pub fn write_parquet<W: Write + Send>(&self, w: W) -> parquet::errors::Result<()> {
+        use std::sync::Arc;
+
+        use parquet::data_type::{DoubleType, Int64Type};
+        use parquet::file::properties::WriterProperties;
+        use parquet::file::writer::SerializedFileWriter;
+        use parquet::schema::parser::parse_message_type;
+
+        let mut message = String::from("message mcaux_log { required double time_s;");
+        let with_utc = !self.utc.is_empty();
+        if with_utc {
+            message.push_str(" required int64 time_utc (TIMESTAMP(MILLIS, true));");
+        }
+        for c in &self.columns {
+            message.push_str(&format!(" optional double {};", c.name));
+        }
+        message.push_str(" }");
+        let schema = Arc::new(parse_message_type(&message)?);
+        let props = Arc::new(WriterProperties::builder().build());
+        let mut writer = SerializedFileWriter::new(w, schema, props)?;
+
+        let mut group = writer.next_row_group()?;
+        let times: Vec<f64> = self.rows.iter().map(|(t, _)| *t as f64 / 1000.0).collect();
+        let first_channel = 1 + with_utc as usize;
+        let mut index = 0;
+        while let Some(mut column) = group.next_column()? {
+            if index == 0 {
+                column
+                    .typed::<DoubleType>()
+                    .write_batch(&times, None, None)?;
+            } else if index < first_channel {
+                column
+                    .typed::<Int64Type>()
+                    .write_batch(&self.utc, None, None)?;
+            } else {
+                let cells = self
+                    .rows
+                    .iter()
+                    .map(|(_, cells)| cells[index - first_channel]);
+                let values: Vec<f64> = cells.clone().flatten().collect();
+                let levels: Vec<i16> = cells.map(|c| c.is_some() as i16).collect();
+                column
+                    .typed::<DoubleType>()
+                    .write_batch(&values, Some(&levels), None)?;
+            }
+            column.close()?;
+            index += 1;
+        }
+        group.close()?;
+        writer.close()?;
+        Ok(())
+    }
+}
+
+/// A row per distinct timestamp of `records` (sorted by time), or with
+/// resampling, a row every period holding each channel's last value.
+fn rows(
+    records: &[Record],
+    ids: &[ChannelId],
+    opts: &ExportOptions,
+    physical: &impl Fn(&Record) -> f64,
+) -> Vec<(u32, Vec<Option<f64>>)> {
+    let col = |id: ChannelId| ids.iter().position(|&i| i == id).unwrap();
+
+    let mut rows: Vec<(u32, Vec<Option<f64>>)> = Vec::new();
+    match opts.resample_ms {
+        None => {
+            for r in records.iter().filter(|r| opts.in_range(r.timestamp)) {
+                if rows.last().is_none_or(|(t, _)| *t != r.timestamp) {
+                    rows.push((r.timestamp, vec![None; ids.len()]));
+                }
+                rows.last_mut().unwrap().1[col(r.channel)] = Some(physical(r));
+            }
+        }
+        Some(period) => {
+            if period == 0 {
+                panic!("Resampling period must be nonzero");
+            }
+            let first = records.iter().find(|r| opts.in_range(r.timestamp));
+            let last = records.iter().rev().find(|r| opts.in_range(r.timestamp));
+            // no grid point left before u32 wraps means nothing to sample
+            let start = first.and_then(|first| {
+                let from = opts.start_ms.unwrap_or(first.timestamp);
+                from.div_ceil(period).checked_mul(period)
+            });
+            if let (Some(start), Some(last)) = (start, last) {
+                let mut held = vec![None; ids.len()];
+                let mut pending = records.iter().peekable();
+                let mut t = start;
+                while t <= last.timestamp {
+                    while let Some(r) = pending.next_if(|r| r.timestamp <= t) {
+                        held[col(r.channel)] = Some(physical(r));
+                    }
+                    rows.push((t, held.clone()));
+                    let Some(next) = t.checked_add(period) else {
+                        break;
+                    };
+                    t = next;
+                }
+            }
+        }
+    }
+    rows
+}
+
+/// Which controller output `channel` carries the changes of, if any.
+fn output(channel: ChannelId) -> Option<u8> {
+    match Event::from_record(&Record::new(0, channel, 0))?.kind {
+        EventKind::OutputChanged(n) => Some(n),
+        _ => None,
+    }
+}
+
+/// Decimal places in the shortest form of `x`. 0.01f32 widens to
+/// 0.009999999776 as f64, so round results back to what the channel means.
+fn decimals(x: f32) -> i32 {
+    x.to_string()
+        .split_once('.')
+        .map_or(0, |(_, frac)| frac.len() as i32)
+}
+
+/// Exact decimal seconds, rather than whatever f64 makes of ms / 1000.
+fn seconds(ms: u32) -> String {
+    format!("{}.{:03}", ms / 1000, ms % 1000)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::{BATTERY_V, RPM};
+
+    fn records() -> Vec<Record> {
+        vec![
+            Record::new(0, RPM, 3000),
+            Record::new(0, BATTERY_V, 13_800),
+            Record::new(250, RPM, 3500),
+            Record::new(700, ChannelId(42), 7),
+            Record::new(1000, BATTERY_V, 14_100),
+        ]
+    }
+
+    #[test]
+    fn wide_csv_and_json_lines() {
+        let table = Table::build(
+            &records(),
+            &ChannelRegistry::standard(),
+            &Default::default(),
+        );
+        let mut csv = Vec::new();
+        table.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "time_s,rpm,battery_v,ch42\n\
+             0.000,3000,13.8,\n\
+             0.250,3500,,\n\
+             0.700,,,7\n\
+             1.000,,14.1,\n"
+        );
+
+        let opts = ExportOptions {
+            start_ms: Some(200),
+            end_ms: Some(1000),
+            ..Default::default()
+        };
+        let table = Table::build(&records(), &ChannelRegistry::standard(), &opts);
+        let mut json = Vec::new();
+        table.write_json_lines(&mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\"time_s\":0.250,\"rpm\":3500}\n{\"time_s\":0.700,\"ch42\":7}\n"
+        );
+    }
+
+    #[test]
+    fn resampling_holds_last_value() {
+        let opts = ExportOptions {
+            start_ms: Some(100),
+            resample_ms: Some(300),
+            ..Default::default()
+        };
+        let table = Table::build(&records(), &ChannelRegistry::standard(), &opts);
+        let times: Vec<u32> = table.rows.iter().map(|(t, _)| *t).collect();
+        assert_eq!(times, [300, 600, 900]);
+        let rpm: Vec<Option<f64>> = table.rows.iter().map(|(_, c)| c[0]).collect();
+        assert_eq!(rpm, [Some(3500.0); 3]);
+        // a channel that hasn't spoken yet stays empty
+        assert_eq!(table.rows[1].1[2], None);
+        assert_eq!(table.rows[2].1[2], Some(7.0));
+
+        let late = [Record::new(u32::MAX - 10, RPM, 3000)];
+        let opts = ExportOptions {
+            resample_ms: Some(300),
+            ..Default::default()
+        };
+        let table = Table::build(&late, &ChannelRegistry::standard(), &opts);
+        assert!(table.rows.is_empty());
+    }
+
+    #[test]
+    fn wall_clock_places_whole_boots() {
+        use crate::time::{SyncPoint, TimeSource};
+
+        // 2026-10-19T08:30:00Z
+        let morning = 1_792_398_600_000;
+        let fix = |tick_ms, utc_ms| {
+            SyncPoint {
+                tick_ms,
+                utc_ms,
+                source: TimeSource::Gps,
+            }
+            .to_record()
+        };
+        let records = [
+            // the afternoon ride, logged first; GPS fix after the first sample
+            Record::new(0, RPM, 3000),
+            fix(2_000, morning + 6 * 3_600_000),
+            Record::new(2_500, RPM, 3500),
+            // a boot that never learned the time
+            Record::new(1_000, RPM, 1000),
+            // the morning ride
+            Record::new(100, BATTERY_V, 13_800),
+            fix(1_000, morning),
+        ];
+        let opts = ExportOptions {
+            wall_clock: true,
+            ..Default::default()
+        };
+        let table = Table::build(&records, &ChannelRegistry::standard(), &opts);
+        let mut csv = Vec::new();
+        table.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "time_s,time_utc,rpm,battery_v\n\
+             0.100,2026-10-19T08:29:59.100Z,,13.8\n\
+             0.000,2026-10-19T14:29:58.000Z,3000,\n\
+             2.500,2026-10-19T14:30:00.500Z,3500,\n"
+        );
+    }
+
+    #[test]
+    fn output_levels_sit_beside_temperature() {
+        use crate::channel::REGULATOR_TEMP_C;
+        use crate::event::{Event, SWITCH_CLOSED};
+
+        let records = [
+            Record::new(0, REGULATOR_TEMP_C, 4000),
+            Event::switch_changed(500, 1, SWITCH_CLOSED).to_record(),
+            Event::output_changed(700, 1, 2).to_record(),
+            Record::new(1000, REGULATOR_TEMP_C, 4300),
+        ];
+        let opts = ExportOptions {
+            resample_ms: Some(500),
+            ..Default::default()
+        };
+        let table = Table::build(&records, &ChannelRegistry::standard(), &opts);
+        let mut csv = Vec::new();
+        table.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "time_s,regulator_temp_c,output_1\n\
+             0.000,40,\n\
+             0.500,40,\n\
+             1.000,43,2\n"
+        );
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn parquet_round_trip() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+
+        let table = Table::build(
+            &records(),
+            &ChannelRegistry::standard(),
+            &Default::default(),
+        );
+        let mut bytes = Vec::new();
+        table.write_parquet(&mut bytes).unwrap();
+        let reader = SerializedFileReader::new(bytes::Bytes::from(bytes)).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 4);
+        let fields = reader
+            .metadata()
+            .file_metadata()
+            .schema()
+            .get_fields()
+            .len();
+        assert_eq!(fields, 4);
+        let rows: Vec<String> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|r| r.unwrap().to_string())
+            .collect();
+        assert_eq!(
+            rows[1],
+            "{time_s: 0.25, rpm: 3500.0, battery_v: null, ch42: null}"
+        );
+    }
+}
@@ -0,0 +1,179 @@
+// mcaux-datalogger/src/flash.rs
+
+/// Raw NOR-style flash: erase whole blocks to all ones, then program can
+/// only clear bits.
+pub trait BlockDevice {
+    type Error: core::fmt::Debug;
+
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> usize;
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    fn program(&mut self, offset: usize, data: &[u8]) -> Result<(), Self::Error>;
+
+    fn erase(&mut self, block: usize) -> Result<(), Self::Error>;
+
+    fn capacity(&self) -> usize {
+        self.block_size() * self.block_count()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlashError {
+    OutOfRange,
+    /// Simulated power loss; everything fails until `restore_power`.
+    PowerCut,
+}
+
+/// Flash simulated in RAM, so littlefs and friends can be exercised on
+/// Linux. Behaves like NOR: programming ANDs into what's there.
+#[derive(Clone, Debug)]
+pub struct RamBlockDevice {
+    data: Vec<u8>,
+    block_size: usize,
+    /// Bytes that may still be programmed before the lights go out
+    power_budget: Option<usize>,
+    powered_off: bool,
+    erases: usize,
+}
+
+impl RamBlockDevice {
+    /// Fresh (fully erased) flash.
+    pub fn new(block_size: usize, block_count: usize) -> Self {
+        RamBlockDevice {
+            data: vec![0xff; block_size * block_count],
+            block_size,
+            power_budget: None,
+            powered_off: false,
+            erases: 0,
+        }
+    }
+
+    /// Wrap an existing image, such as one read back from the bike.
+    pub fn from_image(image: Vec<u8>, block_size: usize) -> Self {
+        if !image.len().is_multiple_of(block_size) {
+            panic!("Flash image is not a whole number of blocks");
+        }
+        RamBlockDevice {
+            data: image,
+            block_size,
+            power_budget: None,
+            powered_off: false,
+            erases: 0,
+        }
+    }
+
+    pub fn image(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn image_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    /// Total block erases so far, as a crude wear gauge.
+    pub fn erase_count(&self) -> usize {
+        self.erases
+    }
+
+    /// Lose power once `bytes` more bytes have been programmed.
+    pub fn cut_power_after(&mut self, bytes: usize) {
+        self.power_budget = Some(bytes);
+    }
+
+    pub fn restore_power(&mut self) {
+        self.power_budget = None;
+        self.powered_off = false;
+    }
+
+    fn check(&self, offset: usize, len: usize) -> Result<(), FlashError> {
+        if self.powered_off {
+            return Err(FlashError::PowerCut);
+        }
+        if offset
+            .checked_add(len)
+            .is_none_or(|end| end > self.data.len())
+        {
+            return Err(FlashError::OutOfRange);
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for RamBlockDevice {
+    type Error = FlashError;
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> usize {
+        self.data.len() / self.block_size
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), FlashError> {
+        self.check(offset, buf.len())?;
+        buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn program(&mut self, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+        self.check(offset, data.len())?;
+        let (data, cut) = match self.power_budget {
+            Some(budget) if budget < data.len() => (&data[..budget], true),
+            _ => (data, false),
+        };
+        if let Some(budget) = self.power_budget.as_mut() {
+            *budget -= data.len();
+        }
+        for (cell, &byte) in self.data[offset..].iter_mut().zip(data) {
+            *cell &= byte;
+        }
+        if cut {
+            self.powered_off = true;
+            return Err(FlashError::PowerCut);
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, block: usize) -> Result<(), FlashError> {
+        let offset = block * self.block_size;
+        self.check(offset, self.block_size)?;
+        self.data[offset..offset + self.block_size].fill(0xff);
+        self.erases += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nor_semantics() {
+        let mut dev = RamBlockDevice::new(16, 4);
+        assert_eq!(dev.capacity(), 64);
+        dev.program(3, &[0x0f, 0xf0]).unwrap();
+        dev.program(3, &[0x3c, 0xff]).unwrap();
+        let mut buf = [0; 3];
+        dev.read(2, &mut buf).unwrap();
+        assert_eq!(buf, [0xff, 0x0c, 0xf0]);
+        dev.erase(0).unwrap();
+        dev.read(2, &mut buf).unwrap();
+        assert_eq!(buf, [0xff; 3]);
+        assert_eq!(dev.read(63, &mut buf), Err(FlashError::OutOfRange));
+    }
+
+    #[test]
+    fn power_cut_tears_a_program() {
+        let mut dev = RamBlockDevice::new(16, 1);
+        dev.cut_power_after(2);
+        assert_eq!(dev.program(0, &[0, 0, 0, 0]), Err(FlashError::PowerCut));
+        assert_eq!(dev.image()[..4], [0, 0, 0xff, 0xff]);
+        assert_eq!(dev.erase(0), Err(FlashError::PowerCut));
+        dev.restore_power();
+        dev.erase(0).unwrap();
+    }
+}
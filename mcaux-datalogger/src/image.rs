@@ -0,0 +1,141 @@
+// mcaux-datalogger/src/image.rs
+
+//! A log image: every log file from the bike's storage packed into one
+//! self-delimiting blob, for saving to disk or streaming over serial.
+//!
+//! `MCI`, version byte, u32 file count, then per file: u8 name length,
+//! name, u32 data length, data. Integers little-endian.
+
+use std::io::{self, Read};
+
+use crate::event::parse_capture_file_name;
+use crate::memfs::MemFs;
+use crate::record::DecodeError;
+use crate::session::SESSIONS_FILE;
+use crate::storage::{LogFs, StorageError, parse_log_file_name};
+
+pub const IMAGE_MAGIC: [u8; 3] = *b"MCI";
+pub const IMAGE_VERSION: u8 = 1;
+pub const IMAGE_HEADER_LEN: usize = IMAGE_MAGIC.len() + 1 + 4;
+
+/// Pack every log file on `fs`, the session summaries and any capture
+/// windows into an image.
+pub fn encode_image<F: LogFs>(fs: &mut F) -> Result<Vec<u8>, StorageError<F::Error>> {
+    let mut names: Vec<String> = fs
+        .list()
+        .map_err(StorageError::Fs)?
+        .into_iter()
+        .filter(|n| {
+            parse_log_file_name(n).is_some()
+                || parse_capture_file_name(n).is_some()
+                || n == SESSIONS_FILE
+        })
+        .collect();
+    names.sort();
+
+    let mut out = IMAGE_MAGIC.to_vec();
+    out.push(IMAGE_VERSION);
+    out.extend_from_slice(&(names.len() as u32).to_le_bytes());
+    for name in names {
+        let data = fs.read(&name).map_err(StorageError::Fs)?;
+        out.push(name.len() as u8);
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&data);
+    }
+    Ok(out)
+}
+
+/// Unpack an image into an in-memory filesystem holding just those files.
+pub fn decode_image(mut bytes: &[u8]) -> Result<MemFs, DecodeError> {
+    read_image(&mut bytes).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => DecodeError::Short,
+        _ => e
+            .into_inner()
+            .and_then(|inner| inner.downcast::<DecodeError>().ok())
+            .map_or(DecodeError::BadMagic, |b| *b),
+    })
+}
+
+/// Read exactly one image from a stream, such as a serial port, stopping
+/// at its end.
+pub fn read_image<R: Read>(r: &mut R) -> io::Result<MemFs> {
+    let mut header = [0u8; IMAGE_HEADER_LEN];
+    r.read_exact(&mut header)?;
+    if header[..3] != IMAGE_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            DecodeError::BadMagic,
+        ));
+    }
+    if header[3] != IMAGE_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            DecodeError::UnsupportedVersion(header[3]),
+        ));
+    }
+    let count = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+    let mut files = Vec::new();
+    for _ in 0..count {
+        let mut len = [0u8; 1];
+        r.read_exact(&mut len)?;
+        let name = read_vec(r, len[0] as usize)?;
+        let name = String::from_utf8(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, DecodeError::BadMagic))?;
+        let mut len = [0u8; 4];
+        r.read_exact(&mut len)?;
+        let data = read_vec(r, u32::from_le_bytes(len) as usize)?;
+        files.push((name, data));
+    }
+    Ok(MemFs::from_files(files))
+}
+
+/// `len` bytes, without trusting `len` enough to allocate it all up front:
+/// a corrupt length shouldn't ask for gigabytes.
+fn read_vec<R: Read>(r: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    r.by_ref().take(len as u64).read_to_end(&mut out)?;
+    if out.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::record::{ChannelId, Record};
+    use crate::storage::{RotatingLog, RotationPolicy};
+
+    #[test]
+    fn image_round_trip() {
+        let policy = RotationPolicy {
+            max_file_bytes: 64,
+            ..Default::default()
+        };
+        let mut log = RotatingLog::open(MemFs::new(4096), policy).unwrap();
+        let records: Vec<Record> = (0..20)
+            .map(|t| Record::new(t * 10, ChannelId(1), -(t as i32)))
+            .collect();
+        log.write(&records).unwrap();
+
+        let image = encode_image(log.fs_mut()).unwrap();
+        let mut copy = RotatingLog::open(decode_image(&image).unwrap(), policy).unwrap();
+        assert_eq!(copy.files().unwrap(), log.files().unwrap());
+        assert_eq!(copy.read_all().unwrap(), records);
+
+        assert_eq!(
+            decode_image(&image[..image.len() - 1]).err(),
+            Some(DecodeError::Short)
+        );
+        assert_eq!(
+            decode_image(b"MCX\x01\0\0\0\0").err(),
+            Some(DecodeError::BadMagic)
+        );
+        assert_eq!(
+            decode_image(b"MCI\x07\0\0\0\0").err(),
+            Some(DecodeError::UnsupportedVersion(7))
+        );
+    }
+}
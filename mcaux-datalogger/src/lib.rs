@@ -0,0 +1,54 @@
+// mcaux-datalogger/src/lib.rs
+
+//! Local storage and remote retrieval of time-series info from the bike.
+
+pub mod channel;
+pub mod charging;
+pub mod compress;
+pub mod controls;
+pub mod crc;
+pub mod ds18b20;
+pub mod event;
+pub mod export;
+pub mod flash;
+pub mod image;
+#[cfg(feature = "littlefs")]
+pub mod littlefs;
+pub mod memfs;
+pub mod protocol;
+pub mod query;
+pub mod record;
+pub mod recover;
+pub mod retention;
+pub mod ring;
+pub mod sensor;
+pub mod session;
+pub mod sim;
+pub mod storage;
+pub mod tach;
+pub mod time;
+pub mod tmp117;
+
+pub use channel::{Channel, ChannelRegistry, Scheduler};
+pub use charging::{ChargeClassifier, ChargeLimits, ChargeState, ChargingMonitor, VoltageDivider};
+pub use compress::{BlockEncoder, Blocks, compress, decompress};
+pub use controls::ControllerLog;
+pub use ds18b20::{BitBangOneWire, Ds18b20, OneWire};
+pub use event::{Capture, CaptureWindow, Condition, Event, EventKind, Rule, Severity, Triggers};
+pub use export::{ExportOptions, Table};
+pub use flash::{BlockDevice, RamBlockDevice};
+pub use image::{decode_image, encode_image, read_image};
+pub use memfs::MemFs;
+pub use protocol::{ByteStream, Device, Host, IoStream, LinkError};
+pub use query::{Aggregation, Bucket, Query, QueryItem};
+pub use record::{ChannelId, DecodeError, Record, RecordSink, Records};
+pub use recover::{RecoveryReport, StreamScan, recover, scan_stream};
+pub use retention::{RetentionPolicy, RetentionReport, apply_retention};
+pub use ring::RingBuffer;
+pub use sensor::{AdcChannel, Ntc, NtcWiring, SensorError, SteinhartHart, TemperatureSensor};
+pub use session::{SessionLog, SessionSummary, Summarizer, Threshold};
+pub use sim::{RideConfig, RideSim};
+pub use storage::{LogFs, RotatingLog, RotationPolicy};
+pub use tach::{TachConfig, Tachometer};
+pub use time::{Clock, SyncPoint, TimeBase, TimeSource};
+pub use tmp117::Tmp117;
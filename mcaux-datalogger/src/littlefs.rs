@@ -0,0 +1,169 @@
+// mcaux-datalogger/src/littlefs.rs
+
+//! littlefs (via the littlefs2 crate) as the log's filesystem. littlefs is
+//! copy-on-write, so a write interrupted by power loss leaves the file as
+//! it was before the write rather than half-updated.
+
+use littlefs2::consts;
+use littlefs2::driver::Storage;
+use littlefs2::fs::Filesystem;
+use littlefs2::io::{self, Write};
+use littlefs2::path::PathBuf;
+
+use crate::flash::BlockDevice;
+use crate::storage::LogFs;
+
+/// Adapts one of our block devices to littlefs. littlefs wants the geometry
+/// at compile time, so it's repeated here as const parameters and checked
+/// against the device on construction.
+pub struct LfsFlash<D, const BLOCK_SIZE: usize, const BLOCK_COUNT: usize> {
+    dev: D,
+}
+
+impl<D: BlockDevice, const BLOCK_SIZE: usize, const BLOCK_COUNT: usize>
+    LfsFlash<D, BLOCK_SIZE, BLOCK_COUNT>
+{
+    pub fn new(dev: D) -> Self {
+        if dev.block_size() != BLOCK_SIZE || dev.block_count() != BLOCK_COUNT {
+            panic!("Block device geometry doesn't match the littlefs configuration");
+        }
+        LfsFlash { dev }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.dev
+    }
+
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.dev
+    }
+
+    pub fn into_inner(self) -> D {
+        self.dev
+    }
+}
+
+impl<D: BlockDevice, const BLOCK_SIZE: usize, const BLOCK_COUNT: usize> Storage
+    for LfsFlash<D, BLOCK_SIZE, BLOCK_COUNT>
+{
+    const READ_SIZE: usize = 16;
+    const WRITE_SIZE: usize = 16;
+    const BLOCK_SIZE: usize = BLOCK_SIZE;
+    const BLOCK_COUNT: usize = BLOCK_COUNT;
+    const BLOCK_CYCLES: isize = 500;
+    type CACHE_SIZE = consts::U256;
+    type LOOKAHEAD_SIZE = consts::U2;
+
+    fn read(&mut self, off: usize, buf: &mut [u8]) -> io::Result<usize> {
+        self.dev.read(off, buf).map_err(|_| io::Error::IO)?;
+        Ok(buf.len())
+    }
+
+    fn write(&mut self, off: usize, data: &[u8]) -> io::Result<usize> {
+        self.dev.program(off, data).map_err(|_| io::Error::IO)?;
+        Ok(data.len())
+    }
+
+    fn erase(&mut self, off: usize, len: usize) -> io::Result<usize> {
+        for block in off / BLOCK_SIZE..(off + len).div_ceil(BLOCK_SIZE) {
+            self.dev.erase(block).map_err(|_| io::Error::IO)?;
+        }
+        Ok(len)
+    }
+}
+
+fn path(name: &str) -> io::Result<PathBuf> {
+    PathBuf::try_from(name).map_err(|_| io::Error::INVALID)
+}
+
+/// Log files live in the root directory.
+impl<S: Storage> LogFs for Filesystem<'_, S> {
+    type Error = io::Error;
+
+    fn append(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        self.open_file_with_options_and_then(
+            |options| options.write(true).create(true).append(true),
+            &path(name)?,
+            |file| file.write_all(data),
+        )
+    }
+
+    fn read(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        self.open_file_and_then(&path(name)?, |file| {
+            let mut buf = vec![0; file.len()?];
+            let mut filled = 0;
+            while filled < buf.len() {
+                match file.read(&mut buf[filled..])? {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            buf.truncate(filled);
+            Ok(buf)
+        })
+    }
+
+    fn remove(&mut self, name: &str) -> io::Result<()> {
+        Filesystem::remove(self, &path(name)?)
+    }
+
+    fn list(&mut self) -> io::Result<Vec<String>> {
+        self.read_dir_and_then(&path("/")?, |dir| {
+            let mut names = Vec::new();
+            for entry in dir {
+                let entry = entry?;
+                if entry.file_type().is_file() {
+                    names.push(entry.file_name().as_ref().to_owned());
+                }
+            }
+            Ok(names)
+        })
+    }
+
+    fn free_space(&mut self) -> io::Result<usize> {
+        self.available_space()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::flash::RamBlockDevice;
+    use crate::record::{ChannelId, Record};
+    use crate::storage::{RotatingLog, RotationPolicy};
+
+    type Flash = LfsFlash<RamBlockDevice, 512, 64>;
+
+    fn rec(t: u32) -> Record {
+        Record::new(t, ChannelId(1), t as i32)
+    }
+
+    #[test]
+    fn rotating_log_on_ram_flash() {
+        let mut flash = Flash::new(RamBlockDevice::new(512, 64));
+        Filesystem::format(&mut flash).unwrap();
+
+        let mut alloc = Filesystem::allocate();
+        let fs = Filesystem::mount(&mut alloc, &mut flash).unwrap();
+        let mut log = RotatingLog::open(fs, RotationPolicy::default()).unwrap();
+        let before = log.free_space().unwrap();
+        log.write(&(0..100).map(rec).collect::<Vec<_>>()).unwrap();
+        assert!(log.free_space().unwrap() <= before);
+        drop(log);
+
+        // power loss partway through the next write
+        flash.device_mut().cut_power_after(300);
+        let mut alloc = Filesystem::allocate();
+        let fs = Filesystem::mount(&mut alloc, &mut flash).unwrap();
+        let mut log = RotatingLog::open(fs, RotationPolicy::default()).unwrap();
+        assert!(log.write(&(100..200).map(rec).collect::<Vec<_>>()).is_err());
+        drop(log);
+
+        flash.device_mut().restore_power();
+        let mut alloc = Filesystem::allocate();
+        let fs = Filesystem::mount(&mut alloc, &mut flash).unwrap();
+        let mut log = RotatingLog::open(fs, RotationPolicy::default()).unwrap();
+        let all = log.read_all().unwrap();
+        assert_eq!(all[..100], (0..100).map(rec).collect::<Vec<_>>());
+    }
+}
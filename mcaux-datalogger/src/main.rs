@@ -1,3 +1,584 @@
-fn main() {
-    println!("Hello, world!");
+// mcaux-datalogger/src/main.rs
+
+//! Host-side tool for pulling logs off the bike and reading them.
+
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::BufWriter;
+use std::path::Path;
+use std::process::ExitCode;
+
+use mcaux_datalogger::channel::CHARGE_STATE;
+use mcaux_datalogger::event::{
+    SWITCH_CLOSED, SWITCH_OPENED, capture_file_name, parse_capture_file_name, read_captures,
+};
+use mcaux_datalogger::record::encode_stream;
+use mcaux_datalogger::retention::{Stat, is_rollup};
+use mcaux_datalogger::session::{QUANTILES, SESSIONS_FILE, read_summaries, summarize_sessions};
+use mcaux_datalogger::storage::{LogFile, StorageError, log_file_name, rollup_file_name};
+use mcaux_datalogger::time::{format_utc, split_boots};
+use mcaux_datalogger::{
+    Aggregation, ChannelId, ChannelRegistry, ChargeState, Event, EventKind, ExportOptions, Host,
+    IoStream, MemFs, QueryItem, Record, Records, RotatingLog, RotationPolicy, SessionSummary,
+    Severity, SyncPoint, Table, Threshold, TimeBase, decode_image, encode_image, recover,
+};
+
+const USAGE: &str = "\
+usage: mcaux-datalogger <command> <source> [options]
+
+<source> is a log image file, or the bike's serial port (put it in raw mode
+with a read timeout first, e.g. `stty -F /dev/ttyACM0 raw min 0 time 5`).
+
+commands:
+  list   <source>                         log files, record counts, time spans,
+                                          and event capture windows
+  dump   <source> [--file SEQ] [--channel NAME] [--from S] [--to S] [--bucket S]
+                                          records as a table; NAME `events`
+                                          shows just the events. With --bucket,
+                                          each channel's min, mean and max over
+                                          every S seconds instead
+  export <source> <out> [--format FMT] [--file SEQ] [--from S] [--to S] [--every S]
+                [--time boot|utc]
+                                          save the log image to a file, or with
+                                          FMT csv, jsonl or parquet, the records
+                                          one column per channel, limited to
+                                          seconds since boot FROM..TO, resampled
+                                          every S seconds. Timestamps restart at
+                                          every boot, so pick a file with --file,
+                                          or with --time utc add wall-clock time
+                                          from the bike's RTC or GPS and order
+                                          boots by it.
+  sessions <source> [--scan] [--above NAME=VALUE]...
+                                          ride summaries as recorded on the bike,
+                                          or with --scan worked out from the
+                                          records, timing each channel NAME above
+                                          VALUE (default regulator_temp_c=80)
+  check  <source>                         verify every frame's CRC and report
+                                          corrupt regions and where the last
+                                          good write ended
+  erase  <source>                         remove every log file, capture window
+                                          and session summary";
+
+type CliResult<T> = Result<T, Box<dyn Error>>;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("mcaux-datalogger: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[String]) -> CliResult<()> {
+    let (Some(command), Some(source)) = (args.first(), args.get(1)) else {
+        return Err(USAGE.into());
+    };
+    let rest = &args[2..];
+    match command.as_str() {
+        "list" => list(source),
+        "dump" => dump(source, rest),
+        "export" => {
+            let Some((out, opts)) = rest.split_first() else {
+                return Err(USAGE.into());
+            };
+            export(source, out, opts)
+        }
+        "sessions" => sessions(source, rest),
+        "check" => check(source),
+        "erase" => erase(source),
+        _ => Err(USAGE.into()),
+    }
+}
+
+fn is_serial(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        std::fs::metadata(path).is_ok_and(|m| m.file_type().is_char_device())
+    }
+    #[cfg(not(unix))]
+    {
+        let p = path.to_string_lossy();
+        p.starts_with(r"\\.\") || p.starts_with("COM")
+    }
+}
+
+fn connect(path: &Path) -> CliResult<Host<IoStream<File>>> {
+    let port = OpenOptions::new().read(true).write(true).open(path)?;
+    Ok(Host::new(IoStream(port)))
+}
+
+/// Everything in the source, as a log we can read.
+fn open(source: &str) -> CliResult<RotatingLog<MemFs>> {
+    let path = Path::new(source);
+    let fs = if is_serial(path) {
+        download(&mut connect(path)?)?
+    } else {
+        decode_image(&std::fs::read(path)?)?
+    };
+    Ok(RotatingLog::open(fs, RotationPolicy::default())?)
+}
+
+/// The bike's log files, capture windows and session summaries, rebuilt
+/// from what it sends.
+fn download(host: &mut Host<IoStream<File>>) -> CliResult<MemFs> {
+    let mut files = Vec::new();
+    for f in host.files()? {
+        let (records, lost) = host.records(f.seq)?;
+        if lost > 0 {
+            eprintln!("file {}: {lost} bytes torn or corrupt on the bike", f.seq);
+        }
+        // only downsampled files hold rollups
+        let name = if records.iter().any(|r| is_rollup(r.channel)) {
+            rollup_file_name(f.seq)
+        } else {
+            log_file_name(f.seq)
+        };
+        files.push((name, encode_stream(&records)));
+    }
+    for c in host.captures()? {
+        let (records, lost) = host.capture_records(c.seq)?;
+        if lost > 0 {
+            eprintln!(
+                "capture {}: {lost} bytes torn or corrupt on the bike",
+                c.seq
+            );
+        }
+        files.push((capture_file_name(c.seq), encode_stream(&records)));
+    }
+    let summaries = host.sessions()?;
+    if !summaries.is_empty() {
+        let data = summaries.iter().flat_map(|s| s.encode()).collect();
+        files.push((SESSIONS_FILE.to_owned(), data));
+    }
+    Ok(MemFs::from_files(files))
+}
+
+fn file_records(log: &mut RotatingLog<MemFs>, file: &LogFile) -> CliResult<(Vec<Record>, usize)> {
+    let bytes = log.read_file(file)?;
+    let mut records = Records::new(&bytes)?;
+    let decoded = records.by_ref().collect();
+    Ok((decoded, records.lost()))
+}
+
+/// Pairs of `--name value`, in order.
+fn options(opts: &[String]) -> CliResult<Vec<(&str, &str)>> {
+    if !opts.len().is_multiple_of(2) {
+        return Err(USAGE.into());
+    }
+    Ok(opts
+        .chunks(2)
+        .map(|pair| (pair[0].as_str(), pair[1].as_str()))
+        .collect())
+}
+
+fn parse_seconds(s: &str) -> CliResult<u32> {
+    let secs: f64 = s.parse()?;
+    if !(0.0..=u32::MAX as f64 / 1000.0).contains(&secs) {
+        return Err(format!("{s} seconds is out of range").into());
+    }
+    Ok((secs * 1000.0).round() as u32)
+}
+
+fn seconds(ms: u32) -> String {
+    format!("{}.{:03}", ms / 1000, ms % 1000)
+}
+
+fn describe_event(e: &Event) -> String {
+    match e.kind {
+        EventKind::Triggered(rule) => format!("rule {rule} triggered"),
+        EventKind::Cleared(rule) => format!("rule {rule} cleared"),
+        EventKind::OutputChanged(output) => format!("output {output} changed"),
+        EventKind::SwitchChanged(switch) => match e.value {
+            SWITCH_OPENED => format!("switch {switch} opened"),
+            SWITCH_CLOSED => format!("switch {switch} closed"),
+            _ => format!("switch {switch} held"),
+        },
+    }
+}
+
+fn list(source: &str) -> CliResult<()> {
+    let mut log = open(source)?;
+    println!(
+        "{:>8}  {:>8}  {:>12}  {:>12}  {:>8}",
+        "file", "records", "first (s)", "last (s)", "bytes"
+    );
+    for file in log.files()? {
+        let size = log.read_file(&file)?.len();
+        let (records, lost) = file_records(&mut log, &file)?;
+        let span = |r: Option<&Record>| r.map_or("-".to_owned(), |r| seconds(r.timestamp));
+        println!(
+            "{:>8}  {:>8}  {:>12}  {:>12}  {:>8}{}",
+            file.seq,
+            records.len(),
+            span(records.first()),
+            span(records.last()),
+            size,
+            match (lost, file.name == rollup_file_name(file.seq)) {
+                (0, false) => String::new(),
+                (0, true) => "  (downsampled)".to_owned(),
+                (lost, _) => format!("  ({lost} bytes torn or corrupt)"),
+            }
+        );
+    }
+    let captures = read_captures(log.fs_mut()).map_err(StorageError::Fs)?;
+    if captures.is_empty() {
+        return Ok(());
+    }
+    println!();
+    println!(
+        "{:>8}  {:>8}  {:>12}  event",
+        "capture", "records", "at (s)"
+    );
+    for (name, data) in captures {
+        let records: Vec<Record> = Records::new(&data)?.collect();
+        let event = records.first().and_then(Event::from_record);
+        println!(
+            "{:>8}  {:>8}  {:>12}  {}",
+            parse_capture_file_name(&name).unwrap_or_default(),
+            records.len().saturating_sub(1),
+            event.map_or("-".to_owned(), |e| seconds(e.timestamp)),
+            event.map_or("unreadable".to_owned(), |e| describe_event(&e)),
+        );
+    }
+    Ok(())
+}
+
+fn dump(source: &str, opts: &[String]) -> CliResult<()> {
+    let mut only_file = None;
+    let mut only_channel = None;
+    let mut range = 0..u32::MAX;
+    let mut aggregation = Aggregation::None;
+    for (opt, value) in options(opts)? {
+        match opt {
+            "--file" => only_file = Some(value.parse::<u32>()?),
+            "--channel" => only_channel = Some(value),
+            "--from" => range.start = parse_seconds(value)?,
+            "--to" => range.end = parse_seconds(value)?,
+            "--bucket" => match parse_seconds(value)? {
+                0 => return Err("buckets must be at least 1 ms wide".into()),
+                ms => aggregation = Aggregation::Buckets(ms),
+            },
+            _ => return Err(USAGE.into()),
+        }
+    }
+
+    let registry = ChannelRegistry::standard();
+    if let Some(name) = only_channel
+        && registry.by_name(name).is_none()
+        && name != "events"
+    {
+        return Err(format!("no channel named {name}").into());
+    }
+    let channels: Vec<ChannelId> = only_channel
+        .and_then(|name| registry.by_name(name))
+        .map(|c| c.id)
+        .into_iter()
+        .collect();
+
+    let mut log = open(source)?;
+    let mut query = log.query(&channels, range, aggregation)?;
+    if let Some(seq) = only_file {
+        query = query.in_files(seq..=seq);
+    }
+    match aggregation {
+        Aggregation::None => println!(
+            "{:>8}  {:>12}  {:<18}  {:>12}  unit",
+            "file", "time (s)", "channel", "value"
+        ),
+        Aggregation::Buckets(_) => println!(
+            "{:>8}  {:>12}  {:<18}  {:>12}  {:>12}  {:>12}  {:>6}  unit",
+            "file", "from (s)", "channel", "min", "mean", "max", "count"
+        ),
+    }
+    for item in query {
+        let (file, r) = match item? {
+            QueryItem::Record { file, record } => (file, record),
+            QueryItem::Bucket { file, bucket: b } => {
+                let c = registry.get(b.channel);
+                // events come through as records, never as buckets
+                if only_channel.is_some_and(|name| c.is_none_or(|c| c.name != name)) {
+                    continue;
+                }
+                let physical = |v: f64| c.map_or(v, |c| v * c.scale as f64 + c.offset as f64);
+                let name = c.map_or(format!("ch{}", b.channel.0), |c| c.name.to_owned());
+                let unit = c.map_or("raw", |c| c.unit);
+                println!(
+                    "{:>8}  {:>12}  {:<18}  {:>12.3}  {:>12.3}  {:>12.3}  {:>6}  {}",
+                    file,
+                    seconds(b.start_ms),
+                    name,
+                    physical(b.min as f64),
+                    physical(b.mean),
+                    physical(b.max as f64),
+                    b.count,
+                    unit
+                );
+                continue;
+            }
+        };
+        // downsampled records read as the channel they summarize
+        let rollup = Stat::parse(r.channel);
+        let channel = registry.get(rollup.map_or(r.channel, |(source, _)| source));
+        let event = Event::from_record(&r);
+        if let Some(sync) = SyncPoint::from_record(&r) {
+            if only_channel.is_none() {
+                println!(
+                    "{:>8}  {:>12}  {:<18}  {}",
+                    file,
+                    seconds(r.timestamp),
+                    format!("clock ({})", sync.source.name()),
+                    format_utc(sync.utc_ms)
+                );
+            }
+            continue;
+        }
+        let name_matches = |name: &str| match channel {
+            Some(c) => c.name == name,
+            None => event.is_some() && name == "events",
+        };
+        if only_channel.is_some_and(|name| !name_matches(name)) {
+            continue;
+        }
+        let (name, value, unit) = match (channel, event) {
+            (Some(c), _) => (
+                match rollup {
+                    Some((_, stat)) => format!("{} ({})", c.name, stat.name()),
+                    None => c.name.to_owned(),
+                },
+                match ChargeState::from_raw(r.value) {
+                    Some(state) if c.id == CHARGE_STATE && rollup.is_none() => {
+                        state.name().to_owned()
+                    }
+                    _ => format!("{:.3}", c.to_physical(r.value)),
+                },
+                c.unit,
+            ),
+            (None, Some(e)) => (
+                describe_event(&e),
+                r.value.to_string(),
+                match e.severity {
+                    Severity::Info => "info",
+                    Severity::Warning => "WARNING",
+                    Severity::Critical => "CRITICAL",
+                },
+            ),
+            (None, None) => (format!("ch{}", r.channel.0), r.value.to_string(), "raw"),
+        };
+        println!(
+            "{:>8}  {:>12}  {:<18}  {:>12}  {}",
+            file,
+            seconds(r.timestamp),
+            name,
+            value,
+            unit
+        );
+    }
+    Ok(())
+}
+
+fn export(source: &str, out: &str, opts: &[String]) -> CliResult<()> {
+    let mut format = "image";
+    let mut only_file = None;
+    let mut export_opts = ExportOptions::default();
+    for (opt, value) in options(opts)? {
+        match opt {
+            "--format" => format = value,
+            "--file" => only_file = Some(value.parse::<u32>()?),
+            "--from" => export_opts.start_ms = Some(parse_seconds(value)?),
+            "--to" => export_opts.end_ms = Some(parse_seconds(value)?),
+            "--every" => match parse_seconds(value)? {
+                0 => return Err("resampling period must be at least 1 ms".into()),
+                ms => export_opts.resample_ms = Some(ms),
+            },
+            "--time" => match value {
+                "boot" => export_opts.wall_clock = false,
+                "utc" => export_opts.wall_clock = true,
+                _ => return Err(format!("--time is boot or utc, not {value}").into()),
+            },
+            _ => return Err(USAGE.into()),
+        }
+    }
+
+    let mut log = open(source)?;
+    if format == "image" {
+        if export_opts != ExportOptions::default() || only_file.is_some() {
+            return Err(
+                "--file, --from, --to, --every and --time need --format csv, jsonl or parquet"
+                    .into(),
+            );
+        }
+        let image = encode_image(log.fs_mut())?;
+        std::io::Write::write_all(&mut File::create(out)?, &image)?;
+        eprintln!("wrote {} log files to {out}", log.files()?.len());
+        return Ok(());
+    }
+
+    let mut records = Vec::new();
+    for file in log.files()? {
+        if only_file.is_none_or(|seq| seq == file.seq) {
+            records.extend(file_records(&mut log, &file)?.0);
+        }
+    }
+    let table = Table::build(&records, &ChannelRegistry::standard(), &export_opts);
+    let file = BufWriter::new(File::create(out)?);
+    match format {
+        "csv" => table.write_csv(file)?,
+        "jsonl" => table.write_json_lines(file)?,
+        #[cfg(feature = "parquet")]
+        "parquet" => table.write_parquet(file)?,
+        _ => return Err(format!("unknown export format {format}").into()),
+    }
+    if export_opts.wall_clock {
+        let unsynced = split_boots(&records)
+            .into_iter()
+            .filter(|boot| !TimeBase::from_records(&records[boot.clone()]).is_synced())
+            .count();
+        if unsynced > 0 {
+            eprintln!("left out {unsynced} boot(s) that never learned the time");
+        }
+    }
+    eprintln!("wrote {} rows to {out}", table.rows.len());
+    Ok(())
+}
+
+fn sessions(source: &str, opts: &[String]) -> CliResult<()> {
+    let registry = ChannelRegistry::standard();
+    let mut scan = false;
+    let mut thresholds = Vec::new();
+    let mut opts = opts.iter();
+    while let Some(opt) = opts.next() {
+        match opt.as_str() {
+            "--scan" => scan = true,
+            "--above" => {
+                let spec = opts.next().ok_or(USAGE)?;
+                let (name, value) = spec.split_once('=').ok_or(USAGE)?;
+                let channel = registry
+                    .by_name(name)
+                    .ok_or_else(|| format!("no channel named {name}"))?;
+                thresholds.push(Threshold::physical(channel, value.parse()?));
+            }
+            _ => return Err(USAGE.into()),
+        }
+    }
+    if thresholds.is_empty() {
+        let regulator = registry.by_name("regulator_temp_c").unwrap();
+        thresholds.push(Threshold::physical(regulator, 80.0));
+    }
+
+    let mut log = open(source)?;
+    let summaries = if scan {
+        let mut records = Vec::new();
+        for file in log.files()? {
+            records.extend(file_records(&mut log, &file)?.0);
+        }
+        summarize_sessions(&records, &thresholds)
+    } else {
+        read_summaries(log.fs_mut())?
+    };
+    for (i, s) in summaries.iter().enumerate() {
+        print_summary(i, s, &registry);
+    }
+    Ok(())
+}
+
+fn print_summary(index: usize, s: &SessionSummary, registry: &ChannelRegistry) {
+    let files = s
+        .files
+        .map_or(String::new(), |(a, b)| format!(", files {a}..={b}"));
+    println!(
+        "session {index}: {} s to {} s ({} s){files}",
+        seconds(s.start_ms),
+        seconds(s.end_ms),
+        seconds(s.duration_ms())
+    );
+    let percentiles: Vec<String> = QUANTILES
+        .iter()
+        .map(|q| format!("p{}", (q * 100.0).round()))
+        .collect();
+    println!(
+        "  {:<18}  {:>7}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}",
+        "channel", "count", "min", "mean", percentiles[0], percentiles[1], percentiles[2], "max"
+    );
+    for c in &s.channels {
+        let (name, scale, offset) = match registry.get(c.channel) {
+            Some(ch) => (ch.name.to_owned(), ch.scale as f64, ch.offset as f64),
+            None => (format!("ch{}", c.channel.0), 1.0, 0.0),
+        };
+        let v = |raw: f64| format!("{:.2}", raw * scale + offset);
+        println!(
+            "  {:<18}  {:>7}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}",
+            name,
+            c.count,
+            v(c.min as f64),
+            v(c.mean()),
+            v(c.percentiles[0] as f64),
+            v(c.percentiles[1] as f64),
+            v(c.percentiles[2] as f64),
+            v(c.max as f64)
+        );
+    }
+    for (t, ms) in &s.above {
+        match registry.get(t.channel) {
+            Some(ch) => println!(
+                "  {} above {} {}: {} s",
+                ch.name,
+                ch.to_physical(t.above),
+                ch.unit,
+                seconds(*ms)
+            ),
+            None => println!("  ch{} above {}: {} s", t.channel.0, t.above, seconds(*ms)),
+        }
+    }
+}
+
+fn check(source: &str) -> CliResult<()> {
+    let mut log = open(source)?;
+    let report = recover(log.fs_mut())?;
+    for file in &report.files {
+        let scan = &file.scan;
+        if scan.bad_regions.is_empty() {
+            println!("{:>8}  ok, {} records", file.seq, scan.records.len());
+            continue;
+        }
+        println!(
+            "{:>8}  {} records, {} of {} bytes lost",
+            file.seq,
+            scan.records.len(),
+            scan.lost_bytes(),
+            file.size
+        );
+        for region in &scan.bad_regions {
+            println!("{:>8}    bytes {}..{}", "", region.start, region.end);
+        }
+    }
+    match report.newest_valid() {
+        Some((seq, end)) => println!(
+            "{} records, {} bytes lost; last good write ends at byte {end} of file {seq}",
+            report.records(),
+            report.lost_bytes()
+        ),
+        None => println!("no readable records"),
+    }
+    if report.lost_bytes() > 0 {
+        return Err("log has torn or corrupt regions".into());
+    }
+    Ok(())
+}
+
+fn erase(source: &str) -> CliResult<()> {
+    if is_serial(Path::new(source)) {
+        let count = connect(Path::new(source))?.erase()?;
+        eprintln!("erased {count} log files on the bike");
+        return Ok(());
+    }
+    let mut log = open(source)?;
+    let count = log.files()?.len();
+    log.erase()?;
+    std::fs::write(source, encode_image(log.fs_mut())?)?;
+    eprintln!("erased {count} log files from {source}");
+    Ok(())
 }
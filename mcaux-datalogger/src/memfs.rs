@@ -0,0 +1,121 @@
+// mcaux-datalogger/src/memfs.rs
+
+use std::collections::BTreeMap;
+
+use crate::storage::LogFs;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemFsError {
+    NotFound,
+    NoSpace,
+    /// Simulated power loss; everything fails until `restore_power`.
+    PowerCut,
+}
+
+/// A flat in-memory filesystem with a fixed capacity, for host-side tests
+/// and tools. Can simulate losing power partway through a write.
+#[derive(Clone, Debug, Default)]
+pub struct MemFs {
+    files: BTreeMap<String, Vec<u8>>,
+    capacity: usize,
+    /// Bytes that may still be written before the lights go out
+    power_budget: Option<usize>,
+    powered_off: bool,
+}
+
+impl MemFs {
+    pub fn new(capacity: usize) -> Self {
+        MemFs {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// Already holding `files`, and full: capacity is exactly what they use.
+    pub fn from_files(files: impl IntoIterator<Item = (String, Vec<u8>)>) -> Self {
+        let files: BTreeMap<String, Vec<u8>> = files.into_iter().collect();
+        let capacity = files.values().map(Vec::len).sum();
+        MemFs {
+            files,
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// Direct access to a file's bytes, e.g. to corrupt them in a test.
+    pub fn file_mut(&mut self, name: &str) -> Option<&mut Vec<u8>> {
+        self.files.get_mut(name)
+    }
+
+    /// Lose power once `bytes` more bytes have been written.
+    pub fn cut_power_after(&mut self, bytes: usize) {
+        self.power_budget = Some(bytes);
+    }
+
+    pub fn restore_power(&mut self) {
+        self.power_budget = None;
+        self.powered_off = false;
+    }
+
+    fn used(&self) -> usize {
+        self.files.values().map(Vec::len).sum()
+    }
+
+    fn check_power(&self) -> Result<(), MemFsError> {
+        if self.powered_off {
+            Err(MemFsError::PowerCut)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl LogFs for MemFs {
+    type Error = MemFsError;
+
+    fn append(&mut self, name: &str, data: &[u8]) -> Result<(), MemFsError> {
+        self.check_power()?;
+        if self.used() + data.len() > self.capacity {
+            return Err(MemFsError::NoSpace);
+        }
+        let (data, cut) = match self.power_budget {
+            Some(budget) if budget < data.len() => (&data[..budget], true),
+            _ => (data, false),
+        };
+        if let Some(budget) = self.power_budget.as_mut() {
+            *budget -= data.len();
+        }
+        self.files
+            .entry(name.to_owned())
+            .or_default()
+            .extend_from_slice(data);
+        if cut {
+            self.powered_off = true;
+            return Err(MemFsError::PowerCut);
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, name: &str) -> Result<Vec<u8>, MemFsError> {
+        self.check_power()?;
+        self.files.get(name).cloned().ok_or(MemFsError::NotFound)
+    }
+
+    fn remove(&mut self, name: &str) -> Result<(), MemFsError> {
+        self.check_power()?;
+        self.files
+            .remove(name)
+            .map(|_| ())
+            .ok_or(MemFsError::NotFound)
+    }
+
+    fn list(&mut self) -> Result<Vec<String>, MemFsError> {
+        self.check_power()?;
+        Ok(self.files.keys().cloned().collect())
+    }
+
+    fn free_space(&mut self) -> Result<usize, MemFsError> {
+        self.check_power()?;
+        Ok(self.capacity - self.used())
+    }
+}
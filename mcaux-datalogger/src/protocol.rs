@@ -0,0 +1,972 @@
+// mcaux-datalogger/src/protocol.rs
+
+//! Request/response protocol for pulling logs off the bike over serial or
+//! USB CDC.
+//!
+//! Each packet is a u16 sequence number, a u8 message type, the payload and
+//! a CRC-32 of all that, COBS-encoded and followed by a zero byte. Zero
+//! never appears inside an encoded packet, so after line noise the reader
+//! picks up again at the next one. Integers little-endian.
+//!
+//! The host numbers its requests; the device answers with the same number,
+//! so the host can ignore late answers to a request it has given up on.
+//! Lists of files, sessions and records come back in chunks, each saying
+//! where in the list it starts, then an `End` giving the total. If a chunk
+//! goes missing the host asks again from where it got to. A summary can
+//! outgrow a packet, so sessions come as a list of bytes, the summaries
+//! encoded one after another, for the host to decode once it has them all.
+//! Event capture windows are listed and streamed like log files, by number.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::crc::crc32;
+use crate::event::{capture_file_name, parse_capture_file_name, read_captures};
+use crate::record::{DecodeError, RECORD_LEN, Record, Records};
+use crate::session::{Reader, SessionSummary, decode_summaries, read_summaries};
+use crate::storage::{LogFs, RotatingLog};
+
+/// Largest payload in one packet, so both ends can size buffers.
+pub const MAX_PAYLOAD: usize = 512;
+/// Sequence number, type and CRC around the payload.
+pub const PACKET_OVERHEAD: usize = 2 + 1 + 4;
+/// COBS adds a byte per 254, plus one.
+const MAX_ENCODED: usize =
+    PACKET_OVERHEAD + MAX_PAYLOAD + (PACKET_OVERHEAD + MAX_PAYLOAD) / 254 + 1;
+/// Times the host sends a request without getting anywhere before giving up.
+pub const MAX_ATTEMPTS: u32 = 4;
+
+/// A serial port or anything like one.
+pub trait ByteStream {
+    type Error: fmt::Debug;
+
+    /// Read whatever has arrived into `buf`, waiting up to the stream's
+    /// timeout for something to. 0 means nothing came in time.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// A `std::io` stream, e.g. a tty opened as a file. Reads that time out
+/// count as nothing arriving.
+pub struct IoStream<T>(pub T);
+
+impl<T: Read + Write> ByteStream for IoStream<T> {
+    type Error = io::Error;
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                ) =>
+            {
+                Ok(0)
+            }
+            r => r,
+        }
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write_all(data)?;
+        self.0.flush()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LinkError<E> {
+    Stream(E),
+    /// No complete answer after `MAX_ATTEMPTS` tries
+    NoResponse,
+    /// The device couldn't do what was asked
+    Device(String),
+}
+
+impl<E: fmt::Debug> fmt::Display for LinkError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::Stream(e) => write!(f, "link error: {e:?}"),
+            LinkError::NoResponse => write!(f, "no response from the bike"),
+            LinkError::Device(msg) => write!(f, "bike says: {msg}"),
+        }
+    }
+}
+
+impl<E: fmt::Debug> std::error::Error for LinkError<E> {}
+
+/// Stuff `data` so it holds no zero bytes.
+pub fn cobs_encode(data: &[u8], out: &mut Vec<u8>) {
+    let mut code_at = out.len();
+    out.push(0);
+    let mut code = 1u8;
+    for &b in data {
+        if b != 0 {
+            out.push(b);
+            code += 1;
+        }
+        if b == 0 || code == 0xff {
+            out[code_at] = code;
+            code_at = out.len();
+            out.push(0);
+            code = 1;
+        }
+    }
+    out[code_at] = code;
+}
+
+pub fn cobs_decode(mut data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    while let Some((&code, rest)) = data.split_first() {
+        let run = rest.get(..(code as usize).checked_sub(1)?)?;
+        out.extend_from_slice(run);
+        data = &rest[run.len()..];
+        if code != 0xff && !data.is_empty() {
+            out.push(0);
+        }
+    }
+    Some(out)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Packet {
+    seq: u16,
+    kind: u8,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn encode(&self) -> Vec<u8> {
+        let mut raw = self.seq.to_le_bytes().to_vec();
+        raw.push(self.kind);
+        raw.extend_from_slice(&self.payload);
+        raw.extend_from_slice(&crc32(&raw).to_le_bytes());
+        let mut out = Vec::with_capacity(MAX_ENCODED + 1);
+        cobs_encode(&raw, &mut out);
+        out.push(0);
+        out
+    }
+
+    fn decode(encoded: &[u8]) -> Option<Packet> {
+        let raw = cobs_decode(encoded)?;
+        if raw.len() < PACKET_OVERHEAD {
+            return None;
+        }
+        let (body, crc) = raw.split_at(raw.len() - 4);
+        if crc32(body).to_le_bytes() != crc {
+            return None;
+        }
+        Some(Packet {
+            seq: u16::from_le_bytes([body[0], body[1]]),
+            kind: body[2],
+            payload: body[3..].to_vec(),
+        })
+    }
+}
+
+/// Splits incoming bytes into packets, dropping any that are corrupt.
+#[derive(Default)]
+struct Deframer {
+    buf: Vec<u8>,
+    overlong: bool,
+    packets: VecDeque<Packet>,
+}
+
+impl Deframer {
+    fn push(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            if b != 0 {
+                if self.buf.len() < MAX_ENCODED {
+                    self.buf.push(b);
+                } else {
+                    self.overlong = true;
+                }
+                continue;
+            }
+            if !self.overlong
+                && let Some(p) = Packet::decode(&self.buf)
+            {
+                self.packets.push_back(p);
+            }
+            self.buf.clear();
+            self.overlong = false;
+        }
+    }
+
+    /// The next packet, or `None` once the stream goes quiet.
+    fn fill<S: ByteStream>(&mut self, stream: &mut S) -> Result<Option<Packet>, S::Error> {
+        let mut buf = [0u8; 256];
+        while self.packets.is_empty() {
+            let n = stream.read(&mut buf)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.push(&buf[..n]);
+        }
+        Ok(self.packets.pop_front())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileInfo {
+    pub seq: u32,
+    pub bytes: u32,
+}
+
+/// Host to device. `skip` resumes a list partway.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    Files {
+        skip: u32,
+    },
+    Sessions {
+        skip: u32,
+    },
+    Records {
+        file: u32,
+        skip: u32,
+    },
+    /// Capture windows, answered as `Files` numbered by capture.
+    Captures {
+        skip: u32,
+    },
+    /// One capture window's records, answered as `Records`.
+    CaptureRecords {
+        capture: u32,
+        skip: u32,
+    },
+    /// Remove every log file, capture window and session summary.
+    Erase,
+}
+
+/// Device to host.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    Files {
+        first: u32,
+        files: Vec<FileInfo>,
+    },
+    /// Encoded summaries; `first` counts bytes
+    Sessions {
+        first: u32,
+        bytes: Vec<u8>,
+    },
+    Records {
+        first: u32,
+        records: Vec<Record>,
+    },
+    /// The list is done: how long it is, and for records, how many bytes
+    /// of the file were torn or corrupt.
+    End {
+        total: u32,
+        lost: u32,
+    },
+    Erased {
+        files: u32,
+    },
+    Error(String),
+}
+
+const REQ_FILES: u8 = 0x01;
+const REQ_SESSIONS: u8 = 0x02;
+const REQ_RECORDS: u8 = 0x03;
+const REQ_ERASE: u8 = 0x04;
+const REQ_CAPTURES: u8 = 0x05;
+const REQ_CAPTURE_RECORDS: u8 = 0x06;
+const RESP_FILES: u8 = 0x81;
+const RESP_SESSIONS: u8 = 0x82;
+const RESP_RECORDS: u8 = 0x83;
+const RESP_END: u8 = 0x84;
+const RESP_ERASED: u8 = 0x85;
+const RESP_ERROR: u8 = 0xff;
+
+impl Request {
+    fn to_packet(&self, seq: u16) -> Packet {
+        let (kind, payload) = match *self {
+            Request::Files { skip } => (REQ_FILES, skip.to_le_bytes().to_vec()),
+            Request::Sessions { skip } => (REQ_SESSIONS, skip.to_le_bytes().to_vec()),
+            Request::Records { file, skip } => {
+                let mut p = file.to_le_bytes().to_vec();
+                p.extend_from_slice(&skip.to_le_bytes());
+                (REQ_RECORDS, p)
+            }
+            Request::Captures { skip } => (REQ_CAPTURES, skip.to_le_bytes().to_vec()),
+            Request::CaptureRecords { capture, skip } => {
+                let mut p = capture.to_le_bytes().to_vec();
+                p.extend_from_slice(&skip.to_le_bytes());
+                (REQ_CAPTURE_RECORDS, p)
+            }
+            Request::Erase => (REQ_ERASE, Vec::new()),
+        };
+        Packet { seq, kind, payload }
+    }
+
+    fn from_packet(p: &Packet) -> Result<Request, DecodeError> {
+        let mut r = Reader(&p.payload);
+        Ok(match p.kind {
+            REQ_FILES => Request::Files { skip: r.u32()? },
+            REQ_SESSIONS => Request::Sessions { skip: r.u32()? },
+            REQ_RECORDS => Request::Records {
+                file: r.u32()?,
+                skip: r.u32()?,
+            },
+            REQ_CAPTURES => Request::Captures { skip: r.u32()? },
+            REQ_CAPTURE_RECORDS => Request::CaptureRecords {
+                capture: r.u32()?,
+                skip: r.u32()?,
+            },
+            REQ_ERASE => Request::Erase,
+            _ => return Err(DecodeError::Malformed),
+        })
+    }
+}
+
+impl Response {
+    fn to_packet(&self, seq: u16) -> Packet {
+        let mut payload = Vec::new();
+        let kind = match self {
+            Response::Files { first, files } => {
+                payload.extend_from_slice(&first.to_le_bytes());
+                for f in files {
+                    payload.extend_from_slice(&f.seq.to_le_bytes());
+                    payload.extend_from_slice(&f.bytes.to_le_bytes());
+                }
+                RESP_FILES
+            }
+            Response::Sessions { first, bytes } => {
+                payload.extend_from_slice(&first.to_le_bytes());
+                payload.extend_from_slice(bytes);
+                RESP_SESSIONS
+            }
+            Response::Records { first, records } => {
+                payload.extend_from_slice(&first.to_le_bytes());
+                for r in records {
+                    payload.extend_from_slice(&r.encode());
+                }
+                RESP_RECORDS
+            }
+            Response::End { total, lost } => {
+                payload.extend_from_slice(&total.to_le_bytes());
+                payload.extend_from_slice(&lost.to_le_bytes());
+                RESP_END
+            }
+            Response::Erased { files } => {
+                payload.extend_from_slice(&files.to_le_bytes());
+                RESP_ERASED
+            }
+            Response::Error(msg) => {
+                payload.extend_from_slice(&msg.as_bytes()[..msg.len().min(MAX_PAYLOAD)]);
+                RESP_ERROR
+            }
+        };
+        Packet { seq, kind, payload }
+    }
+
+    fn from_packet(p: &Packet) -> Result<Response, DecodeError> {
+        let mut r = Reader(&p.payload);
+        Ok(match p.kind {
+            RESP_FILES => {
+                let first = r.u32()?;
+                let mut files = Vec::new();
+                while !r.0.is_empty() {
+                    files.push(FileInfo {
+                        seq: r.u32()?,
+                        bytes: r.u32()?,
+                    });
+                }
+                Response::Files { first, files }
+            }
+            RESP_SESSIONS => Response::Sessions {
+                first: r.u32()?,
+                bytes: r.0.to_vec(),
+            },
+            RESP_RECORDS => {
+                let first = r.u32()?;
+                let records =
+                    r.0.chunks(RECORD_LEN)
+                        .map(Record::decode)
+                        .collect::<Result<_, _>>()?;
+                Response::Records { first, records }
+            }
+            RESP_END => Response::End {
+                total: r.u32()?,
+                lost: r.u32()?,
+            },
+            RESP_ERASED => Response::Erased { files: r.u32()? },
+            RESP_ERROR => Response::Error(String::from_utf8_lossy(r.0).into_owned()),
+            _ => return Err(DecodeError::Malformed),
+        })
+    }
+}
+
+/// The bike's end: answers requests about the log it's keeping.
+pub struct Device<S> {
+    stream: S,
+    deframer: Deframer,
+}
+
+impl<S: ByteStream> Device<S> {
+    pub fn new(stream: S) -> Self {
+        Device {
+            stream,
+            deframer: Deframer::default(),
+        }
+    }
+
+    /// Answer whatever requests have come in. Call this from the main loop;
+    /// it returns once the stream has nothing more.
+    pub fn poll<F: LogFs>(&mut self, log: &mut RotatingLog<F>) -> Result<(), S::Error> {
+        while let Some(p) = self.deframer.fill(&mut self.stream)? {
+            match Request::from_packet(&p) {
+                Ok(request) => self.answer(p.seq, request, log)?,
+                Err(_) => self.send(p.seq, Response::Error("unknown request".into()))?,
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, seq: u16, response: Response) -> Result<(), S::Error> {
+        self.stream.write_all(&response.to_packet(seq).encode())
+    }
+
+    /// `items` of `item_len` bytes each from `skip` on, as many to a chunk
+    /// as fit, then the total.
+    fn send_list<T>(
+        &mut self,
+        seq: u16,
+        skip: u32,
+        items: &[T],
+        item_len: usize,
+        chunk: impl Fn(u32, Vec<T>) -> Response,
+        lost: u32,
+    ) -> Result<(), S::Error>
+    where
+        T: Clone,
+    {
+        // after the u32 giving where the chunk starts
+        let per_chunk = (MAX_PAYLOAD - 4) / item_len;
+        let mut i = (skip as usize).min(items.len());
+        while i < items.len() {
+            let first = i;
+            i = items.len().min(first + per_chunk);
+            self.send(seq, chunk(first as u32, items[first..i].to_vec()))?;
+        }
+        let total = items.len() as u32;
+        self.send(seq, Response::End { total, lost })
+    }
+
+    /// The records in a log stream's `bytes`, from `skip` on.
+    fn send_records<E: fmt::Debug>(
+        &mut self,
+        seq: u16,
+        skip: u32,
+        bytes: Result<Vec<u8>, E>,
+    ) -> Result<(), S::Error> {
+        let failed = |e: &dyn fmt::Debug| Response::Error(format!("{e:?}"));
+        let mut records = match bytes.as_deref().map(Records::new) {
+            Ok(Ok(records)) => records,
+            Ok(Err(e)) => return self.send(seq, failed(&e)),
+            Err(e) => return self.send(seq, failed(&e)),
+        };
+        let decoded: Vec<Record> = records.by_ref().collect();
+        let lost = records.lost() as u32;
+        self.send_list(
+            seq,
+            skip,
+            &decoded,
+            RECORD_LEN,
+            |first, records| Response::Records { first, records },
+            lost,
+        )
+    }
+
+    fn answer<F: LogFs>(
+        &mut self,
+        seq: u16,
+        request: Request,
+        log: &mut RotatingLog<F>,
+    ) -> Result<(), S::Error> {
+        let failed = |e: &dyn fmt::Debug| Response::Error(format!("{e:?}"));
+        match request {
+            Request::Files { skip } => {
+                let mut files = Vec::new();
+                match log.files() {
+                    Ok(found) => {
+                        for f in found {
+                            match log.fs_mut().read(&f.name) {
+                                Ok(data) => files.push(FileInfo {
+                                    seq: f.seq,
+                                    bytes: data.len() as u32,
+                                }),
+                                Err(e) => return self.send(seq, failed(&e)),
+                            }
+                        }
+                    }
+                    Err(e) => return self.send(seq, failed(&e)),
+                }
+                self.send_list(
+                    seq,
+                    skip,
+                    &files,
+                    8,
+                    |first, files| Response::Files { first, files },
+                    0,
+                )
+            }
+            Request::Sessions { skip } => match read_summaries(log.fs_mut()) {
+                Ok(summaries) => self.send_list(
+                    seq,
+                    skip,
+                    &summaries
+                        .iter()
+                        .flat_map(|s| s.encode())
+                        .collect::<Vec<u8>>(),
+                    1,
+                    |first, bytes| Response::Sessions { first, bytes },
+                    0,
+                ),
+                Err(e) => self.send(seq, failed(&e)),
+            },
+            Request::Records { file, skip } => {
+                let found = log
+                    .files()
+                    .map(|files| files.into_iter().find(|f| f.seq == file));
+                let bytes = match found {
+                    Ok(Some(f)) => log.read_file(&f),
+                    Ok(None) => {
+                        return self.send(seq, Response::Error(format!("no log file {file}")));
+                    }
+                    Err(e) => Err(e),
+                };
+                self.send_records(seq, skip, bytes)
+            }
+            Request::Captures { skip } => match read_captures(log.fs_mut()) {
+                Ok(captures) => {
+                    let files: Vec<FileInfo> = captures
+                        .iter()
+                        .filter_map(|(name, data)| {
+                            Some(FileInfo {
+                                seq: parse_capture_file_name(name)?,
+                                bytes: data.len() as u32,
+                            })
+                        })
+                        .collect();
+                    self.send_list(
+                        seq,
+                        skip,
+                        &files,
+                        8,
+                        |first, files| Response::Files { first, files },
+                        0,
+                    )
+                }
+                Err(e) => self.send(seq, failed(&e)),
+            },
+            Request::CaptureRecords { capture, skip } => {
+                let name = capture_file_name(capture);
+                match log.fs_mut().list() {
+                    Ok(names) if names.contains(&name) => {
+                        let bytes = log.fs_mut().read(&name);
+                        self.send_records(seq, skip, bytes)
+                    }
+                    Ok(_) => self.send(seq, Response::Error(format!("no capture {capture}"))),
+                    Err(e) => self.send(seq, failed(&e)),
+                }
+            }
+            Request::Erase => {
+                let count = log.files().map(|f| f.len() as u32);
+                match count.and_then(|count| log.erase().map(|()| count)) {
+                    Ok(files) => self.send(seq, Response::Erased { files }),
+                    Err(e) => self.send(seq, failed(&e)),
+                }
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+/// The computer's end.
+pub struct Host<S> {
+    stream: S,
+    deframer: Deframer,
+    seq: u16,
+}
+
+impl<S: ByteStream> Host<S> {
+    pub fn new(stream: S) -> Self {
+        Host {
+            stream,
+            deframer: Deframer::default(),
+            seq: 0,
+        }
+    }
+
+    pub fn files(&mut self) -> Result<Vec<FileInfo>, LinkError<S::Error>> {
+        let (files, _) = self.fetch(
+            |skip| Request::Files { skip },
+            |r| match r {
+                Response::Files { first, files } => Some((first, files)),
+                _ => None,
+            },
+        )?;
+        Ok(files)
+    }
+
+    pub fn sessions(&mut self) -> Result<Vec<SessionSummary>, LinkError<S::Error>> {
+        let (bytes, _) = self.fetch(
+            |skip| Request::Sessions { skip },
+            |r| match r {
+                Response::Sessions { first, bytes } => Some((first, bytes)),
+                _ => None,
+            },
+        )?;
+        Ok(decode_summaries(&bytes))
+    }
+
+    /// Every record in one log file, and how many bytes of it were torn or
+    /// corrupt.
+    pub fn records(&mut self, file: u32) -> Result<(Vec<Record>, usize), LinkError<S::Error>> {
+        let (records, lost) = self.fetch(
+            |skip| Request::Records { file, skip },
+            |r| match r {
+                Response::Records { first, records } => Some((first, records)),
+                _ => None,
+            },
+        )?;
+        Ok((records, lost as usize))
+    }
+
+    /// Capture windows saved on the bike, numbered as in `{n:08}.mcc`.
+    pub fn captures(&mut self) -> Result<Vec<FileInfo>, LinkError<S::Error>> {
+        let (captures, _) = self.fetch(
+            |skip| Request::Captures { skip },
+            |r| match r {
+                Response::Files { first, files } => Some((first, files)),
+                _ => None,
+            },
+        )?;
+        Ok(captures)
+    }
+
+    /// One capture window: the event record, then the samples around it.
+    pub fn capture_records(
+        &mut self,
+        capture: u32,
+    ) -> Result<(Vec<Record>, usize), LinkError<S::Error>> {
+        let (records, lost) = self.fetch(
+            |skip| Request::CaptureRecords { capture, skip },
+            |r| match r {
+                Response::Records { first, records } => Some((first, records)),
+                _ => None,
+            },
+        )?;
+        Ok((records, lost as usize))
+    }
+
+    /// Erase the log on the bike. Returns how many log files went.
+    pub fn erase(&mut self) -> Result<u32, LinkError<S::Error>> {
+        for _ in 0..MAX_ATTEMPTS {
+            let seq = self.send(&Request::Erase)?;
+            while let Some(response) = self.receive(seq)? {
+                match response {
+                    Response::Erased { files } => return Ok(files),
+                    Response::Error(msg) => return Err(LinkError::Device(msg)),
+                    _ => {}
+                }
+            }
+        }
+        Err(LinkError::NoResponse)
+    }
+
+    fn send(&mut self, request: &Request) -> Result<u16, LinkError<S::Error>> {
+        self.seq = self.seq.wrapping_add(1);
+        self.stream
+            .write_all(&request.to_packet(self.seq).encode())
+            .map_err(LinkError::Stream)?;
+        Ok(self.seq)
+    }
+
+    /// The next answer to request `seq`, or `None` if the stream went quiet.
+    fn receive(&mut self, seq: u16) -> Result<Option<Response>, LinkError<S::Error>> {
+        loop {
+            let Some(p) = self
+                .deframer
+                .fill(&mut self.stream)
+                .map_err(LinkError::Stream)?
+            else {
+                return Ok(None);
+            };
+            if p.seq != seq {
+                continue;
+            }
+            if let Ok(response) = Response::from_packet(&p) {
+                return Ok(Some(response));
+            }
+        }
+    }
+
+    /// A whole list, asking again from where we got to whenever a chunk
+    /// goes missing.
+    fn fetch<T>(
+        &mut self,
+        request: impl Fn(u32) -> Request,
+        chunk: impl Fn(Response) -> Option<(u32, Vec<T>)>,
+    ) -> Result<(Vec<T>, u32), LinkError<S::Error>> {
+        let mut got = Vec::new();
+        let mut attempts = 0;
+        while attempts < MAX_ATTEMPTS {
+            attempts += 1;
+            let seq = self.send(&request(got.len() as u32))?;
+            while let Some(response) = self.receive(seq)? {
+                match response {
+                    Response::End { total, lost } if total as usize == got.len() => {
+                        return Ok((got, lost));
+                    }
+                    Response::End { .. } => break,
+                    Response::Error(msg) => return Err(LinkError::Device(msg)),
+                    response => match chunk(response) {
+                        Some((first, items)) if first as usize == got.len() => {
+                            got.extend(items);
+                            attempts = 0;
+                        }
+                        // a chunk went missing; ask again from here
+                        _ => break,
+                    },
+                }
+            }
+        }
+        Err(LinkError::NoResponse)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::{CaptureWindow, Event, EventKind, Severity};
+    use crate::memfs::MemFs;
+    use crate::record::ChannelId;
+    use crate::session::{SessionLog, Threshold};
+    use crate::storage::RotationPolicy;
+    use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, channel};
+    use std::thread;
+    use std::time::Duration;
+
+    /// One end of an in-process serial line. Optionally corrupts bytes it
+    /// sends, by position in everything it has sent.
+    struct PipeEnd {
+        tx: Sender<Vec<u8>>,
+        rx: Receiver<Vec<u8>>,
+        pending: Vec<u8>,
+        sent: usize,
+        corrupt: Vec<usize>,
+    }
+
+    fn pipe() -> (PipeEnd, PipeEnd) {
+        let (a_tx, b_rx) = channel();
+        let (b_tx, a_rx) = channel();
+        let end = |tx, rx| PipeEnd {
+            tx,
+            rx,
+            pending: Vec::new(),
+            sent: 0,
+            corrupt: Vec::new(),
+        };
+        (end(a_tx, a_rx), end(b_tx, b_rx))
+    }
+
+    impl ByteStream for PipeEnd {
+        type Error = ();
+
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
+            if self.pending.is_empty() {
+                match self.rx.recv_timeout(Duration::from_millis(20)) {
+                    Ok(data) => self.pending = data,
+                    Err(RecvTimeoutError::Timeout) => return Ok(0),
+                    Err(RecvTimeoutError::Disconnected) => return Err(()),
+                }
+            }
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            Ok(n)
+        }
+
+        fn write_all(&mut self, data: &[u8]) -> Result<(), ()> {
+            let mut data = data.to_vec();
+            for &at in &self.corrupt {
+                if let Some(b) = at.checked_sub(self.sent).and_then(|i| data.get_mut(i)) {
+                    *b ^= 0x10;
+                }
+            }
+            self.sent += data.len();
+            self.tx.send(data).map_err(|_| ())
+        }
+    }
+
+    /// A few rides' worth of log on a bike answering over `end` until the
+    /// host hangs up.
+    /// The bike's one capture window, from the first ride.
+    fn capture(records: &[Record]) -> CaptureWindow {
+        CaptureWindow {
+            event: Event {
+                timestamp: 2010,
+                severity: Severity::Warning,
+                kind: EventKind::Triggered(0),
+                value: 5000,
+            },
+            records: records[1..40].to_vec(),
+        }
+    }
+
+    fn bike(end: PipeEnd) -> (thread::JoinHandle<RotatingLog<MemFs>>, Vec<Record>) {
+        let policy = RotationPolicy {
+            max_file_bytes: 1024,
+            ..Default::default()
+        };
+        let rpm = Threshold {
+            channel: ChannelId(2),
+            above: 5000,
+        };
+        let mut log = SessionLog::open(MemFs::new(256 * 1024), policy, &[rpm]).unwrap();
+        let mut records = Vec::new();
+        for ride in 0..3 {
+            let start = ride * 100_000;
+            records.push(Record::new(start, crate::channel::IGNITION, 1));
+            for t in 0..200 {
+                records.push(Record::new(
+                    start + 10 + t * 100,
+                    ChannelId(2),
+                    t as i32 * 50,
+                ));
+            }
+            records.push(Record::new(start + 30_000, crate::channel::IGNITION, 0));
+        }
+        for chunk in records.chunks(16) {
+            log.write(chunk).unwrap();
+        }
+        let mut fs = log.into_inner();
+        capture(&records).save(&mut fs).unwrap();
+        let mut log = RotatingLog::open(fs, policy).unwrap();
+        let handle = thread::spawn(move || {
+            let mut device = Device::new(end);
+            while device.poll(&mut log).is_ok() {}
+            log
+        });
+        (handle, records)
+    }
+
+    fn download(host: &mut Host<PipeEnd>) -> (Vec<Record>, usize, Vec<SessionSummary>) {
+        let mut records = Vec::new();
+        let mut lost = 0;
+        for f in host.files().unwrap() {
+            let (r, l) = host.records(f.seq).unwrap();
+            records.extend(r);
+            lost += l;
+        }
+        (records, lost, host.sessions().unwrap())
+    }
+
+    #[test]
+    fn cobs_round_trip() {
+        let long: Vec<u8> = (1..=255).chain(1..=10).collect();
+        for data in [&[][..], &[0], &[0, 0], &[1, 0, 2], &long, &long[..254]] {
+            let mut out = Vec::new();
+            cobs_encode(data, &mut out);
+            assert!(!out.contains(&0));
+            assert_eq!(cobs_decode(&out).as_deref(), Some(data));
+        }
+        assert_eq!(cobs_decode(&[3, 1]), None);
+    }
+
+    #[test]
+    fn host_downloads_and_erases() {
+        let (host_end, bike_end) = pipe();
+        let (bike, written) = bike(bike_end);
+        let mut host = Host::new(host_end);
+
+        let (records, lost, sessions) = download(&mut host);
+        assert_eq!(records, written);
+        assert_eq!(lost, 0);
+        assert_eq!(sessions.len(), 3);
+        assert_eq!(sessions[1].start_ms, 100_000);
+        assert!(host.files().unwrap().len() > 3);
+
+        assert_eq!(
+            host.records(999).err(),
+            Some(LinkError::Device("no log file 999".into()))
+        );
+
+        let captures = host.captures().unwrap();
+        assert_eq!(captures.len(), 1);
+        let (window, lost) = host.capture_records(captures[0].seq).unwrap();
+        assert_eq!(lost, 0);
+        let expected = capture(&written);
+        assert_eq!(window[0], expected.event.to_record());
+        assert_eq!(window[1..], expected.records);
+        assert_eq!(
+            host.capture_records(7).err(),
+            Some(LinkError::Device("no capture 7".into()))
+        );
+
+        assert!(host.erase().unwrap() > 3);
+        assert_eq!(host.files().unwrap(), []);
+        assert_eq!(host.captures().unwrap(), []);
+        assert_eq!(host.sessions().unwrap(), []);
+        drop(host);
+        let mut log = bike.join().unwrap();
+        assert_eq!(log.read_all().unwrap(), []);
+    }
+
+    #[test]
+    fn recovers_from_line_noise() {
+        let (host_end, mut bike_end) = pipe();
+        // a flipped bit every so often in what the bike sends
+        bike_end.corrupt = (0..15).map(|i| 300 + i * 3001).collect();
+        let (bike, written) = bike(bike_end);
+        let mut host = Host::new(host_end);
+
+        let (records, _, sessions) = download(&mut host);
+        assert_eq!(records, written);
+        assert_eq!(sessions.len(), 3);
+        drop(host);
+        bike.join().unwrap();
+    }
+
+    #[test]
+    fn summaries_bigger_than_a_packet() {
+        let (host_end, bike_end) = pipe();
+        let policy = RotationPolicy::default();
+        let mut log = SessionLog::open(MemFs::new(256 * 1024), policy, &[]).unwrap();
+        let mut records = vec![Record::new(0, crate::channel::IGNITION, 1)];
+        for t in 1..10 {
+            records.extend((0..20).map(|c| Record::new(t * 100, ChannelId(100 + c), t as i32)));
+        }
+        records.push(Record::new(1_000, crate::channel::IGNITION, 0));
+        log.write(&records).unwrap();
+        let written = log.summaries().unwrap();
+        assert!(written[0].encode().len() > MAX_PAYLOAD);
+
+        let mut log = RotatingLog::open(log.into_inner(), policy).unwrap();
+        let bike = thread::spawn(move || {
+            let mut device = Device::new(bike_end);
+            while device.poll(&mut log).is_ok() {}
+        });
+        let mut host = Host::new(host_end);
+        let sessions = host.sessions().unwrap();
+        assert_eq!(sessions, written);
+        assert_eq!(sessions[0].channels.len(), 21);
+        drop(host);
+        bike.join().unwrap();
+    }
+
+    #[test]
+    fn gives_up_on_a_silent_line() {
+        let (host_end, _bike_end) = pipe();
+        let mut host = Host::new(host_end);
+        assert_eq!(host.files().err(), Some(LinkError::NoResponse));
+    }
+}
@@ -0,0 +1,586 @@
+// mcaux-datalogger/src/query.rs
+
+//! Reading back just what was asked for: some channels over a stretch of
+//! time, as logged or boiled down to min/mean/max per bucket.
+//!
+//! `RotatingLog` keeps a `TimeIndex` in memory: for each run of up to
+//! `INDEX_BLOCK_BYTES` of each log file, its time span and which channels
+//! appear in it. The first query indexes the files present, and the log
+//! extends the index as it writes from then on. A query reads only the
+//! files with a block that might match, and decodes only those blocks.
+
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::{Range, RangeInclusive};
+
+use crate::channel::IGNITION;
+use crate::event::is_event;
+use crate::record::{
+    ChannelId, FRAME_SYNC, HEADER_LEN, RECORD_LEN, Record, decode_frame, decode_header, resync,
+};
+use crate::retention::Stat;
+use crate::storage::{LogFile, LogFs, RotatingLog, StorageError};
+use crate::time::is_time_sync;
+
+/// Index blocks cover about this much of a file. Smaller blocks skip more
+/// but cost more memory.
+pub const INDEX_BLOCK_BYTES: usize = 1024;
+
+/// A run of frames (or, in version 1 files, records) within one file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexBlock {
+    pub offset: usize,
+    pub len: usize,
+    pub min_ms: u32,
+    pub max_ms: u32,
+    /// Bit `id % 64` set for every channel in the block; rollups count as
+    /// the channel they summarize.
+    pub channels: u64,
+}
+
+impl IndexBlock {
+    fn overlaps(&self, range: &Range<u32>) -> bool {
+        self.max_ms >= range.start && self.min_ms < range.end
+    }
+}
+
+fn channel_bit(channel: ChannelId) -> u64 {
+    let source = Stat::parse(channel).map_or(channel, |(source, _)| source);
+    1 << (source.0 % 64)
+}
+
+#[derive(Clone, Debug, Default)]
+struct FileIndex {
+    name: String,
+    version: u8,
+    len: usize,
+    blocks: Vec<IndexBlock>,
+}
+
+impl FileIndex {
+    fn build(name: String, bytes: &[u8]) -> FileIndex {
+        let mut index = FileIndex {
+            name,
+            ..Default::default()
+        };
+        let Ok(version) = decode_header(bytes) else {
+            // nothing in it can be read
+            index.len = bytes.len();
+            return index;
+        };
+        index.version = version;
+        index.len = HEADER_LEN;
+        while index.len < bytes.len() {
+            let rest = &bytes[index.len..];
+            let decoded = if version == 1 {
+                Record::decode(rest).map(|r| (vec![r], RECORD_LEN))
+            } else {
+                decode_frame(rest).map(|(frame, used)| (decode_records(frame), used))
+            };
+            match decoded {
+                Ok((records, used)) => index.add(used, &records),
+                // as readers do: skip to where frames pick up again
+                Err(_) => {
+                    index.len += resync(rest, FRAME_SYNC, |b| decode_frame(b).is_ok())
+                        .filter(|_| version != 1)
+                        .unwrap_or(rest.len());
+                }
+            }
+        }
+        index
+    }
+
+    /// `used` bytes holding `records` follow what's indexed so far.
+    fn add(&mut self, used: usize, records: &[Record]) {
+        let offset = self.len;
+        self.len += used;
+        let Some(first) = records.first() else {
+            return;
+        };
+        let block = match self.blocks.last_mut() {
+            Some(b) if b.offset + b.len == offset && b.len + used <= INDEX_BLOCK_BYTES => b,
+            _ => {
+                self.blocks.push(IndexBlock {
+                    offset,
+                    len: 0,
+                    min_ms: first.timestamp,
+                    max_ms: first.timestamp,
+                    channels: 0,
+                });
+                self.blocks.last_mut().unwrap()
+            }
+        };
+        block.len = offset + used - block.offset;
+        for r in records {
+            block.min_ms = block.min_ms.min(r.timestamp);
+            block.max_ms = block.max_ms.max(r.timestamp);
+            block.channels |= channel_bit(r.channel);
+        }
+    }
+
+    fn decode(&self, bytes: &[u8], block: &IndexBlock) -> Vec<Record> {
+        let Some(mut rest) = bytes.get(block.offset..block.offset + block.len) else {
+            return Vec::new();
+        };
+        if self.version == 1 {
+            return decode_records(rest);
+        }
+        let mut records = Vec::new();
+        while let Ok((frame, used)) = decode_frame(rest) {
+            records.extend(decode_records(frame));
+            rest = &rest[used..];
+        }
+        records
+    }
+}
+
+fn decode_records(bytes: &[u8]) -> Vec<Record> {
+    bytes
+        .chunks_exact(RECORD_LEN)
+        .filter_map(|c| Record::decode(c).ok())
+        .collect()
+}
+
+/// Where in each log file records from which times and channels are.
+#[derive(Clone, Debug, Default)]
+pub struct TimeIndex {
+    files: BTreeMap<u32, FileIndex>,
+}
+
+impl TimeIndex {
+    /// Index files that are new or have been replaced (as by retention)
+    /// and forget those that are gone.
+    fn refresh<F: LogFs>(&mut self, files: &[LogFile], fs: &mut F) -> Result<(), F::Error> {
+        self.files
+            .retain(|seq, index| files.iter().any(|f| f.seq == *seq && f.name == index.name));
+        for f in files {
+            if let Entry::Vacant(slot) = self.files.entry(f.seq) {
+                let bytes = fs.read(&f.name)?;
+                slot.insert(FileIndex::build(f.name.clone(), &bytes));
+            }
+        }
+        Ok(())
+    }
+
+    /// The log appended `used` bytes holding `records` to file `seq`.
+    pub(crate) fn appended(&mut self, seq: u32, used: usize, records: &[Record]) {
+        if let Some(index) = self.files.get_mut(&seq) {
+            index.add(used, records);
+        }
+    }
+
+    /// Blocks of file `seq`, if it's been indexed.
+    pub fn blocks(&self, seq: u32) -> &[IndexBlock] {
+        self.files.get(&seq).map_or(&[], |f| &f.blocks)
+    }
+}
+
+/// How records are handed back.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Aggregation {
+    /// Each record as logged
+    #[default]
+    None,
+    /// Min, mean and max of each channel over buckets this many ms wide
+    Buckets(u32),
+}
+
+/// One channel over one bucket. Values are raw, like records'.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bucket {
+    pub channel: ChannelId,
+    /// ms since boot, a multiple of the bucket width
+    pub start_ms: u32,
+    /// Samples averaged; a downsampled minute counts as one
+    pub count: u32,
+    pub min: i32,
+    pub max: i32,
+    pub mean: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueryItem {
+    /// A record as logged, and the log file it's in
+    Record {
+        file: u32,
+        record: Record,
+    },
+    Bucket {
+        file: u32,
+        bucket: Bucket,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Acc {
+    file: u32,
+    count: u32,
+    min: i32,
+    max: i32,
+    sum: i64,
+}
+
+/// Folds time-ordered records into buckets, closing them as time moves on.
+#[derive(Clone, Debug, Default)]
+struct Bucketer {
+    width: u32,
+    start_ms: u32,
+    last_ms: u32,
+    open: BTreeMap<ChannelId, Acc>,
+}
+
+impl Bucketer {
+    fn push(&mut self, file: u32, r: Record, out: &mut VecDeque<QueryItem>) {
+        let start_ms = r.timestamp - r.timestamp % self.width;
+        // time going backwards is a reboot: a new bucket regardless
+        if start_ms != self.start_ms || r.timestamp < self.last_ms {
+            self.flush(out);
+            self.start_ms = start_ms;
+        }
+        self.last_ms = r.timestamp;
+        let (channel, stat) =
+            Stat::parse(r.channel).map_or((r.channel, None), |(c, s)| (c, Some(s)));
+        let acc = self.open.entry(channel).or_insert(Acc {
+            file,
+            count: 0,
+            min: i32::MAX,
+            max: i32::MIN,
+            sum: 0,
+        });
+        if stat != Some(Stat::Max) {
+            acc.min = acc.min.min(r.value);
+        }
+        if stat != Some(Stat::Min) {
+            acc.max = acc.max.max(r.value);
+        }
+        if stat.is_none() || stat == Some(Stat::Mean) {
+            acc.count += 1;
+            acc.sum += r.value as i64;
+        }
+    }
+
+    fn flush(&mut self, out: &mut VecDeque<QueryItem>) {
+        for (channel, acc) in std::mem::take(&mut self.open) {
+            let mean = match acc.count {
+                0 => (acc.min as f64 + acc.max as f64) / 2.0,
+                n => acc.sum as f64 / n as f64,
+            };
+            out.push_back(QueryItem::Bucket {
+                file: acc.file,
+                bucket: Bucket {
+                    channel,
+                    start_ms: self.start_ms,
+                    count: acc.count,
+                    min: acc.min,
+                    max: acc.max,
+                    mean,
+                },
+            });
+        }
+    }
+}
+
+/// Records or buckets matching a query, oldest file first. Files are read
+/// as the iteration gets to them, so reading can fail partway.
+///
+/// With buckets, a bucket comes out once a later record closes it. Events,
+/// sync points and ignition aren't averaged and come out as records.
+pub struct Query<'a, F: LogFs> {
+    log: &'a mut RotatingLog<F>,
+    channels: Vec<ChannelId>,
+    range: Range<u32>,
+    bucketer: Option<Bucketer>,
+    /// Files yet to read
+    files: VecDeque<u32>,
+    ready: VecDeque<QueryItem>,
+}
+
+impl<F: LogFs> Query<'_, F> {
+    /// Only look in these log files, e.g. those of one boot.
+    pub fn in_files(mut self, files: RangeInclusive<u32>) -> Self {
+        self.files.retain(|seq| files.contains(seq));
+        self
+    }
+
+    fn wanted(&self, channel: ChannelId) -> bool {
+        let source = Stat::parse(channel).map_or(channel, |(source, _)| source);
+        self.channels.is_empty() || self.channels.contains(&source)
+    }
+
+    fn read(&mut self, seq: u32) -> Result<(), StorageError<F::Error>> {
+        let want = self.channels.iter().fold(0, |m, &c| m | channel_bit(c));
+        let Some(index) = self.log.index().files.get(&seq) else {
+            return Ok(());
+        };
+        let blocks: Vec<IndexBlock> = index
+            .blocks
+            .iter()
+            .filter(|b| b.overlaps(&self.range) && (want == 0 || b.channels & want != 0))
+            .copied()
+            .collect();
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        let index = index.clone();
+        let bytes = self
+            .log
+            .fs_mut()
+            .read(&index.name)
+            .map_err(StorageError::Fs)?;
+        for block in &blocks {
+            for r in index.decode(&bytes, block) {
+                if !self.range.contains(&r.timestamp) || !self.wanted(r.channel) {
+                    continue;
+                }
+                match self.bucketer.as_mut() {
+                    Some(b)
+                        if !is_event(r.channel)
+                            && !is_time_sync(r.channel)
+                            && r.channel != IGNITION =>
+                    {
+                        b.push(seq, r, &mut self.ready)
+                    }
+                    _ => self.ready.push_back(QueryItem::Record {
+                        file: seq,
+                        record: r,
+                    }),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<F: LogFs> Iterator for Query<'_, F> {
+    type Item = Result<QueryItem, StorageError<F::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.ready.pop_front() {
+                return Some(Ok(item));
+            }
+            match self.files.pop_front() {
+                Some(seq) => {
+                    if let Err(e) = self.read(seq) {
+                        return Some(Err(e));
+                    }
+                }
+                None => {
+                    self.bucketer.as_mut()?.flush(&mut self.ready);
+                    return self.ready.pop_front().map(Ok);
+                }
+            }
+        }
+    }
+}
+
+impl<F: LogFs> RotatingLog<F> {
+    /// Records of `channels` (all of them if empty, and rollups of them
+    /// too) timestamped within `range` ms since boot, in every boot.
+    ///
+    /// Panics if bucket width is zero.
+    pub fn query(
+        &mut self,
+        channels: &[ChannelId],
+        range: Range<u32>,
+        aggregation: Aggregation,
+    ) -> Result<Query<'_, F>, StorageError<F::Error>> {
+        let bucketer = match aggregation {
+            Aggregation::None => None,
+            Aggregation::Buckets(0) => panic!("Buckets need a nonzero width"),
+            Aggregation::Buckets(width) => Some(Bucketer {
+                width,
+                ..Default::default()
+            }),
+        };
+        let files = self.files()?;
+        let (index, fs) = self.index_and_fs();
+        index.refresh(&files, fs).map_err(StorageError::Fs)?;
+        Ok(Query {
+            channels: channels.to_vec(),
+            range,
+            bucketer,
+            files: files.iter().map(|f| f.seq).collect(),
+            ready: VecDeque::new(),
+            log: self,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::{BATTERY_V, REGULATOR_TEMP_C, RPM};
+    use crate::memfs::{MemFs, MemFsError};
+    use crate::retention::{RetentionPolicy, apply_retention};
+    use crate::session::SessionLog;
+    use crate::sim::{RideConfig, ride};
+    use crate::storage::RotationPolicy;
+
+    /// Counts file reads.
+    struct Counting {
+        fs: MemFs,
+        reads: usize,
+    }
+
+    impl LogFs for Counting {
+        type Error = MemFsError;
+
+        fn append(&mut self, name: &str, data: &[u8]) -> Result<(), MemFsError> {
+            self.fs.append(name, data)
+        }
+
+        fn read(&mut self, name: &str) -> Result<Vec<u8>, MemFsError> {
+            self.reads += 1;
+            self.fs.read(name)
+        }
+
+        fn remove(&mut self, name: &str) -> Result<(), MemFsError> {
+            self.fs.remove(name)
+        }
+
+        fn list(&mut self) -> Result<Vec<String>, MemFsError> {
+            self.fs.list()
+        }
+
+        fn free_space(&mut self) -> Result<usize, MemFsError> {
+            self.fs.free_space()
+        }
+    }
+
+    const MINUTE: u32 = 60_000;
+
+    /// An hour's ride, logged a few seconds at a time.
+    fn logged() -> (RotatingLog<Counting>, Vec<Record>) {
+        let fs = Counting {
+            fs: MemFs::new(1 << 20),
+            reads: 0,
+        };
+        let mut log = RotatingLog::open(fs, RotationPolicy::default()).unwrap();
+        let records = ride(RideConfig::default(), 60 * MINUTE);
+        for chunk in records.chunks(50) {
+            log.write(chunk).unwrap();
+        }
+        (log, records)
+    }
+
+    fn records<F: LogFs>(q: Query<'_, F>) -> Vec<Record> {
+        q.map(|item| match item.unwrap() {
+            QueryItem::Record { record, .. } => record,
+            other => panic!("{other:?}"),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn reads_only_matching_blocks() {
+        let (mut log, all) = logged();
+        let range = 20 * MINUTE..21 * MINUTE;
+        let q = log.query(&[RPM], range.clone(), Aggregation::None).unwrap();
+        let rpm: Vec<Record> = all
+            .iter()
+            .filter(|r| r.channel == RPM && range.contains(&r.timestamp))
+            .copied()
+            .collect();
+        assert_eq!(records(q), rpm);
+
+        // indexed now: the next query reads one file, not all of them
+        let files = log.files().unwrap().len();
+        assert!(files > 4);
+        log.fs_mut().reads = 0;
+        let q = log
+            .query(
+                &[REGULATOR_TEMP_C, BATTERY_V],
+                range.clone(),
+                Aggregation::None,
+            )
+            .unwrap();
+        assert_eq!(
+            records(q),
+            all.iter()
+                .filter(|r| [REGULATOR_TEMP_C, BATTERY_V].contains(&r.channel)
+                    && range.contains(&r.timestamp))
+                .copied()
+                .collect::<Vec<_>>()
+        );
+        assert!(log.fs_mut().reads <= 2);
+        let blocks = log.index().blocks(log.current_file().unwrap());
+        assert!(blocks.len() > 1 && blocks.iter().all(|b| b.len <= INDEX_BLOCK_BYTES));
+
+        // records written after the index was built are found too
+        let late = Record::new(61 * MINUTE, RPM, 4_321);
+        log.write(&[late]).unwrap();
+        let q = log.query(&[RPM], 61 * MINUTE..u32::MAX, Aggregation::None);
+        assert_eq!(records(q.unwrap()), [late]);
+    }
+
+    #[test]
+    fn buckets_of_min_mean_max() {
+        let (mut log, all) = logged();
+        let q = log
+            .query(&[RPM], 0..u32::MAX, Aggregation::Buckets(5 * MINUTE))
+            .unwrap();
+        let buckets: Vec<Bucket> = q
+            .map(|item| match item.unwrap() {
+                QueryItem::Bucket { bucket, .. } => bucket,
+                other => panic!("{other:?}"),
+            })
+            .collect();
+        assert_eq!(buckets.len(), 12);
+        for (i, b) in buckets.iter().enumerate() {
+            let start = i as u32 * 5 * MINUTE;
+            let values: Vec<i32> = all
+                .iter()
+                .filter(|r| r.channel == RPM && (start..start + 5 * MINUTE).contains(&r.timestamp))
+                .map(|r| r.value)
+                .collect();
+            assert_eq!(b.start_ms, start);
+            assert_eq!(b.count as usize, values.len());
+            assert_eq!(b.min, *values.iter().min().unwrap());
+            assert_eq!(b.max, *values.iter().max().unwrap());
+            let mean = values.iter().map(|&v| v as f64).sum::<f64>() / values.len() as f64;
+            assert!((b.mean - mean).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn downsampled_rides_fold_into_buckets() {
+        let mut log =
+            SessionLog::open(MemFs::new(1 << 20), RotationPolicy::default(), &[]).unwrap();
+        for i in 0..2 {
+            let mut records = ride(RideConfig::default(), 30 * MINUTE);
+            for r in records.iter_mut() {
+                r.timestamp += i * 40 * MINUTE;
+            }
+            for chunk in records.chunks(50) {
+                log.write(chunk).unwrap();
+            }
+        }
+        let policy = RetentionPolicy {
+            full_rate_rides: 1,
+            ..Default::default()
+        };
+        let hour = Aggregation::Buckets(60 * MINUTE);
+        let peak = |log: &mut SessionLog<MemFs>| {
+            let q = log.log().query(&[RPM], 0..30 * MINUTE, hour).unwrap();
+            let buckets: Vec<QueryItem> = q.map(Result::unwrap).collect();
+            match buckets[..] {
+                [QueryItem::Bucket { bucket, .. }] => bucket,
+                _ => panic!("{buckets:?}"),
+            }
+        };
+        let before = peak(&mut log);
+        assert!(
+            !apply_retention(log.log(), &policy)
+                .unwrap()
+                .downsampled
+                .is_empty()
+        );
+        // the index notices the replaced files
+        let after = peak(&mut log);
+        assert_eq!((after.min, after.max), (before.min, before.max));
+        // a minute per rollup, or two where a minute straddles two files
+        assert!((30..35).contains(&after.count), "{}", after.count);
+        assert!((after.mean - before.mean).abs() < 200.0);
+    }
+}
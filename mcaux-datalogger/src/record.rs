@@ -0,0 +1,321 @@
+// mcaux-datalogger/src/record.rs
+
+use std::fmt;
+
+use crate::crc::crc32;
+
+/// Leads every log stream, followed by one format version byte.
+pub const MAGIC: [u8; 3] = *b"MCL";
+
+/// Bump on any change to the encoding; readers refuse versions they don't know.
+/// Version 1 was bare records; version 2 wraps them in CRC'd frames.
+pub const FORMAT_VERSION: u8 = 2;
+
+pub const HEADER_LEN: usize = MAGIC.len() + 1;
+
+/// timestamp u32, channel u16, value i32, all little-endian.
+pub const RECORD_LEN: usize = 10;
+
+/// Which measurement a record belongs to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ChannelId(pub u16);
+
+/// One sample. The value is a raw integer in the channel's own units; the
+/// channel definition says how to scale it to something physical.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Record {
+    /// Milliseconds of monotonic time since boot.
+    pub timestamp: u32,
+    pub channel: ChannelId,
+    pub value: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Fewer bytes than a header or record needs.
+    Short,
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// Checksum doesn't match the data it covers.
+    BadCrc,
+    /// Passed its checksum but doesn't decode, e.g. an overlong varint.
+    Malformed,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Short => write!(f, "log data ends early"),
+            DecodeError::BadMagic => write!(f, "not an mcaux log (bad magic)"),
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported log format version {v}"),
+            DecodeError::BadCrc => write!(f, "log data is corrupt (checksum mismatch)"),
+            DecodeError::Malformed => write!(f, "log data is malformed"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Record {
+    pub fn new(timestamp: u32, channel: ChannelId, value: i32) -> Self {
+        Record {
+            timestamp,
+            channel,
+            value,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut buf = [0; RECORD_LEN];
+        buf[0..4].copy_from_slice(&self.timestamp.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.channel.0.to_le_bytes());
+        buf[6..10].copy_from_slice(&self.value.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Record, DecodeError> {
+        if buf.len() < RECORD_LEN {
+            return Err(DecodeError::Short);
+        }
+        Ok(Record {
+            timestamp: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            channel: ChannelId(u16::from_le_bytes([buf[4], buf[5]])),
+            value: i32::from_le_bytes([buf[6], buf[7], buf[8], buf[9]]),
+        })
+    }
+}
+
+/// Somewhere records go once they've been decided on.
+pub trait RecordSink {
+    fn push_record(&mut self, record: Record);
+}
+
+impl RecordSink for Vec<Record> {
+    fn push_record(&mut self, record: Record) {
+        self.push(record);
+    }
+}
+
+pub fn encode_header() -> [u8; HEADER_LEN] {
+    [MAGIC[0], MAGIC[1], MAGIC[2], FORMAT_VERSION]
+}
+
+/// Check a stream header, returning its format version.
+pub fn decode_header(buf: &[u8]) -> Result<u8, DecodeError> {
+    if buf.len() < HEADER_LEN {
+        return Err(DecodeError::Short);
+    }
+    if buf[..MAGIC.len()] != MAGIC {
+        return Err(DecodeError::BadMagic);
+    }
+    match buf[MAGIC.len()] {
+        v @ (1 | FORMAT_VERSION) => Ok(v),
+        v => Err(DecodeError::UnsupportedVersion(v)),
+    }
+}
+
+/// Records are written in frames, each append its own: sync, u16 record
+/// count, the records, then a CRC-32 of count and records.
+pub const FRAME_SYNC: [u8; 2] = [0xa5, 0x3c];
+
+pub const FRAME_OVERHEAD: usize = FRAME_SYNC.len() + 2 + 4;
+
+/// Most records one frame can hold.
+pub const MAX_FRAME_RECORDS: usize = u16::MAX as usize;
+
+pub fn encode_frame(records: &[Record]) -> Vec<u8> {
+    if records.len() > MAX_FRAME_RECORDS {
+        panic!("At most {MAX_FRAME_RECORDS} records to a frame");
+    }
+    let mut out = FRAME_SYNC.to_vec();
+    out.extend_from_slice(&(records.len() as u16).to_le_bytes());
+    for r in records {
+        out.extend_from_slice(&r.encode());
+    }
+    let crc = crc32(&out[FRAME_SYNC.len()..]);
+    out.extend_from_slice(&crc.to_le_bytes());
+    out
+}
+
+/// The encoded records of the frame at the front of `buf`, and the bytes
+/// the whole frame took.
+pub fn decode_frame(buf: &[u8]) -> Result<(&[u8], usize), DecodeError> {
+    if buf.len() < FRAME_OVERHEAD {
+        return Err(DecodeError::Short);
+    }
+    if buf[..2] != FRAME_SYNC {
+        return Err(DecodeError::BadMagic);
+    }
+    let len = u16::from_le_bytes([buf[2], buf[3]]) as usize * RECORD_LEN;
+    let total = len + FRAME_OVERHEAD;
+    let Some(crc) = buf.get(4 + len..total) else {
+        return Err(DecodeError::Short);
+    };
+    if crc32(&buf[2..4 + len]).to_le_bytes() != crc {
+        return Err(DecodeError::BadCrc);
+    }
+    Ok((&buf[4..4 + len], total))
+}
+
+/// Where good data picks up again after a bad spot at the front of `buf`:
+/// the first later `sync` that `decodes` there.
+pub(crate) fn resync(buf: &[u8], sync: [u8; 2], decodes: impl Fn(&[u8]) -> bool) -> Option<usize> {
+    (1..buf.len().saturating_sub(1))
+        .filter(|&i| buf[i..i + 2] == sync)
+        .find(|&i| decodes(&buf[i..]))
+}
+
+/// Header followed by every record, ready to write out.
+pub fn encode_stream<'a>(records: impl IntoIterator<Item = &'a Record>) -> Vec<u8> {
+    let records: Vec<Record> = records.into_iter().copied().collect();
+    let mut out = encode_header().to_vec();
+    for chunk in records.chunks(MAX_FRAME_RECORDS) {
+        out.extend_from_slice(&encode_frame(chunk));
+    }
+    out
+}
+
+/// Iterate the records of an encoded stream, skipping any that are torn
+/// by power loss or fail their frame's CRC.
+///
+/// Version 1 streams, from before frames, have no CRCs: only a partial
+/// record at the very end is noticed.
+pub struct Records<'a> {
+    version: u8,
+    /// Frames not yet looked at (or, in version 1, records)
+    rest: &'a [u8],
+    /// Records left in the current frame
+    frame: &'a [u8],
+    lost: usize,
+}
+
+impl<'a> Records<'a> {
+    pub fn new(stream: &'a [u8]) -> Result<Self, DecodeError> {
+        let version = decode_header(stream)?;
+        Ok(Records {
+            version,
+            rest: &stream[HEADER_LEN..],
+            frame: &[],
+            lost: 0,
+        })
+    }
+
+    /// Bytes skipped so far as torn or corrupt.
+    pub fn lost(&self) -> usize {
+        self.lost
+    }
+
+    fn next_frame(&mut self) -> bool {
+        while !self.rest.is_empty() {
+            match decode_frame(self.rest) {
+                Ok((frame, used)) => {
+                    self.frame = frame;
+                    self.rest = &self.rest[used..];
+                    return true;
+                }
+                Err(_) => {
+                    let skip = resync(self.rest, FRAME_SYNC, |b| decode_frame(b).is_ok())
+                        .unwrap_or(self.rest.len());
+                    self.lost += skip;
+                    self.rest = &self.rest[skip..];
+                }
+            }
+        }
+        false
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        if self.version == 1 {
+            let Some((head, rest)) = self.rest.split_at_checked(RECORD_LEN) else {
+                self.lost += std::mem::take(&mut self.rest).len();
+                return None;
+            };
+            self.rest = rest;
+            return Record::decode(head).ok();
+        }
+        while self.frame.is_empty() {
+            if !self.next_frame() {
+                return None;
+            }
+        }
+        let (head, rest) = self.frame.split_at(RECORD_LEN);
+        self.frame = rest;
+        Record::decode(head).ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn record_round_trip() {
+        for r in [
+            Record::new(0, ChannelId(0), 0),
+            Record::new(123_456, ChannelId(3), -4_000),
+            Record::new(u32::MAX, ChannelId(u16::MAX), i32::MIN),
+        ] {
+            assert_eq!(Record::decode(&r.encode()), Ok(r));
+        }
+    }
+
+    #[test]
+    fn stream_round_trip_skips_torn_tail() {
+        let records: Vec<Record> = (0..5)
+            .map(|i| Record::new(i * 100, ChannelId(i as u16 % 2), i as i32 * 7))
+            .collect();
+        let mut bytes = encode_stream(&records);
+        let torn = encode_frame(&records[..2]);
+        bytes.extend_from_slice(&torn[..torn.len() - 1]);
+        let mut reader = Records::new(&bytes).unwrap();
+        assert_eq!(reader.by_ref().collect::<Vec<_>>(), records);
+        assert_eq!(reader.lost(), torn.len() - 1);
+    }
+
+    #[test]
+    fn skips_corrupt_frames() {
+        let records: Vec<Record> = (0..6).map(|i| Record::new(i, ChannelId(1), 0)).collect();
+        let mut bytes = encode_header().to_vec();
+        for pair in records.chunks(2) {
+            bytes.extend_from_slice(&encode_frame(pair));
+        }
+        let frame_len = FRAME_OVERHEAD + 2 * RECORD_LEN;
+        bytes[HEADER_LEN + frame_len + 7] ^= 1;
+        let mut reader = Records::new(&bytes).unwrap();
+        let survivors: Vec<u32> = reader.by_ref().map(|r| r.timestamp).collect();
+        assert_eq!(survivors, [0, 1, 4, 5]);
+        assert_eq!(reader.lost(), frame_len);
+    }
+
+    #[test]
+    fn reads_version_1() {
+        let records = [
+            Record::new(1, ChannelId(2), 3),
+            Record::new(4, ChannelId(5), 6),
+        ];
+        let mut bytes = b"MCL\x01".to_vec();
+        for r in &records {
+            bytes.extend_from_slice(&r.encode());
+        }
+        bytes.push(0);
+        let mut reader = Records::new(&bytes).unwrap();
+        assert_eq!(reader.by_ref().collect::<Vec<_>>(), records);
+        assert_eq!(reader.lost(), 1);
+    }
+
+    #[test]
+    fn header_checks() {
+        assert_eq!(decode_header(&encode_header()), Ok(FORMAT_VERSION));
+        assert_eq!(decode_header(b"MCL\x01"), Ok(1));
+        assert_eq!(decode_header(b"MC"), Err(DecodeError::Short));
+        assert_eq!(decode_header(b"XYZ\x01"), Err(DecodeError::BadMagic));
+        assert_eq!(
+            decode_header(b"MCL\x63"),
+            Err(DecodeError::UnsupportedVersion(0x63))
+        );
+    }
+}
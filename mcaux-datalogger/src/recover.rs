@@ -0,0 +1,320 @@
+// mcaux-datalogger/src/recover.rs
+
+//! Taking stock after an unclean power cut: which stored data still checks
+//! out, what's torn or corrupt, and where the last good write ended.
+
+use std::ops::Range;
+
+use crate::compress::{BLOCK_SYNC, COMPRESSED_MAGIC, COMPRESSED_VERSION, decode_block};
+use crate::record::{
+    DecodeError, FRAME_SYNC, HEADER_LEN, RECORD_LEN, Record, decode_frame, decode_header, resync,
+};
+use crate::storage::{LogFs, StorageError, parse_log_file_name};
+
+/// Decodes the frame or block at the start of a buffer, and its length.
+type DecodeUnit = fn(&[u8]) -> Result<(Vec<Record>, usize), DecodeError>;
+
+/// What a scan of one stream found.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StreamScan {
+    pub records: Vec<Record>,
+    /// Byte ranges skipped as torn or corrupt
+    pub bad_regions: Vec<Range<usize>>,
+    /// Just past the last frame or block that checked out
+    pub valid_end: usize,
+}
+
+impl StreamScan {
+    pub fn lost_bytes(&self) -> usize {
+        self.bad_regions.iter().map(|r| r.len()).sum()
+    }
+}
+
+/// Walk a plain or compressed stream, keeping every frame or block that
+/// passes its CRC and resynchronising past any that don't.
+///
+/// Plain version 1 streams have no CRCs, so only a partial record at the
+/// end can be noticed there.
+pub fn scan_stream(bytes: &[u8]) -> StreamScan {
+    let mut scan = StreamScan::default();
+    let whole = 0..bytes.len();
+
+    let (sync, decode): (_, DecodeUnit) =
+        if bytes.len() >= 4 && bytes[..3] == COMPRESSED_MAGIC && bytes[3] == COMPRESSED_VERSION {
+            (BLOCK_SYNC, |b| {
+                decode_block(b).map(|(block, used)| (block.records, used))
+            })
+        } else {
+            match decode_header(bytes) {
+                Ok(1) => {
+                    let body = &bytes[HEADER_LEN..];
+                    let whole_records = body.len() / RECORD_LEN * RECORD_LEN;
+                    scan.records = body[..whole_records]
+                        .chunks(RECORD_LEN)
+                        .filter_map(|c| Record::decode(c).ok())
+                        .collect();
+                    scan.valid_end = HEADER_LEN + whole_records;
+                    if scan.valid_end < bytes.len() {
+                        scan.bad_regions.push(scan.valid_end..bytes.len());
+                    }
+                    return scan;
+                }
+                Ok(_) => (FRAME_SYNC, |b| {
+                    decode_frame(b).map(|(records, used)| {
+                        let records = records
+                            .chunks(RECORD_LEN)
+                            .filter_map(|c| Record::decode(c).ok())
+                            .collect();
+                        (records, used)
+                    })
+                }),
+                Err(_) => {
+                    if !bytes.is_empty() {
+                        scan.bad_regions.push(whole);
+                    }
+                    return scan;
+                }
+            }
+        };
+
+    let mut pos = HEADER_LEN;
+    scan.valid_end = pos;
+    while pos < bytes.len() {
+        match decode(&bytes[pos..]) {
+            Ok((records, used)) => {
+                scan.records.extend(records);
+                pos += used;
+                scan.valid_end = pos;
+            }
+            Err(_) => {
+                let skip =
+                    resync(&bytes[pos..], sync, |b| decode(b).is_ok()).unwrap_or(bytes.len() - pos);
+                // merge with a bad region we're still in
+                match scan.bad_regions.last_mut() {
+                    Some(last) if last.end == pos => last.end += skip,
+                    _ => scan.bad_regions.push(pos..pos + skip),
+                }
+                pos += skip;
+            }
+        }
+    }
+    scan
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileReport {
+    pub seq: u32,
+    pub name: String,
+    pub size: usize,
+    pub scan: StreamScan,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Every log file, oldest first
+    pub files: Vec<FileReport>,
+}
+
+impl RecoveryReport {
+    pub fn records(&self) -> usize {
+        self.files.iter().map(|f| f.scan.records.len()).sum()
+    }
+
+    pub fn lost_bytes(&self) -> usize {
+        self.files.iter().map(|f| f.scan.lost_bytes()).sum()
+    }
+
+    /// Where the newest good data ends: the last log file holding any,
+    /// and the offset just past its last good frame. Logging resumes in a
+    /// fresh file after this one.
+    pub fn newest_valid(&self) -> Option<(u32, usize)> {
+        self.files
+            .iter()
+            .rev()
+            .find(|f| !f.scan.records.is_empty())
+            .map(|f| (f.seq, f.scan.valid_end))
+    }
+
+    /// Records that survived, oldest first.
+    pub fn all_records(&self) -> impl Iterator<Item = &Record> + '_ {
+        self.files.iter().flat_map(|f| f.scan.records.iter())
+    }
+}
+
+/// Scan every log file on `fs`. Nothing is changed: corrupt regions stay
+/// on storage, and readers skip them the same way.
+pub fn recover<F: LogFs>(fs: &mut F) -> Result<RecoveryReport, StorageError<F::Error>> {
+    let mut files: Vec<(u32, String)> = fs
+        .list()
+        .map_err(StorageError::Fs)?
+        .into_iter()
+        .filter_map(|n| parse_log_file_name(&n).map(|seq| (seq, n)))
+        .collect();
+    files.sort();
+    let mut report = RecoveryReport::default();
+    for (seq, name) in files {
+        let bytes = fs.read(&name).map_err(StorageError::Fs)?;
+        report.files.push(FileReport {
+            seq,
+            size: bytes.len(),
+            scan: scan_stream(&bytes),
+            name,
+        });
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compress::compress;
+    use crate::image::{decode_image, encode_image};
+    use crate::memfs::MemFs;
+    use crate::record::{ChannelId, FRAME_OVERHEAD};
+    use crate::storage::{RotatingLog, RotationPolicy};
+
+    /// xorshift, so failures are repeatable
+    struct Rng(u32);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 as usize % n
+        }
+    }
+
+    fn rec(t: u32) -> Record {
+        Record::new(t, ChannelId(1 + t as u16 % 3), t as i32 * 7 - 500)
+    }
+
+    /// Logged in writes of a few records each, over several files.
+    fn stored() -> (MemFs, Vec<Record>) {
+        let policy = RotationPolicy {
+            max_file_bytes: 400,
+            ..Default::default()
+        };
+        let mut log = RotatingLog::open(MemFs::new(64 * 1024), policy).unwrap();
+        let records: Vec<Record> = (0..300).map(rec).collect();
+        for chunk in records.chunks(7) {
+            log.write(chunk).unwrap();
+        }
+        (log.into_inner(), records)
+    }
+
+    /// Every survivor was really written, in the order it was written.
+    fn assert_subsequence<'a>(survivors: impl IntoIterator<Item = &'a Record>, of: &[Record]) {
+        let mut originals = of.iter();
+        for s in survivors {
+            assert!(originals.any(|o| o == s), "{s:?} was never written");
+        }
+    }
+
+    #[test]
+    fn clean_storage_loses_nothing() {
+        let (mut fs, records) = stored();
+        let report = recover(&mut fs).unwrap();
+        assert_eq!(report.lost_bytes(), 0);
+        assert_eq!(report.all_records().copied().collect::<Vec<_>>(), records);
+        let last = report.files.last().unwrap();
+        assert_eq!(report.newest_valid(), Some((last.seq, last.size)));
+    }
+
+    #[test]
+    fn torn_and_corrupt_regions_are_skipped_and_counted() {
+        let (mut fs, records) = stored();
+        let names = fs.list().unwrap();
+        // a bit flipped in the first frame of one file...
+        fs.file_mut(&names[1]).unwrap()[HEADER_LEN + 5] ^= 0x40;
+        // ...and the last write torn partway
+        let last = fs.file_mut(names.last().unwrap()).unwrap();
+        let torn_at = last.len() - 3;
+        last.truncate(torn_at);
+
+        let report = recover(&mut fs).unwrap();
+        let frame = FRAME_OVERHEAD + 7 * RECORD_LEN;
+        let bad = &report.files[1].scan.bad_regions;
+        assert_eq!(bad.len(), 1);
+        assert_eq!(bad[0], HEADER_LEN..HEADER_LEN + frame);
+        let newest = report.files.last().unwrap();
+        assert_eq!(newest.scan.bad_regions.len(), 1);
+        assert_eq!(newest.scan.bad_regions[0].end, torn_at);
+        assert_eq!(
+            report.newest_valid(),
+            Some((newest.seq, newest.scan.bad_regions[0].start))
+        );
+        assert_eq!(report.records(), records.len() - 7 - newest_lost(&records));
+        assert_subsequence(report.all_records(), &records);
+    }
+
+    /// The last write holds whatever's left after the 7-record chunks.
+    fn newest_lost(records: &[Record]) -> usize {
+        match records.len() % 7 {
+            0 => 7,
+            n => n,
+        }
+    }
+
+    #[test]
+    fn fuzz_corrupt_stored_files() {
+        let (clean, records) = stored();
+        let mut rng = Rng(0x9e37_79b9);
+        for _ in 0..300 {
+            let mut fs = clean.clone();
+            let names = fs.list().unwrap();
+            for _ in 0..1 + rng.below(4) {
+                let name = &names[rng.below(names.len())];
+                let data = fs.file_mut(name).unwrap();
+                let at = rng.below(data.len());
+                match rng.below(3) {
+                    0 => data[at] ^= 1 << rng.below(8),
+                    1 => data[at] = rng.below(256) as u8,
+                    _ => data.truncate(at),
+                }
+            }
+
+            let report = recover(&mut fs).unwrap();
+            assert_subsequence(report.all_records(), &records);
+            for f in &report.files {
+                assert!(f.scan.valid_end <= f.size);
+                assert!(f.scan.lost_bytes() <= f.size);
+            }
+            // readers agree with the scan
+            let mut log = RotatingLog::open(fs, RotationPolicy::default()).unwrap();
+            if let Ok(read) = log.read_all() {
+                let scanned: Vec<Record> = report.all_records().copied().collect();
+                assert_eq!(read, scanned);
+            }
+        }
+    }
+
+    #[test]
+    fn fuzz_corrupt_compressed_streams_and_images() {
+        let records: Vec<Record> = (0..500).map(rec).collect();
+        let stream = compress(&records, 50);
+        let (mut clean, _) = stored();
+        let image = encode_image(&mut clean).unwrap();
+
+        let mut rng = Rng(0x1234_5678);
+        for _ in 0..300 {
+            let mut bad = stream.clone();
+            for _ in 0..1 + rng.below(3) {
+                let at = rng.below(bad.len());
+                bad[at] ^= 1 << rng.below(8);
+            }
+            let scan = scan_stream(&bad);
+            assert_subsequence(&scan.records, &records);
+            if scan.lost_bytes() == 0 {
+                assert_eq!(scan.records, records);
+            }
+
+            // the image container has no CRC of its own, but mustn't panic
+            // or allocate wildly on garbage
+            let mut bad = image.clone();
+            let at = rng.below(bad.len());
+            bad[at] = rng.below(256) as u8;
+            let _ = decode_image(&bad);
+        }
+    }
+}
@@ -0,0 +1,508 @@
+// mcaux-datalogger/src/retention.rs
+
+//! Tiered retention: the last few rides at full rate, older ones boiled
+//! down to min/mean/max per minute, and the oldest deleted once the log
+//! outgrows its budget.
+//!
+//! The budget covers everything the log keeps: log files, event capture
+//! windows and the session summaries. Log files go first, oldest first,
+//! and with them the summaries of rides left with no records; capture
+//! windows, the records worth keeping longest, only go once no log file
+//! but the one being written is left.
+//!
+//! Downsampled data goes in a `.mcr` file with the sequence number of the
+//! `.mcl` file it replaces and the same stream format, so it sorts and
+//! reads like any other log file. Each bucket becomes three records on
+//! rollup channels, stamped with the start of the bucket. Events, sync
+//! points and ignition are kept as they were.
+//!
+//! Compaction never leaves a ride half-replaced. The `.mcr` is written in
+//! full before the `.mcl` goes, and a `.mcr` found next to its `.mcl` is
+//! what power loss partway through leaves behind: `RotatingLog::open`
+//! removes it, and the next pass starts that file over.
+
+use std::collections::BTreeMap;
+
+use crate::channel::IGNITION;
+use crate::event::{is_event, parse_capture_file_name, read_captures};
+use crate::record::{ChannelId, Record, Records, encode_stream};
+use crate::session::{SESSIONS_FILE, SessionSummary, read_summaries};
+use crate::storage::{
+    LogFile, LogFs, RotatingLog, StorageError, parse_log_file_name, rollup_file_name,
+};
+use crate::time::is_time_sync;
+
+/// Top four bits of rollup channel ids. The next two say which statistic,
+/// and the low ten which channel it summarizes.
+pub const ROLLUP_TAG: u16 = 0xd000;
+
+pub fn is_rollup(channel: ChannelId) -> bool {
+    channel.0 & 0xf000 == ROLLUP_TAG
+}
+
+/// What a rollup record says about its bucket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Stat {
+    Min,
+    Mean,
+    Max,
+}
+
+impl Stat {
+    pub const ALL: [Stat; 3] = [Stat::Min, Stat::Mean, Stat::Max];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stat::Min => "min",
+            Stat::Mean => "mean",
+            Stat::Max => "max",
+        }
+    }
+
+    /// The channel carrying this statistic of `source`, or None if
+    /// `source`'s id is too big to fit (0x400 and up).
+    pub fn channel(self, source: ChannelId) -> Option<ChannelId> {
+        (source.0 < 0x400).then_some(ChannelId(ROLLUP_TAG | (self as u16) << 10 | source.0))
+    }
+
+    /// The channel summarized and how, if `channel` is a rollup.
+    pub fn parse(channel: ChannelId) -> Option<(ChannelId, Stat)> {
+        if !is_rollup(channel) {
+            return None;
+        }
+        let stat = *Stat::ALL.get((channel.0 >> 10 & 3) as usize)?;
+        Some((ChannelId(channel.0 & 0x3ff), stat))
+    }
+}
+
+/// Min, mean and max of each channel per `bucket_ms`, from one boot's
+/// records. Records with nothing to average (events, sync points,
+/// ignition, rollups already, and channels too big to tag) pass through.
+/// Comes out in time order.
+pub fn downsample(records: &[Record], bucket_ms: u32) -> Vec<Record> {
+    assert!(bucket_ms > 0, "Downsampling needs a bucket width");
+    let mut out = Vec::new();
+    // (bucket, channel) -> min, max, sum, count
+    let mut buckets: BTreeMap<(u32, ChannelId), (i32, i32, i64, i64)> = BTreeMap::new();
+    for r in records {
+        if is_event(r.channel)
+            || is_time_sync(r.channel)
+            || is_rollup(r.channel)
+            || r.channel == IGNITION
+            || Stat::Min.channel(r.channel).is_none()
+        {
+            out.push(*r);
+            continue;
+        }
+        let b = buckets
+            .entry((r.timestamp / bucket_ms, r.channel))
+            .or_insert((i32::MAX, i32::MIN, 0, 0));
+        b.0 = b.0.min(r.value);
+        b.1 = b.1.max(r.value);
+        b.2 += r.value as i64;
+        b.3 += 1;
+    }
+    for ((bucket, channel), (min, max, sum, count)) in buckets {
+        let t = bucket * bucket_ms;
+        let mean = (sum as f64 / count as f64).round() as i32;
+        for (stat, value) in Stat::ALL.into_iter().zip([min, mean, max]) {
+            out.push(Record::new(t, stat.channel(channel).unwrap(), value));
+        }
+    }
+    // stable, so pass-through records stay in logged order
+    out.sort_by_key(|r| r.timestamp);
+    out
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Rides kept at full rate, counting back from the newest finished one.
+    /// The file being written is never touched.
+    pub full_rate_rides: usize,
+    /// Width of the buckets older rides are downsampled to.
+    pub bucket_ms: u32,
+    /// Delete the oldest log files, then capture windows, while they and
+    /// the session summaries together are bigger.
+    pub max_total_bytes: usize,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            full_rate_rides: 5,
+            bucket_ms: 60_000,
+            max_total_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// What a retention pass did, by log file sequence number.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetentionReport {
+    pub downsampled: Vec<u32>,
+    pub deleted: Vec<u32>,
+    /// Capture windows deleted, by number
+    pub deleted_captures: Vec<u32>,
+    /// Session summaries dropped along with the last of their files
+    pub dropped_sessions: usize,
+    /// Bytes of log files, capture windows and summaries before and after
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+}
+
+/// Downsample rides older than the last `full_rate_rides`, then delete
+/// from the oldest down to the budget. Rides are found from the session
+/// summaries, so this goes with a `SessionLog`; without summaries every
+/// file counts as recent, and only the budget applies.
+pub fn apply_retention<F: LogFs>(
+    log: &mut RotatingLog<F>,
+    policy: &RetentionPolicy,
+) -> Result<RetentionReport, StorageError<F::Error>> {
+    let mut summaries = read_summaries(log.fs_mut())?;
+    // the first file of the oldest ride to keep at full rate
+    let keep_from = match summaries.len().checked_sub(policy.full_rate_rides) {
+        None => 0,
+        Some(older) => summaries[older..]
+            .iter()
+            .filter_map(|s| s.files)
+            .map(|(first, _)| first)
+            .min()
+            .unwrap_or(u32::MAX),
+    };
+    let current = log.current_file();
+
+    let captures = read_captures(log.fs_mut()).map_err(StorageError::Fs)?;
+    let capture_bytes: usize = captures.iter().map(|(_, data)| data.len()).sum();
+    let mut sessions_bytes = file_size(log.fs_mut(), SESSIONS_FILE)?;
+
+    let mut report = RetentionReport {
+        bytes_before: capture_bytes + sessions_bytes,
+        ..Default::default()
+    };
+    // (seq, name, size) after downsampling, oldest first
+    let mut kept = Vec::new();
+    for file in log.files()? {
+        let bytes = log.read_file(&file)?;
+        report.bytes_before += bytes.len();
+        if file.seq < keep_from && Some(file.seq) != current && !is_rollup_file(&file) {
+            let size = compact(log.fs_mut(), &file, &bytes, policy.bucket_ms)?;
+            report.downsampled.push(file.seq);
+            kept.push((file.seq, rollup_file_name(file.seq), size));
+        } else {
+            kept.push((file.seq, file.name, bytes.len()));
+        }
+    }
+
+    let mut total: usize =
+        kept.iter().map(|(_, _, size)| size).sum::<usize>() + capture_bytes + sessions_bytes;
+    let mut left = Vec::new();
+    for (seq, name, size) in kept {
+        if total > policy.max_total_bytes && Some(seq) != current {
+            log.fs_mut().remove(&name).map_err(StorageError::Fs)?;
+            report.deleted.push(seq);
+            total -= size;
+        } else {
+            left.push(seq);
+        }
+    }
+
+    // a summary stays while any of its files does
+    let count = summaries.len();
+    summaries.retain(|s| match s.files {
+        Some((first, last)) => left.iter().any(|seq| (first..=last).contains(seq)),
+        None => true,
+    });
+    if summaries.len() < count {
+        report.dropped_sessions = count - summaries.len();
+        total -= sessions_bytes;
+        sessions_bytes = rewrite_summaries(log.fs_mut(), &summaries)?;
+        total += sessions_bytes;
+    }
+
+    for (name, data) in captures {
+        if total <= policy.max_total_bytes {
+            break;
+        }
+        log.fs_mut().remove(&name).map_err(StorageError::Fs)?;
+        report
+            .deleted_captures
+            .extend(parse_capture_file_name(&name));
+        total -= data.len();
+    }
+    report.bytes_after = total;
+    Ok(report)
+}
+
+/// Size of `name`, or 0 if there's no such file.
+fn file_size<F: LogFs>(fs: &mut F, name: &str) -> Result<usize, StorageError<F::Error>> {
+    if !fs
+        .list()
+        .map_err(StorageError::Fs)?
+        .iter()
+        .any(|n| n == name)
+    {
+        return Ok(0);
+    }
+    Ok(fs.read(name).map_err(StorageError::Fs)?.len())
+}
+
+/// Replace the summaries file with just `summaries`, returning its size.
+/// Power loss in between loses the summaries, never records, and
+/// `summarize_sessions` can work them out again.
+fn rewrite_summaries<F: LogFs>(
+    fs: &mut F,
+    summaries: &[SessionSummary],
+) -> Result<usize, StorageError<F::Error>> {
+    fs.remove(SESSIONS_FILE).map_err(StorageError::Fs)?;
+    let bytes: Vec<u8> = summaries.iter().flat_map(|s| s.encode()).collect();
+    if !bytes.is_empty() {
+        fs.append(SESSIONS_FILE, &bytes).map_err(StorageError::Fs)?;
+    }
+    Ok(bytes.len())
+}
+
+fn is_rollup_file(file: &LogFile) -> bool {
+    file.name == rollup_file_name(file.seq)
+}
+
+/// Replace a `.mcl` file with its downsampled `.mcr`, returning the new
+/// size. The `.mcr` is complete before the `.mcl` is removed.
+fn compact<F: LogFs>(
+    fs: &mut F,
+    file: &LogFile,
+    bytes: &[u8],
+    bucket_ms: u32,
+) -> Result<usize, StorageError<F::Error>> {
+    let records: Vec<Record> = Records::new(bytes)?.collect();
+    let rollup = encode_stream(&downsample(&records, bucket_ms));
+    fs.append(&rollup_file_name(file.seq), &rollup)
+        .map_err(StorageError::Fs)?;
+    fs.remove(&file.name).map_err(StorageError::Fs)?;
+    Ok(rollup.len())
+}
+
+/// Remove `.mcr` files whose `.mcl` is still there: compaction was cut
+/// short, so they may be incomplete.
+pub(crate) fn remove_unfinished<F: LogFs>(fs: &mut F) -> Result<(), F::Error> {
+    let names = fs.list()?;
+    for name in &names {
+        if let Some(seq) = parse_log_file_name(name)
+            && *name == rollup_file_name(seq)
+            && names
+                .iter()
+                .any(|n| parse_log_file_name(n) == Some(seq) && n != name)
+        {
+            fs.remove(name)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::{REGULATOR_TEMP_C, RPM};
+    use crate::event::{CaptureWindow, Event, capture_file_name};
+    use crate::memfs::MemFs;
+    use crate::session::SessionLog;
+    use crate::sim::{RideConfig, ride};
+    use crate::storage::RotationPolicy;
+
+    #[test]
+    fn min_mean_max_per_bucket() {
+        let ev = Event::output_changed(61_000, 1, 2).to_record();
+        let records = [
+            Record::new(0, IGNITION, 1),
+            Record::new(1_000, RPM, 1_000),
+            Record::new(30_000, RPM, 3_000),
+            Record::new(59_999, RPM, 2_500),
+            Record::new(60_000, REGULATOR_TEMP_C, 4_000),
+            ev,
+        ];
+        let rpm = |stat: Stat| stat.channel(RPM).unwrap();
+        let reg = |stat: Stat| stat.channel(REGULATOR_TEMP_C).unwrap();
+        assert_eq!(
+            downsample(&records, 60_000),
+            [
+                Record::new(0, IGNITION, 1),
+                Record::new(0, rpm(Stat::Min), 1_000),
+                Record::new(0, rpm(Stat::Mean), 2_167),
+                Record::new(0, rpm(Stat::Max), 3_000),
+                Record::new(60_000, reg(Stat::Min), 4_000),
+                Record::new(60_000, reg(Stat::Mean), 4_000),
+                Record::new(60_000, reg(Stat::Max), 4_000),
+                ev,
+            ]
+        );
+        assert_eq!(Stat::parse(rpm(Stat::Max)), Some((RPM, Stat::Max)));
+        assert_eq!(Stat::parse(RPM), None);
+        assert_eq!(Stat::Min.channel(ChannelId(0x400)), None);
+    }
+
+    /// `rides` twenty-minute rides, one after the other in one boot.
+    fn logged(rides: u32) -> SessionLog<MemFs> {
+        let mut log =
+            SessionLog::open(MemFs::new(1 << 20), RotationPolicy::default(), &[]).unwrap();
+        for i in 0..rides {
+            let mut records = ride(
+                RideConfig {
+                    seed: i + 1,
+                    ..Default::default()
+                },
+                20 * 60_000,
+            );
+            for r in records.iter_mut() {
+                r.timestamp += i * 30 * 60_000;
+            }
+            // a few seconds at a time, as on the bike
+            for chunk in records.chunks(50) {
+                log.write(chunk).unwrap();
+            }
+        }
+        log
+    }
+
+    #[test]
+    fn keeps_recent_rides_full_rate_and_downsamples_older() {
+        let mut log = logged(4);
+        let summaries = log.summaries().unwrap();
+        let before = log.log().read_all().unwrap();
+        let policy = RetentionPolicy {
+            full_rate_rides: 2,
+            ..Default::default()
+        };
+        let report = apply_retention(log.log(), &policy).unwrap();
+        let (first_kept, _) = summaries[2].files.unwrap();
+        assert_eq!(report.downsampled, (0..first_kept).collect::<Vec<_>>());
+        assert!(report.deleted.is_empty());
+        // half the rides, each shrunk many times over
+        assert!(report.bytes_after * 10 < report.bytes_before * 6);
+
+        // older rides are rollups now; recent ones untouched
+        let after = log.log().read_all().unwrap();
+        let split = before
+            .iter()
+            .position(|r| r.timestamp >= 60 * 60_000)
+            .unwrap();
+        assert_eq!(
+            after[after.len() - (before.len() - split)..],
+            before[split..]
+        );
+        assert!(
+            after[..after.len() - (before.len() - split)]
+                .iter()
+                .all(|r| { is_rollup(r.channel) || r.channel == IGNITION })
+        );
+        assert_eq!(log.summaries().unwrap(), summaries);
+
+        // a second pass has nothing left to do
+        let again = apply_retention(log.log(), &policy).unwrap();
+        assert!(again.downsampled.is_empty());
+        assert_eq!(again.bytes_before, report.bytes_after);
+    }
+
+    #[test]
+    fn budget_deletes_oldest_first() {
+        let mut log = logged(3);
+        let files = log.log().files().unwrap();
+        let policy = RetentionPolicy {
+            full_rate_rides: 3,
+            max_total_bytes: 40_000,
+            ..Default::default()
+        };
+        let report = apply_retention(log.log(), &policy).unwrap();
+        assert!(report.downsampled.is_empty());
+        assert!(!report.deleted.is_empty());
+        assert!(report.bytes_after <= 40_000);
+        let left: Vec<u32> = log.log().files().unwrap().iter().map(|f| f.seq).collect();
+        assert_eq!(
+            left,
+            files[report.deleted.len()..]
+                .iter()
+                .map(|f| f.seq)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn budget_counts_captures_and_summaries() {
+        let mut log = logged(3);
+        let summaries = log.summaries().unwrap();
+        let window = |t| CaptureWindow {
+            event: Event::output_changed(t, 1, 2),
+            records: vec![Record::new(t, RPM, 3_000); 20],
+        };
+        for t in [1_000, 2_000] {
+            window(t).save(log.log().fs_mut()).unwrap();
+        }
+        let files = log.log().files().unwrap();
+        let newest = files.last().unwrap();
+        // room for the file being written, the last ride's summary and
+        // one capture window
+        let budget = log.log().read_file(newest).unwrap().len()
+            + summaries[2].encode().len()
+            + window(0).encode().len();
+        let policy = RetentionPolicy {
+            full_rate_rides: 3,
+            max_total_bytes: budget,
+            ..Default::default()
+        };
+        let report = apply_retention(log.log(), &policy).unwrap();
+        assert_eq!(report.deleted.len(), files.len() - 1);
+        assert_eq!(report.dropped_sessions, 2);
+        assert_eq!(report.deleted_captures, [0]);
+        assert_eq!(report.bytes_after, budget);
+        assert_eq!(log.summaries().unwrap(), summaries[2..]);
+        let left = read_captures(log.log().fs_mut()).unwrap();
+        assert_eq!(left, [(capture_file_name(1), window(2_000).encode())]);
+    }
+
+    #[test]
+    fn power_loss_during_compaction_loses_nothing() {
+        let clean = logged(2).into_inner();
+        let policy = RetentionPolicy {
+            full_rate_rides: 1,
+            ..Default::default()
+        };
+        // each file's records, at full rate and downsampled
+        let by_file = |fs: MemFs| {
+            let mut log = RotatingLog::open(fs, RotationPolicy::default()).unwrap();
+            let mut files = BTreeMap::new();
+            for f in log.files().unwrap() {
+                let bytes = log.read_file(&f).unwrap();
+                files.insert(f.seq, Records::new(&bytes).unwrap().collect::<Vec<_>>());
+            }
+            files
+        };
+        let original = by_file(clean.clone());
+        let mut done = RotatingLog::open(clean.clone(), RotationPolicy::default()).unwrap();
+        let report = apply_retention(&mut done, &policy).unwrap();
+        assert!(report.downsampled.len() > 1);
+        let compacted = by_file(done.into_inner());
+
+        let mut cuts = 0;
+        for cut in (0..4_000).step_by(37) {
+            let mut fs = clean.clone();
+            fs.cut_power_after(cut);
+            let mut log = RotatingLog::open(fs, RotationPolicy::default()).unwrap();
+            if apply_retention(&mut log, &policy).is_ok() {
+                continue;
+            }
+            cuts += 1;
+            // reboot: every file reads back at full rate or downsampled,
+            // never half done, and the next pass finishes the job
+            let mut fs = log.into_inner();
+            fs.restore_power();
+            for (seq, records) in by_file(fs.clone()) {
+                assert!(
+                    records == original[&seq] || records == compacted[&seq],
+                    "file {seq} after a cut at {cut}"
+                );
+            }
+            let mut log = RotatingLog::open(fs, RotationPolicy::default()).unwrap();
+            apply_retention(&mut log, &policy).unwrap();
+            assert_eq!(by_file(log.into_inner()), compacted);
+        }
+        assert!(cuts > 10);
+    }
+}
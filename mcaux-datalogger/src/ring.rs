@@ -0,0 +1,133 @@
+// mcaux-datalogger/src/ring.rs
+
+use crate::record::{Record, RecordSink, encode_stream};
+
+/// Fixed-capacity record store in RAM. Once full, each new record
+/// overwrites the oldest one.
+pub struct RingBuffer<const N: usize> {
+    buf: [Record; N],
+    /// Index of the oldest record
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> Default for RingBuffer<N> {
+    fn default() -> Self {
+        RingBuffer {
+            buf: [Record::default(); N],
+            head: 0,
+            len: 0,
+        }
+    }
+}
+
+impl<const N: usize> RingBuffer<N> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a record, returning the one it displaced if we were full.
+    pub fn push(&mut self, record: Record) -> Option<Record> {
+        if N == 0 {
+            return Some(record);
+        }
+        if self.len < N {
+            self.buf[(self.head + self.len) % N] = record;
+            self.len += 1;
+            None
+        } else {
+            let old = std::mem::replace(&mut self.buf[self.head], record);
+            self.head = (self.head + 1) % N;
+            Some(old)
+        }
+    }
+
+    /// Remove and return the oldest record.
+    pub fn pop(&mut self) -> Option<Record> {
+        if self.len == 0 {
+            return None;
+        }
+        let r = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(r)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Oldest to newest.
+    pub fn iter(&self) -> impl Iterator<Item = &Record> + '_ {
+        (0..self.len).map(move |i| &self.buf[(self.head + i) % N])
+    }
+
+    /// Everything held, oldest first, as an encoded log stream.
+    pub fn encode(&self) -> Vec<u8> {
+        encode_stream(self.iter())
+    }
+}
+
+impl<const N: usize> RecordSink for RingBuffer<N> {
+    fn push_record(&mut self, record: Record) {
+        self.push(record);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::record::{ChannelId, Records};
+
+    fn rec(t: u32) -> Record {
+        Record::new(t, ChannelId(1), t as i32)
+    }
+
+    #[test]
+    fn overwrites_oldest() {
+        let mut ring: RingBuffer<3> = RingBuffer::new();
+        assert_eq!(ring.push(rec(1)), None);
+        assert_eq!(ring.push(rec(2)), None);
+        assert_eq!(ring.push(rec(3)), None);
+        assert!(ring.is_full());
+        assert_eq!(ring.push(rec(4)), Some(rec(1)));
+        assert_eq!(
+            ring.iter().copied().collect::<Vec<_>>(),
+            [rec(2), rec(3), rec(4)]
+        );
+        assert_eq!(ring.pop(), Some(rec(2)));
+        ring.push(rec(5));
+        assert_eq!(
+            ring.iter().map(|r| r.timestamp).collect::<Vec<_>>(),
+            [3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn encodes_in_order() {
+        let mut ring: RingBuffer<4> = RingBuffer::new();
+        for t in 0..10 {
+            ring.push(rec(t));
+        }
+        let bytes = ring.encode();
+        let decoded: Vec<u32> = Records::new(&bytes).unwrap().map(|r| r.timestamp).collect();
+        assert_eq!(decoded, [6, 7, 8, 9]);
+    }
+}
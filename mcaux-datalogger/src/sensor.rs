@@ -0,0 +1,254 @@
+// mcaux-datalogger/src/sensor.rs
+
+//! Temperature sensors as sample sources for the logger's channels.
+//!
+//! Each driver implements `TemperatureSensor` over embedded-hal traits, so
+//! the same code reads real hardware on the bike and mock buses in tests.
+//! `TemperatureSensor::log_to` reads one and offers the sample to a
+//! `Scheduler`. This module has the NTC thermistor, read through an ADC;
+//! `ds18b20` and `tmp117` have the digital ones.
+
+use std::fmt;
+
+use crate::channel::Scheduler;
+use crate::record::{ChannelId, RecordSink};
+
+/// Zero Celsius in kelvin
+const KELVIN: f32 = 273.15;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SensorError<E> {
+    Bus(E),
+    /// Nothing answered, or something other than the expected part did.
+    Missing,
+    /// A reading failed its checksum.
+    Crc,
+    /// No conversion has been started yet.
+    NotReady,
+    /// A reading no working sensor gives, such as an open or shorted
+    /// thermistor.
+    Fault,
+}
+
+impl<E: fmt::Debug> fmt::Display for SensorError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SensorError::Bus(e) => write!(f, "bus error: {e:?}"),
+            SensorError::Missing => write!(f, "sensor not found"),
+            SensorError::Crc => write!(f, "reading failed its CRC"),
+            SensorError::NotReady => write!(f, "no conversion yet"),
+            SensorError::Fault => write!(f, "sensor fault"),
+        }
+    }
+}
+
+impl<E: fmt::Debug> std::error::Error for SensorError<E> {}
+
+pub trait TemperatureSensor {
+    type Error;
+
+    fn read_celsius(&mut self) -> Result<f32, Self::Error>;
+
+    /// Read and offer the sample on channel `id`, passing it on to `sink`
+    /// if it's worth logging. Call when `scheduler.due(now)` names `id`.
+    fn log_to<S: RecordSink>(
+        &mut self,
+        scheduler: &mut Scheduler,
+        sink: &mut S,
+        now: u32,
+        id: ChannelId,
+    ) -> Result<bool, Self::Error> {
+        let celsius = self.read_celsius()?;
+        Ok(scheduler.offer_to(sink, now, id, celsius))
+    }
+}
+
+/// One ADC input. embedded-hal 1.0 has no ADC trait, so wrap the HAL's
+/// one-shot read in this.
+pub trait AdcChannel {
+    type Error;
+
+    /// The raw conversion, 0..=`max_code()`
+    fn read(&mut self) -> Result<u16, Self::Error>;
+
+    /// The code at the reference voltage, e.g. 4095 for 12 bits.
+    fn max_code(&self) -> u16;
+}
+
+/// 1/T = a + b ln R + c (ln R)^3, T in kelvin and R in ohms.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SteinhartHart {
+    pub a: f32,
+    pub b: f32,
+    pub c: f32,
+}
+
+impl SteinhartHart {
+    /// From the two figures on most datasheets: resistance at 25 °C and
+    /// the B constant. Good to a degree or so over 0..100 °C.
+    pub fn from_beta(r25_ohms: f32, beta: f32) -> Self {
+        let b = 1.0 / beta;
+        SteinhartHart {
+            a: 1.0 / (25.0 + KELVIN) - b * r25_ohms.ln(),
+            b,
+            c: 0.0,
+        }
+    }
+
+    /// Through three (°C, ohms) points from the resistance table, ideally
+    /// spread over the range that matters.
+    pub fn fit(points: [(f32, f32); 3]) -> Self {
+        // in f64: the differences of reciprocals lose too much in f32
+        let [(l1, y1), (l2, y2), (l3, y3)] =
+            points.map(|(c, r)| ((r as f64).ln(), 1.0 / (c as f64 + KELVIN as f64)));
+        let g2 = (y2 - y1) / (l2 - l1);
+        let g3 = (y3 - y1) / (l3 - l1);
+        let c = (g3 - g2) / (l3 - l2) / (l1 + l2 + l3);
+        let b = g2 - c * (l1 * l1 + l1 * l2 + l2 * l2);
+        let a = y1 - (b + l1 * l1 * c) * l1;
+        SteinhartHart {
+            a: a as f32,
+            b: b as f32,
+            c: c as f32,
+        }
+    }
+
+    pub fn celsius(&self, ohms: f32) -> f32 {
+        let l = ohms.ln();
+        1.0 / (self.a + self.b * l + self.c * l * l * l) - KELVIN
+    }
+}
+
+/// Which side of the divider the thermistor is on; the fixed resistor is
+/// on the other, and the ADC reads the middle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NtcWiring {
+    /// Thermistor to ground, resistor to the ADC reference
+    LowSide,
+    /// Thermistor to the ADC reference, resistor to ground
+    HighSide,
+}
+
+/// An NTC thermistor in a divider on an ADC input. The divider has to be
+/// fed from the ADC's own reference for the ratio to mean anything.
+pub struct Ntc<A> {
+    adc: A,
+    series_ohms: f32,
+    wiring: NtcWiring,
+    curve: SteinhartHart,
+}
+
+impl<A: AdcChannel> Ntc<A> {
+    pub fn new(adc: A, series_ohms: f32, wiring: NtcWiring, curve: SteinhartHart) -> Self {
+        Ntc {
+            adc,
+            series_ohms,
+            wiring,
+            curve,
+        }
+    }
+
+    pub fn release(self) -> A {
+        self.adc
+    }
+
+    /// The thermistor's resistance, or `Fault` if the reading is pinned at
+    /// either end: an open or shorted thermistor or wire.
+    pub fn ohms(&mut self) -> Result<f32, SensorError<A::Error>> {
+        let max = self.adc.max_code();
+        let code = self.adc.read().map_err(SensorError::Bus)?;
+        if code == 0 || code >= max {
+            return Err(SensorError::Fault);
+        }
+        let ratio = code as f32 / max as f32;
+        Ok(match self.wiring {
+            NtcWiring::LowSide => self.series_ohms * ratio / (1.0 - ratio),
+            NtcWiring::HighSide => self.series_ohms * (1.0 - ratio) / ratio,
+        })
+    }
+}
+
+impl<A: AdcChannel> TemperatureSensor for Ntc<A> {
+    type Error = SensorError<A::Error>;
+
+    fn read_celsius(&mut self) -> Result<f32, Self::Error> {
+        self.ohms().map(|r| self.curve.celsius(r))
+    }
+}
+
+/// A 12-bit ADC reading whatever the test sets.
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::AdcChannel;
+    use core::convert::Infallible;
+
+    pub(crate) struct MockAdc {
+        pub(crate) code: u16,
+    }
+
+    impl AdcChannel for MockAdc {
+        type Error = Infallible;
+
+        fn read(&mut self) -> Result<u16, Self::Error> {
+            Ok(self.code)
+        }
+
+        fn max_code(&self) -> u16 {
+            4095
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::mock::MockAdc;
+    use super::*;
+    use crate::channel::{ChannelRegistry, REGULATOR_TEMP_C};
+    use crate::record::Record;
+
+    /// A 10k B3950 part's table: (°C, ohms)
+    const TABLE: [(f32, f32); 3] = [(0.0, 32_650.0), (50.0, 3_603.0), (100.0, 679.3)];
+
+    #[test]
+    fn steinhart_hart_fits_the_table() {
+        let curve = SteinhartHart::fit(TABLE);
+        for (c, r) in TABLE {
+            assert!((curve.celsius(r) - c).abs() < 0.01, "{c}");
+        }
+        // between the points it agrees with the beta model to a degree
+        let beta = SteinhartHart::from_beta(10_000.0, 3950.0);
+        assert!((beta.celsius(10_000.0) - 25.0).abs() < 1e-3);
+        assert!((curve.celsius(10_000.0) - 25.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn divider_reading_to_channel_sample() {
+        let curve = SteinhartHart::from_beta(10_000.0, 3950.0);
+        // equal halves: 10k at 25 °C
+        let mut ntc = Ntc::new(MockAdc { code: 2048 }, 10_000.0, NtcWiring::LowSide, curve);
+        let mut scheduler = Scheduler::new(ChannelRegistry::standard());
+        let mut records: Vec<Record> = Vec::new();
+        assert_eq!(
+            ntc.log_to(&mut scheduler, &mut records, 0, REGULATOR_TEMP_C),
+            Ok(true)
+        );
+        assert_eq!(records[0].channel, REGULATOR_TEMP_C);
+        assert!((records[0].value - 2500).abs() < 5, "{}", records[0].value);
+
+        // hotter: less resistance, lower on the low side, higher on the high
+        let mut low = Ntc::new(MockAdc { code: 1000 }, 10_000.0, NtcWiring::LowSide, curve);
+        let mut high = Ntc::new(MockAdc { code: 3095 }, 10_000.0, NtcWiring::HighSide, curve);
+        let (low, high) = (low.read_celsius().unwrap(), high.read_celsius().unwrap());
+        assert!((low - high).abs() < 1e-3);
+        assert!(low > 40.0);
+    }
+
+    #[test]
+    fn open_or_shorted_thermistor_is_a_fault() {
+        let curve = SteinhartHart::from_beta(10_000.0, 3950.0);
+        for code in [0, 4095] {
+            let mut ntc = Ntc::new(MockAdc { code }, 10_000.0, NtcWiring::LowSide, curve);
+            assert_eq!(ntc.read_celsius(), Err(SensorError::Fault));
+        }
+    }
+}
@@ -0,0 +1,714 @@
+// mcaux-datalogger/src/session.rs
+
+//! Ride sessions, from ignition on to ignition off, and what happened in
+//! each: per-channel min/max/mean/percentiles and time spent above
+//! thresholds.
+//!
+//! On the bike, `SessionLog` keeps running statistics as records go by and
+//! appends a summary to `sessions.mcs` at ignition off, so listing rides
+//! means reading one small file rather than decoding every record. On the
+//! host, `split_sessions` and `Summarizer` rebuild the same summaries from
+//! the records themselves, e.g. for a ride that never saw ignition off.
+
+use std::ops::Range;
+
+use crate::channel::{Channel, IGNITION};
+use crate::event::is_event;
+use crate::record::{ChannelId, DecodeError, Record};
+use crate::retention::{RetentionPolicy, RetentionReport, apply_retention};
+use crate::storage::{LogFs, RotatingLog, RotationPolicy, StorageError};
+use crate::time::is_time_sync;
+
+/// Where `SessionLog` appends its summaries.
+pub const SESSIONS_FILE: &str = "sessions.mcs";
+
+/// Fractions estimated for every channel: median, 90th and 99th percentile.
+pub const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// Most channels, and most thresholds, a stored summary has room for.
+/// `Summarizer` stops taking on new channels there, and `encode` keeps the
+/// first this many of each.
+pub const MAX_SUMMARY_ITEMS: usize = u8::MAX as usize;
+
+/// Keep track of how long a channel spends strictly above a value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Threshold {
+    pub channel: ChannelId,
+    /// Raw units, as logged
+    pub above: i32,
+}
+
+impl Threshold {
+    /// e.g. `Threshold::physical(reg.get(REGULATOR_TEMP_C).unwrap(), 80.0)`
+    pub fn physical(channel: &Channel, above: f32) -> Self {
+        Threshold {
+            channel: channel.id,
+            above: channel.to_raw(above),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelSummary {
+    pub channel: ChannelId,
+    pub count: u32,
+    /// Raw units, like everything below
+    pub min: i32,
+    pub max: i32,
+    pub sum: i64,
+    /// Estimates at `QUANTILES`
+    pub percentiles: [i32; 3],
+}
+
+impl ChannelSummary {
+    pub fn mean(&self) -> f64 {
+        self.sum as f64 / self.count as f64
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SessionSummary {
+    /// Milliseconds since boot
+    pub start_ms: u32,
+    pub end_ms: u32,
+    /// Log files holding the session's records, first and last.
+    pub files: Option<(u32, u32)>,
+    pub channels: Vec<ChannelSummary>,
+    /// Milliseconds spent above each threshold
+    pub above: Vec<(Threshold, u32)>,
+}
+
+impl SessionSummary {
+    pub fn duration_ms(&self) -> u32 {
+        self.end_ms.saturating_sub(self.start_ms)
+    }
+
+    pub fn channel(&self, id: ChannelId) -> Option<&ChannelSummary> {
+        self.channels.iter().find(|c| c.channel == id)
+    }
+
+    pub fn time_above(&self, threshold: Threshold) -> Option<u32> {
+        self.above
+            .iter()
+            .find(|(t, _)| *t == threshold)
+            .map(|(_, ms)| *ms)
+    }
+
+    /// u16 length, then start, end, first and last file (u32::MAX for
+    /// none), channel and threshold counts, then each of those, up to
+    /// `MAX_SUMMARY_ITEMS`. Integers little-endian.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&self.start_ms.to_le_bytes());
+        body.extend_from_slice(&self.end_ms.to_le_bytes());
+        let (first, last) = self.files.unwrap_or((u32::MAX, u32::MAX));
+        body.extend_from_slice(&first.to_le_bytes());
+        body.extend_from_slice(&last.to_le_bytes());
+        let channels = &self.channels[..self.channels.len().min(MAX_SUMMARY_ITEMS)];
+        let above = &self.above[..self.above.len().min(MAX_SUMMARY_ITEMS)];
+        body.push(channels.len() as u8);
+        body.push(above.len() as u8);
+        for c in channels {
+            body.extend_from_slice(&c.channel.0.to_le_bytes());
+            body.extend_from_slice(&c.count.to_le_bytes());
+            body.extend_from_slice(&c.min.to_le_bytes());
+            body.extend_from_slice(&c.max.to_le_bytes());
+            body.extend_from_slice(&c.sum.to_le_bytes());
+            for p in c.percentiles {
+                body.extend_from_slice(&p.to_le_bytes());
+            }
+        }
+        for (t, ms) in above {
+            body.extend_from_slice(&t.channel.0.to_le_bytes());
+            body.extend_from_slice(&t.above.to_le_bytes());
+            body.extend_from_slice(&ms.to_le_bytes());
+        }
+        let mut out = (body.len() as u16).to_le_bytes().to_vec();
+        out.extend_from_slice(&body);
+        out
+    }
+
+    /// Decode one summary from the front of `buf`, returning it and the
+    /// bytes it took.
+    pub fn decode(buf: &[u8]) -> Result<(SessionSummary, usize), DecodeError> {
+        let mut r = Reader(buf);
+        let len = r.u16()? as usize;
+        let mut r = Reader(r.take(len)?);
+        let start_ms = r.u32()?;
+        let end_ms = r.u32()?;
+        let files = match (r.u32()?, r.u32()?) {
+            (u32::MAX, _) => None,
+            pair => Some(pair),
+        };
+        let channel_count = r.take(1)?[0];
+        let above_count = r.take(1)?[0];
+        let mut channels = Vec::new();
+        for _ in 0..channel_count {
+            channels.push(ChannelSummary {
+                channel: ChannelId(r.u16()?),
+                count: r.u32()?,
+                min: r.i32()?,
+                max: r.i32()?,
+                sum: i64::from_le_bytes(r.take(8)?.try_into().unwrap()),
+                percentiles: [r.i32()?, r.i32()?, r.i32()?],
+            });
+        }
+        let mut above = Vec::new();
+        for _ in 0..above_count {
+            let channel = ChannelId(r.u16()?);
+            let threshold = Threshold {
+                channel,
+                above: r.i32()?,
+            };
+            above.push((threshold, r.u32()?));
+        }
+        let summary = SessionSummary {
+            start_ms,
+            end_ms,
+            files,
+            channels,
+            above,
+        };
+        Ok((summary, 2 + len))
+    }
+}
+
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        let (head, rest) = self.0.split_at_checked(n).ok_or(DecodeError::Short)?;
+        self.0 = rest;
+        Ok(head)
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn i32(&mut self) -> Result<i32, DecodeError> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+/// Every summary in a sessions file. A summary torn by power loss at the
+/// end is dropped.
+pub fn decode_summaries(mut buf: &[u8]) -> Vec<SessionSummary> {
+    let mut out = Vec::new();
+    while let Ok((summary, used)) = SessionSummary::decode(buf) {
+        out.push(summary);
+        buf = &buf[used..];
+    }
+    out
+}
+
+/// Streaming quantile estimate in constant memory: the P² algorithm (Jain &
+/// Chlamtac, 1985), which nudges five markers toward where the quantile
+/// and its neighbours should sit.
+#[derive(Clone, Copy, Debug)]
+struct P2 {
+    count: usize,
+    /// Marker heights
+    h: [f64; 5],
+    /// Marker positions, actual and desired, and how the desired ones move
+    /// per observation
+    n: [f64; 5],
+    want: [f64; 5],
+    step: [f64; 5],
+}
+
+impl P2 {
+    fn new(q: f64) -> Self {
+        P2 {
+            count: 0,
+            h: [0.0; 5],
+            n: [1.0, 2.0, 3.0, 4.0, 5.0],
+            want: [1.0, 1.0 + 2.0 * q, 1.0 + 4.0 * q, 3.0 + 2.0 * q, 5.0],
+            step: [0.0, q / 2.0, q, (1.0 + q) / 2.0, 1.0],
+        }
+    }
+
+    fn push(&mut self, x: f64) {
+        if self.count < 5 {
+            self.h[self.count] = x;
+            self.count += 1;
+            if self.count == 5 {
+                self.h.sort_by(f64::total_cmp);
+            }
+            return;
+        }
+        self.count += 1;
+
+        let k = if x < self.h[0] {
+            self.h[0] = x;
+            0
+        } else if x >= self.h[4] {
+            self.h[4] = x;
+            3
+        } else {
+            (1..5).find(|&i| x < self.h[i]).unwrap() - 1
+        };
+        for n in self.n.iter_mut().skip(k + 1) {
+            *n += 1.0;
+        }
+        for (want, step) in self.want.iter_mut().zip(self.step) {
+            *want += step;
+        }
+
+        for i in 1..4 {
+            let d = self.want[i] - self.n[i];
+            if (d >= 1.0 && self.n[i + 1] - self.n[i] > 1.0)
+                || (d <= -1.0 && self.n[i - 1] - self.n[i] < -1.0)
+            {
+                let s = d.signum();
+                let (n, h) = (&self.n, &self.h);
+                let parabolic = h[i]
+                    + s / (n[i + 1] - n[i - 1])
+                        * ((n[i] - n[i - 1] + s) * (h[i + 1] - h[i]) / (n[i + 1] - n[i])
+                            + (n[i + 1] - n[i] - s) * (h[i] - h[i - 1]) / (n[i] - n[i - 1]));
+                self.h[i] = if h[i - 1] < parabolic && parabolic < h[i + 1] {
+                    parabolic
+                } else {
+                    let j = if s > 0.0 { i + 1 } else { i - 1 };
+                    h[i] + s * (h[j] - h[i]) / (n[j] - n[i])
+                };
+                self.n[i] += s;
+            }
+        }
+    }
+
+    /// With fewer than five observations, the nearest one by rank.
+    fn value(&self, q: f64) -> f64 {
+        if self.count >= 5 {
+            return self.h[2];
+        }
+        let mut seen = self.h;
+        let seen = &mut seen[..self.count];
+        seen.sort_by(f64::total_cmp);
+        seen[(q * (self.count - 1) as f64).round() as usize]
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ChannelAcc {
+    summary: ChannelSummary,
+    quantiles: [P2; 3],
+    /// (timestamp, raw value) of the latest record
+    last: Option<(u32, i32)>,
+}
+
+/// Running statistics for one session, in memory proportional to the
+/// number of channels, not records.
+#[derive(Clone, Debug)]
+pub struct Summarizer {
+    start_ms: Option<u32>,
+    end_ms: u32,
+    channels: Vec<ChannelAcc>,
+    above: Vec<(Threshold, u32)>,
+}
+
+impl Summarizer {
+    pub fn new(thresholds: &[Threshold]) -> Self {
+        Summarizer {
+            start_ms: None,
+            end_ms: 0,
+            channels: Vec::new(),
+            above: thresholds.iter().map(|&t| (t, 0)).collect(),
+        }
+    }
+
+    /// Event and sync point records count toward the session's span but
+    /// aren't channels.
+    pub fn push(&mut self, r: &Record) {
+        self.start_ms.get_or_insert(r.timestamp);
+        self.end_ms = self.end_ms.max(r.timestamp);
+        if is_event(r.channel) || is_time_sync(r.channel) {
+            return;
+        }
+
+        let idx = match self
+            .channels
+            .iter()
+            .position(|c| c.summary.channel == r.channel)
+        {
+            Some(idx) => idx,
+            // no room to store it, so don't spend memory on it
+            None if self.channels.len() == MAX_SUMMARY_ITEMS => return,
+            None => {
+                self.channels.push(ChannelAcc {
+                    summary: ChannelSummary {
+                        channel: r.channel,
+                        count: 0,
+                        min: i32::MAX,
+                        max: i32::MIN,
+                        sum: 0,
+                        percentiles: [0; 3],
+                    },
+                    quantiles: QUANTILES.map(P2::new),
+                    last: None,
+                });
+                self.channels.len() - 1
+            }
+        };
+        let acc = &mut self.channels[idx];
+        if let Some(last) = acc.last {
+            Self::credit_above(&mut self.above, r.channel, last, r.timestamp);
+        }
+        acc.last = Some((r.timestamp, r.value));
+        let s = &mut acc.summary;
+        s.count += 1;
+        s.min = s.min.min(r.value);
+        s.max = s.max.max(r.value);
+        s.sum += r.value as i64;
+        for q in acc.quantiles.iter_mut() {
+            q.push(r.value as f64);
+        }
+    }
+
+    /// A channel holds its value until its next record, so the time since
+    /// the last one counts as above if that value was.
+    fn credit_above(
+        above: &mut [(Threshold, u32)],
+        channel: ChannelId,
+        last: (u32, i32),
+        now: u32,
+    ) {
+        for (t, ms) in above.iter_mut() {
+            if t.channel == channel && last.1 > t.above {
+                *ms += now.saturating_sub(last.0);
+            }
+        }
+    }
+
+    /// Whether anything has been pushed.
+    pub fn is_empty(&self) -> bool {
+        self.start_ms.is_none()
+    }
+
+    /// The summary so far, with the session ending at `end_ms` or its
+    /// latest record, whichever is later.
+    pub fn finish(&self, end_ms: u32) -> SessionSummary {
+        let end_ms = end_ms.max(self.end_ms);
+        let mut above = self.above.clone();
+        let mut channels = Vec::new();
+        for acc in &self.channels {
+            if let Some(last) = acc.last {
+                Self::credit_above(&mut above, acc.summary.channel, last, end_ms);
+            }
+            let mut s = acc.summary;
+            for ((p, q), quantile) in s.percentiles.iter_mut().zip(&acc.quantiles).zip(QUANTILES) {
+                *p = q.value(quantile).round() as i32;
+            }
+            channels.push(s);
+        }
+        SessionSummary {
+            start_ms: self.start_ms.unwrap_or(end_ms),
+            end_ms,
+            files: None,
+            channels,
+            above,
+        }
+    }
+}
+
+fn ignition(r: &Record) -> Option<bool> {
+    (r.channel == IGNITION).then_some(r.value != 0)
+}
+
+/// Split records (in logged order) into sessions. A session starts at
+/// ignition on, or at the first record after a reboot (time going
+/// backwards), and ends after ignition off. Records between ignition off
+/// and the next on belong to no session.
+pub fn split_sessions(records: &[Record]) -> Vec<Range<usize>> {
+    let mut sessions = Vec::new();
+    let mut open: Option<usize> = None;
+    let mut prev_time = None;
+    for (i, r) in records.iter().enumerate() {
+        let rebooted = prev_time.is_some_and(|t| r.timestamp < t);
+        prev_time = Some(r.timestamp);
+        if let Some(start) = open
+            && (rebooted || ignition(r) == Some(true))
+        {
+            sessions.push(start..i);
+            open = None;
+        }
+        let parked = !rebooted && i > 0 && open.is_none() && ignition(r) != Some(true);
+        if open.is_none() && !parked {
+            open = Some(i);
+        }
+        if ignition(r) == Some(false)
+            && let Some(start) = open.take()
+        {
+            sessions.push(start..i + 1);
+        }
+    }
+    if let Some(start) = open {
+        sessions.push(start..records.len());
+    }
+    sessions
+}
+
+/// Summaries of every session in `records`, worked out from scratch.
+pub fn summarize_sessions(records: &[Record], thresholds: &[Threshold]) -> Vec<SessionSummary> {
+    split_sessions(records)
+        .into_iter()
+        .map(|range| {
+            let mut s = Summarizer::new(thresholds);
+            for r in &records[range] {
+                s.push(r);
+            }
+            s.finish(0)
+        })
+        .collect()
+}
+
+/// A `RotatingLog` that also keeps session statistics as records go by.
+/// Each session starts a fresh log file, and its summary is appended to
+/// `SESSIONS_FILE` at ignition off. Sessions are bounded as in
+/// `split_sessions`.
+pub struct SessionLog<F: LogFs> {
+    log: RotatingLog<F>,
+    thresholds: Vec<Threshold>,
+    /// Statistics and first log file of the session under way
+    current: Option<(Summarizer, Option<u32>)>,
+    /// Ignition is off; records are logged but belong to no session.
+    parked: bool,
+}
+
+impl<F: LogFs> SessionLog<F> {
+    pub fn open(
+        fs: F,
+        policy: RotationPolicy,
+        thresholds: &[Threshold],
+    ) -> Result<Self, StorageError<F::Error>> {
+        Ok(SessionLog {
+            log: RotatingLog::open(fs, policy)?,
+            thresholds: thresholds.to_vec(),
+            current: None,
+            parked: false,
+        })
+    }
+
+    pub fn write(&mut self, records: &[Record]) -> Result<(), StorageError<F::Error>> {
+        // records[run..] are yet to be handed to the log
+        let mut run = 0;
+        for (i, r) in records.iter().enumerate() {
+            if ignition(r) == Some(true) {
+                self.flush(&records[run..i])?;
+                run = i;
+                self.close(r.timestamp)?;
+                self.parked = false;
+            }
+            if self.current.is_none() && !self.parked {
+                self.flush(&records[run..i])?;
+                run = i;
+                self.log.rotate();
+                self.current = Some((Summarizer::new(&self.thresholds), None));
+            }
+            if let Some((summarizer, _)) = self.current.as_mut() {
+                summarizer.push(r);
+            }
+            if ignition(r) == Some(false) {
+                self.flush(&records[run..=i])?;
+                run = i + 1;
+                self.close(r.timestamp)?;
+                self.parked = true;
+            }
+        }
+        self.flush(&records[run..])
+    }
+
+    fn flush(&mut self, records: &[Record]) -> Result<(), StorageError<F::Error>> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.log.write(records)?;
+        if let Some((_, first @ None)) = self.current.as_mut() {
+            *first = self.log.current_file();
+        }
+        Ok(())
+    }
+
+    fn files(&self, first: Option<u32>) -> Option<(u32, u32)> {
+        Some((first?, self.log.current_file()?))
+    }
+
+    /// End the session under way, e.g. on a clean shutdown without an
+    /// ignition-off record.
+    pub fn close(&mut self, now: u32) -> Result<(), StorageError<F::Error>> {
+        let Some((summarizer, first)) = self.current.take() else {
+            return Ok(());
+        };
+        let mut summary = summarizer.finish(now);
+        summary.files = self.files(first);
+        self.log
+            .fs_mut()
+            .append(SESSIONS_FILE, &summary.encode())
+            .map_err(StorageError::Fs)
+    }
+
+    /// The session under way, so far.
+    pub fn current(&self) -> Option<SessionSummary> {
+        self.current.as_ref().map(|(s, first)| {
+            let mut summary = s.finish(0);
+            summary.files = self.files(*first);
+            summary
+        })
+    }
+
+    /// Summaries of finished sessions, oldest first.
+    pub fn summaries(&mut self) -> Result<Vec<SessionSummary>, StorageError<F::Error>> {
+        read_summaries(self.log.fs_mut())
+    }
+
+    /// Downsample and prune older rides; see `retention`. Best run at
+    /// ignition off, while nothing else is being logged.
+    pub fn apply_retention(
+        &mut self,
+        policy: &RetentionPolicy,
+    ) -> Result<RetentionReport, StorageError<F::Error>> {
+        apply_retention(&mut self.log, policy)
+    }
+
+    pub fn log(&mut self) -> &mut RotatingLog<F> {
+        &mut self.log
+    }
+
+    pub fn into_inner(self) -> F {
+        self.log.into_inner()
+    }
+}
+
+/// Summaries in `fs`'s sessions file, if there is one.
+pub fn read_summaries<F: LogFs>(fs: &mut F) -> Result<Vec<SessionSummary>, StorageError<F::Error>> {
+    if !fs
+        .list()
+        .map_err(StorageError::Fs)?
+        .iter()
+        .any(|n| n == SESSIONS_FILE)
+    {
+        return Ok(Vec::new());
+    }
+    Ok(decode_summaries(
+        &fs.read(SESSIONS_FILE).map_err(StorageError::Fs)?,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::{ChannelRegistry, REGULATOR_TEMP_C, RPM};
+    use crate::memfs::MemFs;
+
+    fn ign(t: u32, on: bool) -> Record {
+        Record::new(t, IGNITION, on as i32)
+    }
+
+    #[test]
+    fn splits_on_ignition_and_reboot() {
+        let rpm = |t| Record::new(t, RPM, 1000);
+        let records = [
+            rpm(0),         // boot without an ignition record: implicit start
+            ign(10, false), // ends session 0
+            rpm(20),        // parked
+            ign(30, true),
+            rpm(40),
+            rpm(5), // reboot
+            ign(6, false),
+        ];
+        assert_eq!(split_sessions(&records), [0..2, 3..5, 5..7]);
+    }
+
+    #[test]
+    fn percentiles_and_time_above() {
+        let reg = ChannelRegistry::standard();
+        let hot = Threshold::physical(reg.get(REGULATOR_TEMP_C).unwrap(), 80.0);
+        let mut s = Summarizer::new(&[hot]);
+        // regulator at 1 °C steps from 0 to 100 °C, one per second
+        for i in 0..=100 {
+            s.push(&Record::new(i * 1000, REGULATOR_TEMP_C, i as i32 * 100));
+        }
+        let summary = s.finish(110_000);
+        let c = summary.channel(REGULATOR_TEMP_C).unwrap();
+        assert_eq!((c.count, c.min, c.max), (101, 0, 10_000));
+        assert_eq!(c.mean(), 5000.0);
+        for (p, q) in c.percentiles.iter().zip(QUANTILES) {
+            assert!((*p as f64 - q * 10_000.0).abs() < 300.0, "{p} vs {q}");
+        }
+        // above 80 °C from 81 s on, held through to the end at 110 s
+        assert_eq!(summary.time_above(hot), Some(29_000));
+        assert_eq!(summary.duration_ms(), 110_000);
+    }
+
+    #[test]
+    fn session_log_writes_cheap_summaries() {
+        let hot = Threshold {
+            channel: REGULATOR_TEMP_C,
+            above: 8000,
+        };
+        let mut log =
+            SessionLog::open(MemFs::new(8192), RotationPolicy::default(), &[hot]).unwrap();
+        let mut records = Vec::new();
+        for ride in 0..2u32 {
+            let t0 = ride * 100_000;
+            records.push(ign(t0, true));
+            for i in 0..10 {
+                records.push(Record::new(
+                    t0 + i * 1000,
+                    REGULATOR_TEMP_C,
+                    7500 + i as i32 * 100,
+                ));
+            }
+            records.push(ign(t0 + 10_000, false));
+        }
+        log.write(&records).unwrap();
+        assert!(log.current().is_none());
+
+        let summaries = log.summaries().unwrap();
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[1].start_ms, 100_000);
+        assert_eq!(summaries[1].duration_ms(), 10_000);
+        assert_eq!(summaries[0].time_above(hot), Some(4_000));
+        assert_eq!(summaries[0].files, Some((0, 0)));
+        assert_eq!(summaries[1].files, Some((1, 1)));
+        assert_eq!(
+            summaries,
+            summarize_sessions(&log.log().read_all().unwrap(), &[hot])
+                .into_iter()
+                .zip([(0, 0), (1, 1)])
+                .map(|(mut s, files)| {
+                    s.files = Some(files);
+                    s
+                })
+                .collect::<Vec<_>>()
+        );
+
+        // a summary torn at the end of the file is dropped
+        let mut bytes = summaries[0].encode();
+        bytes.extend_from_slice(&summaries[1].encode()[..7]);
+        assert_eq!(decode_summaries(&bytes), summaries[..1]);
+    }
+
+    #[test]
+    fn summaries_keep_what_they_have_room_for() {
+        let hot = Threshold {
+            channel: REGULATOR_TEMP_C,
+            above: 8000,
+        };
+        let mut summarizer = Summarizer::new(&[hot; 300]);
+        for id in 0..300 {
+            summarizer.push(&Record::new(id, ChannelId(id as u16 + 100), 1));
+        }
+        let summary = summarizer.finish(1000);
+        assert_eq!(summary.channels.len(), MAX_SUMMARY_ITEMS);
+        assert_eq!(summary.end_ms, 1000);
+
+        let (back, _) = SessionSummary::decode(&summary.encode()).unwrap();
+        assert_eq!(back.channels, summary.channels);
+        assert_eq!(back.above.len(), MAX_SUMMARY_ITEMS);
+    }
+}
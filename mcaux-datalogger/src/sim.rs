@@ -0,0 +1,372 @@
+// mcaux-datalogger/src/sim.rs
+
+//! Made-up but plausible rides, for tests, benchmarks and plots when there's
+//! no bike to hand.
+//!
+//! The electrical model is the one that motivated the logger: a stator
+//! feeding a shunt regulator, which turns whatever the bike's electrics and
+//! battery don't take into heat. With the aftermarket 280 W stator that's a
+//! lot of heat at cruising RPM. Runs are repeatable for a given seed.
+
+use crate::ChannelRegistry;
+use crate::channel::{
+    AMBIENT_TEMP_C, BATTERY_V, CHARGE_STATE, IGNITION, REGULATOR_TEMP_C, RPM, Scheduler,
+};
+use crate::charging::{ChargeClassifier, ChargeLimits};
+use crate::record::{ChannelId, Record, RecordSink};
+use crate::time::{Clock, TimeSource};
+
+/// Simulation step, ms: as fast as the fastest standard channel.
+pub const STEP_MS: u32 = 100;
+
+/// When, after boot, a simulated GPS gets its first fix.
+pub const GPS_FIX_MS: u32 = 45_000;
+
+/// xorshift, so runs are repeatable without pulling in a crate
+#[derive(Clone, Debug)]
+struct Noise(u32);
+
+impl Noise {
+    /// -0.5..0.5
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f32 / u32::MAX as f32 - 0.5
+    }
+}
+
+/// The kind of riding being done.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Leg {
+    /// Warming up or stuck in traffic, at idle
+    Idle,
+    /// Stop and go
+    Town,
+    Highway,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RideConfig {
+    pub seed: u32,
+    /// Stator output at `full_output_rpm` and above, W. It rises roughly
+    /// in proportion to RPM below that.
+    pub stator_w: f32,
+    pub full_output_rpm: f32,
+    /// What ignition, lights, fuel pump and accessories draw, W
+    pub load_w: f32,
+    /// Air temperature at the start, °C
+    pub ambient_c: f32,
+    /// Battery state of charge at the start, 0..1
+    pub charge: f32,
+    /// Legs and how many minutes each lasts, repeated for as long as the
+    /// ride goes on
+    pub route: Vec<(Leg, u32)>,
+    /// UTC (ms since the Unix epoch) at boot. If set, sync points are
+    /// logged from `GPS_FIX_MS` on.
+    pub start_utc_ms: Option<i64>,
+}
+
+impl Default for RideConfig {
+    /// The aftermarket stator: 280 W in the middle RPMs.
+    fn default() -> Self {
+        RideConfig {
+            seed: 0x2545_f491,
+            stator_w: 280.0,
+            full_output_rpm: 5_000.0,
+            load_w: 120.0,
+            ambient_c: 22.0,
+            charge: 0.8,
+            route: vec![(Leg::Idle, 2), (Leg::Town, 6), (Leg::Highway, 4)],
+            start_utc_ms: None,
+        }
+    }
+}
+
+impl RideConfig {
+    /// The stock stator, ~190 W only near redline.
+    pub fn stock() -> Self {
+        RideConfig {
+            stator_w: 190.0,
+            full_output_rpm: 8_000.0,
+            ..Default::default()
+        }
+    }
+}
+
+/// A bike being ridden, advanced one step at a time.
+#[derive(Clone, Debug)]
+pub struct RideSim {
+    config: RideConfig,
+    noise: Noise,
+    now: u32,
+    rpm: f32,
+    /// Crankcase, which warms the regulator mounted near it
+    engine_c: f32,
+    regulator_c: f32,
+    ambient_c: f32,
+    battery_v: f32,
+    charge: f32,
+    /// What the charging monitor makes of `battery_v`
+    charging: ChargeClassifier,
+    /// Sensor noise for the current step
+    jitter: f32,
+}
+
+impl RideSim {
+    /// Panics if the route is empty or has no minutes in it.
+    pub fn new(config: RideConfig) -> Self {
+        if config.route.iter().all(|&(_, minutes)| minutes == 0) {
+            panic!("A simulated ride needs a route");
+        }
+        let ocv = open_circuit_v(config.charge);
+        RideSim {
+            noise: Noise(config.seed.max(1)),
+            now: 0,
+            rpm: 0.0,
+            engine_c: config.ambient_c,
+            regulator_c: config.ambient_c,
+            ambient_c: config.ambient_c,
+            battery_v: ocv,
+            charge: config.charge,
+            charging: ChargeClassifier::new(ChargeLimits::default()),
+            jitter: 0.0,
+            config,
+        }
+    }
+
+    /// ms since boot
+    pub fn now(&self) -> u32 {
+        self.now
+    }
+
+    pub fn leg(&self) -> Leg {
+        self.leg_at(self.now).0
+    }
+
+    /// The leg at `t` and how far into it we are, ms.
+    fn leg_at(&self, t: u32) -> (Leg, u32) {
+        let lap: u32 = self.config.route.iter().map(|&(_, m)| m * 60_000).sum();
+        let mut into = t % lap;
+        for &(leg, minutes) in &self.config.route {
+            if into < minutes * 60_000 {
+                return (leg, into);
+            }
+            into -= minutes * 60_000;
+        }
+        unreachable!()
+    }
+
+    fn target_rpm(&self) -> f32 {
+        let (leg, into) = self.leg_at(self.now);
+        let s = into as f32 / 1000.0;
+        match leg {
+            Leg::Idle => 1_200.0,
+            // pull away, run up through the gears, brake, wait at the lights
+            Leg::Town => match into % 40_000 {
+                0..=7_999 => 1_200.0,
+                t @ 8_000..=29_999 => 2_500.0 + 2_000.0 * ((t % 5_500) as f32 / 5_500.0),
+                _ => 1_800.0,
+            },
+            Leg::Highway => 5_500.0 + 400.0 * (s / 50.0).sin(),
+        }
+    }
+
+    /// What the stator could deliver at the current RPM, W.
+    pub fn stator_w(&self) -> f32 {
+        self.config.stator_w * (self.rpm / self.config.full_output_rpm).clamp(0.0, 1.0)
+    }
+
+    /// Advance the ride by `dt_ms`.
+    pub fn step(&mut self, dt_ms: u32) {
+        let dt = dt_ms as f32 / 1000.0;
+        let lag = |tau_s: f32| (dt / tau_s).min(1.0);
+        self.now += dt_ms;
+        self.jitter = self.noise.next();
+
+        let target = self.target_rpm();
+        self.rpm += (target - self.rpm) * lag(0.7) + 40.0 * self.noise.next();
+        self.rpm = self.rpm.max(0.0);
+
+        // The battery soaks up some surplus while it isn't full; the shunt
+        // regulator burns off the rest. Short of the load, the battery
+        // makes up the difference.
+        let available = self.stator_w();
+        let surplus = available - self.config.load_w;
+        let ocv = open_circuit_v(self.charge);
+        let (charging_w, target_v) = if surplus >= 0.0 {
+            let accepted = surplus.min(60.0 * (1.0 - self.charge).min(0.2) / 0.2);
+            (accepted, (14.4f32).min(ocv + 0.9 + 0.6 * surplus / 100.0))
+        } else {
+            // ~20 mΩ internal resistance
+            (surplus, ocv + surplus / ocv * 0.02)
+        };
+        self.charge = (self.charge + charging_w * dt / (100.0 * 3_600.0)).clamp(0.0, 1.0);
+        self.battery_v += (target_v - self.battery_v) * lag(2.0);
+        let shunted = (surplus - charging_w).max(0.0);
+        let heat_w = 0.5 * shunted + 0.05 * available.min(self.config.load_w);
+
+        // Airflow grows with road speed; there's none at idle.
+        let airflow = match self.leg() {
+            Leg::Idle => 0.0,
+            _ => self.rpm / 3_000.0,
+        };
+        self.engine_c += (90.0 - self.engine_c) * lag(600.0);
+        let surroundings = self.ambient_c + 0.25 * (self.engine_c - self.ambient_c);
+        let rise = heat_w * 1.5 / (1.0 + airflow);
+        self.regulator_c += (surroundings + rise - self.regulator_c) * lag(240.0);
+
+        self.ambient_c += (self.config.ambient_c - self.ambient_c) * lag(3_600.0)
+            + 0.01 * self.noise.next() * dt.sqrt();
+        self.charging.update(self.now, self.battery_v, self.rpm);
+    }
+
+    /// What a sensor on a standard channel would read now, in physical
+    /// units; None for other channels.
+    pub fn read(&self, id: ChannelId) -> Option<f32> {
+        Some(match id {
+            RPM => self.rpm,
+            REGULATOR_TEMP_C => self.regulator_c + 0.05 * self.jitter,
+            AMBIENT_TEMP_C => self.ambient_c,
+            BATTERY_V => self.battery_v + 0.02 * self.jitter,
+            IGNITION => 1.0,
+            CHARGE_STATE => self.charging.state() as i32 as f32,
+            _ => return None,
+        })
+    }
+
+    /// Ride for `duration_ms`, sampling each channel as `scheduler` asks
+    /// and passing what's worth logging to `sink`, then switch the
+    /// ignition off. Sync points are logged too once the GPS has a fix.
+    pub fn run<S: RecordSink>(
+        &mut self,
+        duration_ms: u32,
+        scheduler: &mut Scheduler,
+        sink: &mut S,
+    ) {
+        let end = self.now + duration_ms;
+        let mut clock = Clock::new(10 * 60_000);
+        scheduler.offer_to(sink, self.now, IGNITION, 1.0);
+        while self.now < end {
+            if let Some(boot_utc) = self.config.start_utc_ms
+                && self.now >= GPS_FIX_MS
+                && let Some(r) =
+                    clock.observe(self.now, boot_utc + self.now as i64, TimeSource::Gps)
+            {
+                sink.push_record(r);
+            }
+            let due: Vec<ChannelId> = scheduler.due(self.now).collect();
+            for id in due {
+                let Some(physical) = self.read(id) else {
+                    continue;
+                };
+                // ignition is logged once, at the start: heartbeats would
+                // read as new sessions
+                if id == IGNITION {
+                    scheduler.offer(self.now, id, physical);
+                } else {
+                    scheduler.offer_to(sink, self.now, id, physical);
+                }
+            }
+            let next = scheduler.next_due().unwrap_or(end).clamp(self.now + 1, end);
+            while self.now < next {
+                self.step(STEP_MS.min(next - self.now));
+            }
+        }
+        scheduler.offer_to(sink, self.now, IGNITION, 0.0);
+    }
+}
+
+/// Resting voltage of a lead-acid battery at a state of charge.
+fn open_circuit_v(charge: f32) -> f32 {
+    11.9 + 0.9 * charge
+}
+
+/// One ride of `duration_ms` through the standard channels and deadbands,
+/// as the bike would log it.
+pub fn ride(config: RideConfig, duration_ms: u32) -> Vec<Record> {
+    let mut scheduler = Scheduler::new(ChannelRegistry::standard());
+    let mut records = Vec::new();
+    RideSim::new(config).run(duration_ms, &mut scheduler, &mut records);
+    records
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::session::{Summarizer, Threshold, split_sessions};
+    use crate::time::TimeBase;
+
+    const HOUR_MS: u32 = 60 * 60 * 1000;
+
+    fn peak(records: &[Record], id: ChannelId) -> f32 {
+        let channel = *ChannelRegistry::standard().get(id).unwrap();
+        records
+            .iter()
+            .filter(|r| r.channel == id)
+            .map(|r| channel.to_physical(r.value))
+            .fold(f32::MIN, f32::max)
+    }
+
+    #[test]
+    fn repeatable_and_one_session() {
+        let records = ride(RideConfig::default(), HOUR_MS);
+        assert_eq!(records, ride(RideConfig::default(), HOUR_MS));
+        let reseeded = RideConfig {
+            seed: 7,
+            ..Default::default()
+        };
+        assert_ne!(records, ride(reseeded, HOUR_MS));
+
+        let sessions = split_sessions(&records);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0], 0..records.len());
+        let last = records.last().unwrap();
+        assert_eq!((last.channel, last.value), (IGNITION, 0));
+        assert!(records.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+    }
+
+    #[test]
+    fn big_stator_runs_the_regulator_hotter() {
+        let big = ride(RideConfig::default(), HOUR_MS);
+        let stock = ride(RideConfig::stock(), HOUR_MS);
+        let (big_peak, stock_peak) = (peak(&big, REGULATOR_TEMP_C), peak(&stock, REGULATOR_TEMP_C));
+        assert!(big_peak > stock_peak + 10.0, "{big_peak} vs {stock_peak}");
+        assert!((40.0..110.0).contains(&big_peak), "{big_peak}");
+
+        // the battery charges while riding, and never cooks
+        for records in [&big, &stock] {
+            let v = peak(records, BATTERY_V);
+            assert!((13.0..=14.5).contains(&v), "{v}");
+        }
+        assert!((5_000.0..6_500.0).contains(&peak(&big, RPM)));
+
+        // and the regulator spends longer above 50 °C
+        let hot = [Threshold {
+            channel: REGULATOR_TEMP_C,
+            above: 5_000,
+        }];
+        let minutes_hot = |records: &[Record]| {
+            let mut summarizer = Summarizer::new(&hot);
+            for r in records {
+                summarizer.push(r);
+            }
+            summarizer.finish(0).above[0].1 / 60_000
+        };
+        assert!(minutes_hot(&big) > minutes_hot(&stock) + 10);
+    }
+
+    #[test]
+    fn gps_fix_places_the_ride() {
+        let boot = 1_792_398_600_000;
+        let config = RideConfig {
+            start_utc_ms: Some(boot),
+            ..Default::default()
+        };
+        let records = ride(config, 30 * 60_000);
+        let base = TimeBase::from_records(&records);
+        assert!(base.is_synced());
+        assert_eq!(base.to_utc(0), Some(boot));
+        assert_eq!(base.to_utc(20 * 60_000), Some(boot + 20 * 60_000));
+    }
+}
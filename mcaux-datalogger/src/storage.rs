@@ -0,0 +1,399 @@
+// mcaux-datalogger/src/storage.rs
+
+use std::fmt;
+
+use crate::event::parse_capture_file_name;
+use crate::query::TimeIndex;
+use crate::record::{
+    DecodeError, FRAME_OVERHEAD, MAX_FRAME_RECORDS, RECORD_LEN, Record, Records, encode_frame,
+    encode_header,
+};
+use crate::retention::remove_unfinished;
+use crate::session::SESSIONS_FILE;
+
+/// The handful of filesystem operations the log needs. littlefs provides
+/// them on the bike; `MemFs` provides them in tests.
+pub trait LogFs {
+    type Error: fmt::Debug;
+
+    /// Append to a file, creating it if need be. If power is lost partway,
+    /// a prefix of `data` may have landed.
+    fn append(&mut self, name: &str, data: &[u8]) -> Result<(), Self::Error>;
+
+    fn read(&mut self, name: &str) -> Result<Vec<u8>, Self::Error>;
+
+    fn remove(&mut self, name: &str) -> Result<(), Self::Error>;
+
+    /// Names of all files present.
+    fn list(&mut self) -> Result<Vec<String>, Self::Error>;
+
+    /// Bytes that can still be written.
+    fn free_space(&mut self) -> Result<usize, Self::Error>;
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StorageError<E> {
+    Fs(E),
+    Decode(DecodeError),
+}
+
+impl<E: fmt::Debug> fmt::Display for StorageError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Fs(e) => write!(f, "filesystem error: {e:?}"),
+            StorageError::Decode(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl<E: fmt::Debug> std::error::Error for StorageError<E> {}
+
+impl<E> From<DecodeError> for StorageError<E> {
+    fn from(e: DecodeError) -> Self {
+        StorageError::Decode(e)
+    }
+}
+
+/// When to close the current log file and start the next.
+#[derive(Clone, Copy, Debug)]
+pub struct RotationPolicy {
+    /// Start a new file before this size would be exceeded.
+    pub max_file_bytes: usize,
+    /// Start a new file once the current one spans this much time.
+    pub max_file_age_ms: u32,
+    /// Delete the oldest files beyond this many.
+    pub max_files: usize,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        RotationPolicy {
+            max_file_bytes: 16 * 1024,
+            max_file_age_ms: 10 * 60 * 1000,
+            max_files: 64,
+        }
+    }
+}
+
+/// One log file as found on storage.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogFile {
+    pub seq: u32,
+    pub name: String,
+}
+
+/// Log file names carry a sequence number, so sorting by it is oldest first.
+pub fn log_file_name(seq: u32) -> String {
+    format!("{seq:08}.mcl")
+}
+
+/// A log file once retention has downsampled it; see `retention`.
+pub fn rollup_file_name(seq: u32) -> String {
+    format!("{seq:08}.mcr")
+}
+
+/// The sequence number of a log file, full rate or downsampled.
+pub fn parse_log_file_name(name: &str) -> Option<u32> {
+    let digits = name
+        .strip_suffix(".mcl")
+        .or_else(|| name.strip_suffix(".mcr"))?;
+    if digits.len() != 8 {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+#[derive(Clone, Copy, Debug)]
+struct CurrentFile {
+    seq: u32,
+    bytes: usize,
+    opened_at: u32,
+}
+
+/// Records appended to a series of files, rotated by size and age.
+///
+/// We never append to a file left over from before `open`. Readers would
+/// step over a frame torn by power loss, but the tick counter restarts at
+/// every boot, and a file's time span only means something if its records
+/// share one clock. So every boot starts a fresh file.
+pub struct RotatingLog<F: LogFs> {
+    fs: F,
+    policy: RotationPolicy,
+    current: Option<CurrentFile>,
+    next_seq: u32,
+    /// Built by the first query, then kept up to date by writes
+    index: TimeIndex,
+}
+
+impl<F: LogFs> RotatingLog<F> {
+    pub fn open(mut fs: F, policy: RotationPolicy) -> Result<Self, StorageError<F::Error>> {
+        remove_unfinished(&mut fs).map_err(StorageError::Fs)?;
+        let next_seq = fs
+            .list()
+            .map_err(StorageError::Fs)?
+            .iter()
+            .filter_map(|n| parse_log_file_name(n))
+            .max()
+            .map_or(0, |s| s + 1);
+        Ok(RotatingLog {
+            fs,
+            policy,
+            current: None,
+            next_seq,
+            index: TimeIndex::default(),
+        })
+    }
+
+    pub fn policy(&self) -> RotationPolicy {
+        self.policy
+    }
+
+    /// Append records, rotating files as the policy requires. Each file
+    /// gets them in one CRC'd frame, so a write torn by power loss is lost
+    /// whole rather than read back half-done.
+    pub fn write(&mut self, records: &[Record]) -> Result<(), StorageError<F::Error>> {
+        let mut pending: Vec<Record> = Vec::new();
+        for r in records {
+            if pending.len() == MAX_FRAME_RECORDS {
+                self.flush_pending(&mut pending)?;
+            }
+            if self.needs_rotation(r.timestamp, Self::cost(&pending)) {
+                self.flush_pending(&mut pending)?;
+                self.start_file(r.timestamp)?;
+            }
+            let cost = Self::cost(&pending);
+            pending.push(*r);
+            if let Some(cur) = self.current.as_mut() {
+                cur.bytes += cost;
+            }
+        }
+        self.flush_pending(&mut pending)
+    }
+
+    /// Bytes one more record adds to `pending`.
+    fn cost(pending: &[Record]) -> usize {
+        if pending.is_empty() {
+            RECORD_LEN + FRAME_OVERHEAD
+        } else {
+            RECORD_LEN
+        }
+    }
+
+    /// Sequence number of the file being written, if any.
+    pub fn current_file(&self) -> Option<u32> {
+        self.current.map(|c| c.seq)
+    }
+
+    /// Close the current file; the next write starts another.
+    pub fn rotate(&mut self) {
+        self.current = None;
+    }
+
+    fn needs_rotation(&self, timestamp: u32, cost: usize) -> bool {
+        match self.current {
+            None => true,
+            Some(cur) => {
+                cur.bytes + cost > self.policy.max_file_bytes
+                    || timestamp < cur.opened_at
+                    || timestamp - cur.opened_at >= self.policy.max_file_age_ms
+            }
+        }
+    }
+
+    fn flush_pending(&mut self, pending: &mut Vec<Record>) -> Result<(), StorageError<F::Error>> {
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let Some(cur) = self.current else {
+            panic!("Logic trouble: records pending with no file to write them to");
+        };
+        let frame = encode_frame(pending);
+        self.fs
+            .append(&log_file_name(cur.seq), &frame)
+            .map_err(StorageError::Fs)?;
+        self.index.appended(cur.seq, frame.len(), pending);
+        pending.clear();
+        Ok(())
+    }
+
+    fn start_file(&mut self, timestamp: u32) -> Result<(), StorageError<F::Error>> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.current = None;
+        self.fs
+            .append(&log_file_name(seq), &encode_header())
+            .map_err(StorageError::Fs)?;
+        self.current = Some(CurrentFile {
+            seq,
+            bytes: encode_header().len(),
+            opened_at: timestamp,
+        });
+        self.enforce_max_files()
+    }
+
+    fn enforce_max_files(&mut self) -> Result<(), StorageError<F::Error>> {
+        let files = self.files()?;
+        let excess = files.len().saturating_sub(self.policy.max_files.max(1));
+        for f in &files[..excess] {
+            self.fs.remove(&f.name).map_err(StorageError::Fs)?;
+        }
+        Ok(())
+    }
+
+    /// Log files present, oldest first.
+    pub fn files(&mut self) -> Result<Vec<LogFile>, StorageError<F::Error>> {
+        let mut files: Vec<LogFile> = self
+            .fs
+            .list()
+            .map_err(StorageError::Fs)?
+            .into_iter()
+            .filter_map(|name| parse_log_file_name(&name).map(|seq| LogFile { seq, name }))
+            .collect();
+        files.sort_by_key(|f| f.seq);
+        Ok(files)
+    }
+
+    /// Raw bytes of one log file.
+    pub fn read_file(&mut self, file: &LogFile) -> Result<Vec<u8>, StorageError<F::Error>> {
+        self.fs.read(&file.name).map_err(StorageError::Fs)
+    }
+
+    /// Every record in every file, oldest first.
+    pub fn read_all(&mut self) -> Result<Vec<Record>, StorageError<F::Error>> {
+        let mut out = Vec::new();
+        for f in self.files()? {
+            let bytes = self.read_file(&f)?;
+            out.extend(Records::new(&bytes)?);
+        }
+        Ok(out)
+    }
+
+    /// Remove every log file, and with them the capture windows and
+    /// session summaries taken from their records.
+    pub fn erase(&mut self) -> Result<(), StorageError<F::Error>> {
+        for name in self.fs.list().map_err(StorageError::Fs)? {
+            if parse_log_file_name(&name).is_some()
+                || parse_capture_file_name(&name).is_some()
+                || name == SESSIONS_FILE
+            {
+                self.fs.remove(&name).map_err(StorageError::Fs)?;
+            }
+        }
+        self.current = None;
+        Ok(())
+    }
+
+    pub fn free_space(&mut self) -> Result<usize, StorageError<F::Error>> {
+        self.fs.free_space().map_err(StorageError::Fs)
+    }
+
+    pub fn fs_mut(&mut self) -> &mut F {
+        &mut self.fs
+    }
+
+    /// Where in the log files to find which times and channels. Empty
+    /// until the first query.
+    pub fn index(&self) -> &TimeIndex {
+        &self.index
+    }
+
+    pub(crate) fn index_and_fs(&mut self) -> (&mut TimeIndex, &mut F) {
+        (&mut self.index, &mut self.fs)
+    }
+
+    pub fn into_inner(self) -> F {
+        self.fs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memfs::MemFs;
+    use crate::record::{ChannelId, HEADER_LEN};
+
+    fn rec(t: u32) -> Record {
+        Record::new(t, ChannelId(2), t as i32 * 3)
+    }
+
+    #[test]
+    fn names_round_trip() {
+        assert_eq!(log_file_name(42), "00000042.mcl");
+        assert_eq!(parse_log_file_name("00000042.mcl"), Some(42));
+        assert_eq!(parse_log_file_name("00000042.mcr"), Some(42));
+        assert_eq!(parse_log_file_name("42.mcl"), None);
+        assert_eq!(parse_log_file_name("00000042.txt"), None);
+    }
+
+    #[test]
+    fn rotates_by_size_and_age() {
+        let policy = RotationPolicy {
+            max_file_bytes: HEADER_LEN + FRAME_OVERHEAD + 3 * RECORD_LEN,
+            max_file_age_ms: 1000,
+            max_files: 10,
+        };
+        let mut log = RotatingLog::open(MemFs::new(4096), policy).unwrap();
+        log.write(&(0..7).map(rec).collect::<Vec<_>>()).unwrap();
+        assert_eq!(log.files().unwrap().len(), 3);
+        log.write(&[rec(2000)]).unwrap();
+        assert_eq!(log.files().unwrap().len(), 4);
+        let all: Vec<u32> = log
+            .read_all()
+            .unwrap()
+            .iter()
+            .map(|r| r.timestamp)
+            .collect();
+        assert_eq!(all, [0, 1, 2, 3, 4, 5, 6, 2000]);
+    }
+
+    #[test]
+    fn drops_oldest_files_beyond_limit() {
+        let policy = RotationPolicy {
+            max_file_bytes: HEADER_LEN + FRAME_OVERHEAD + RECORD_LEN,
+            max_file_age_ms: u32::MAX,
+            max_files: 2,
+        };
+        let mut log = RotatingLog::open(MemFs::new(4096), policy).unwrap();
+        log.write(&(0..5).map(rec).collect::<Vec<_>>()).unwrap();
+        let seqs: Vec<u32> = log.files().unwrap().iter().map(|f| f.seq).collect();
+        assert_eq!(seqs, [3, 4]);
+    }
+
+    #[test]
+    fn survives_power_loss_mid_write() {
+        let mut log = RotatingLog::open(MemFs::new(4096), RotationPolicy::default()).unwrap();
+        log.write(&[rec(0), rec(1)]).unwrap();
+        // the lights go out partway through a record of the next write,
+        // which is lost whole
+        log.fs_mut()
+            .cut_power_after(FRAME_OVERHEAD + RECORD_LEN + 4);
+        assert!(log.write(&(2..5).map(rec).collect::<Vec<_>>()).is_err());
+
+        // reboot: remount what survived and carry on logging
+        let mut fs = log.into_inner();
+        fs.restore_power();
+        let mut log = RotatingLog::open(fs, RotationPolicy::default()).unwrap();
+        log.write(&[rec(10), rec(11)]).unwrap();
+        let all: Vec<u32> = log
+            .read_all()
+            .unwrap()
+            .iter()
+            .map(|r| r.timestamp)
+            .collect();
+        assert_eq!(all, [0, 1, 10, 11]);
+        assert_eq!(log.files().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn reports_free_space() {
+        let mut log = RotatingLog::open(MemFs::new(1000), RotationPolicy::default()).unwrap();
+        assert_eq!(log.free_space().unwrap(), 1000);
+        log.write(&[rec(1)]).unwrap();
+        assert_eq!(
+            log.free_space().unwrap(),
+            1000 - HEADER_LEN - FRAME_OVERHEAD - RECORD_LEN
+        );
+        log.erase().unwrap();
+        assert_eq!(log.free_space().unwrap(), 1000);
+    }
+}
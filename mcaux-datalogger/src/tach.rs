@@ -0,0 +1,254 @@
+// mcaux-datalogger/src/tach.rs
+
+//! RPM from ignition pulses: a tach wire or a pickup on the coil lead,
+//! timestamped by a timer's input capture.
+//!
+//! Coil leads ring and pick up noise from everything else firing, so
+//! `Tachometer` is choosy about what counts as a pulse. Anything sooner
+//! after the last than the engine could turn is dropped outright, and a
+//! pulse well short of the current period is taken for a glitch. RPM comes
+//! from the median of the last three intervals, so one missed or stray
+//! pulse doesn't move it.
+
+use crate::channel::{RPM, Scheduler};
+use crate::record::RecordSink;
+
+/// RPM times pulse period in µs, at one pulse per revolution
+const US_PER_MINUTE: f32 = 60_000_000.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TachConfig {
+    /// Pulses per crank revolution: 1 for a single that sparks every turn,
+    /// 0.5 for one that sparks every other, 2 for a twin's shared signal.
+    pub pulses_per_rev: f32,
+    /// Pulses closer together than this engine speed are noise.
+    pub max_rpm: f32,
+    /// No pulse for longer than this engine speed allows means stopped.
+    pub min_rpm: f32,
+}
+
+impl TachConfig {
+    /// The KLR650: a single whose CDI sparks once per revolution, redline
+    /// about 7000.
+    pub fn klr650() -> Self {
+        TachConfig {
+            pulses_per_rev: 1.0,
+            max_rpm: 9000.0,
+            min_rpm: 300.0,
+        }
+    }
+
+    fn period_us(&self, rpm: f32) -> u32 {
+        (US_PER_MINUTE / (rpm * self.pulses_per_rev)) as u32
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Tachometer {
+    config: TachConfig,
+    /// Last pulse accepted, µs
+    last: Option<u32>,
+    /// Latest intervals between accepted pulses, newest last
+    intervals: [u32; 3],
+    len: usize,
+    /// Glitches rejected in a row; enough of them and the period must
+    /// really have halved
+    streak: u8,
+    rejected: u32,
+}
+
+impl Tachometer {
+    pub fn new(config: TachConfig) -> Self {
+        if config.pulses_per_rev <= 0.0 || config.min_rpm >= config.max_rpm {
+            panic!("Tachometer needs positive pulses per rev and min_rpm below max_rpm");
+        }
+        Tachometer {
+            config,
+            last: None,
+            intervals: [0; 3],
+            len: 0,
+            streak: 0,
+            rejected: 0,
+        }
+    }
+
+    /// Feed the capture time of each pulse, in µs from a free-running
+    /// timer. Wrapping is fine as long as pulses come oftener than every
+    /// 71 minutes.
+    pub fn pulse(&mut self, t_us: u32) {
+        let Some(last) = self.last else {
+            self.last = Some(t_us);
+            return;
+        };
+        let dt = t_us.wrapping_sub(last);
+        if dt < self.config.period_us(self.config.max_rpm) {
+            self.rejected += 1;
+            return;
+        }
+        if dt > self.config.period_us(self.config.min_rpm) {
+            // it had stopped; this is the first pulse of a restart
+            self.last = Some(t_us);
+            self.len = 0;
+            return;
+        }
+        if let Some(period) = self.period()
+            && dt < period / 2
+            && self.streak < 2
+        {
+            self.rejected += 1;
+            self.streak += 1;
+            return;
+        }
+        self.streak = 0;
+        self.intervals.rotate_left(1);
+        self.intervals[2] = dt;
+        self.len = (self.len + 1).min(3);
+        self.last = Some(t_us);
+    }
+
+    /// Median of the intervals seen so far, up to three.
+    fn period(&self) -> Option<u32> {
+        let mut recent = self.intervals;
+        let recent = &mut recent[3 - self.len..];
+        recent.sort_unstable();
+        match recent.len() {
+            0 => None,
+            2 => Some((recent[0] + recent[1]) / 2),
+            n => Some(recent[n / 2]),
+        }
+    }
+
+    /// Engine speed at `now_us`, on the same timer as the pulses: 0 until
+    /// two pulses have come, and once they stop for longer than `min_rpm`
+    /// allows. While the next pulse is overdue the reading falls, as the
+    /// engine must be turning slower than the last period said.
+    pub fn rpm(&self, now_us: u32) -> f32 {
+        let (Some(last), Some(period)) = (self.last, self.period()) else {
+            return 0.0;
+        };
+        let since = now_us.wrapping_sub(last);
+        if since > self.config.period_us(self.config.min_rpm) {
+            return 0.0;
+        }
+        US_PER_MINUTE / (period.max(since) as f32 * self.config.pulses_per_rev)
+    }
+
+    /// Offer the reading at `now_us` on the `RPM` channel, at `now_ms` on
+    /// the logger's clock, passing it on to `sink` if it's worth logging.
+    pub fn log_to<S: RecordSink>(
+        &self,
+        scheduler: &mut Scheduler,
+        sink: &mut S,
+        now_ms: u32,
+        now_us: u32,
+    ) -> bool {
+        scheduler.offer_to(sink, now_ms, RPM, self.rpm(now_us))
+    }
+
+    /// Pulses dropped as noise so far, to tell a bad pickup from a good
+    /// one.
+    pub fn rejected(&self) -> u32 {
+        self.rejected
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::ChannelRegistry;
+    use crate::record::Record;
+
+    /// Pulse times for an engine following `rpm(t_us)` from `start_us`,
+    /// at `pulses_per_rev`.
+    fn pulse_train(
+        start_us: u32,
+        end_us: u32,
+        pulses_per_rev: f32,
+        rpm: impl Fn(u32) -> f32,
+    ) -> Vec<u32> {
+        let mut t = start_us;
+        let mut pulses = Vec::new();
+        while t < end_us {
+            pulses.push(t);
+            t += (US_PER_MINUTE / (rpm(t) * pulses_per_rev)) as u32;
+        }
+        pulses
+    }
+
+    #[test]
+    fn steady_idle_and_pulses_per_rev() {
+        let mut tach = Tachometer::new(TachConfig::klr650());
+        assert_eq!(tach.rpm(0), 0.0);
+        for t in pulse_train(0, 1_000_000, 1.0, |_| 1300.0) {
+            tach.pulse(t);
+        }
+        assert!((tach.rpm(1_000_000) - 1300.0).abs() < 2.0);
+        let mut scheduler = Scheduler::new(ChannelRegistry::standard());
+        let mut records: Vec<Record> = Vec::new();
+        assert!(tach.log_to(&mut scheduler, &mut records, 1000, 1_000_000));
+        assert_eq!(records, [Record::new(1000, RPM, 1300)]);
+
+        // the same pulses from an engine sparking every other turn
+        let config = TachConfig {
+            pulses_per_rev: 0.5,
+            ..TachConfig::klr650()
+        };
+        let mut tach = Tachometer::new(config);
+        for t in pulse_train(0, 1_000_000, 0.5, |_| 2600.0) {
+            tach.pulse(t);
+        }
+        assert!((tach.rpm(1_000_000) - 2600.0).abs() < 4.0);
+    }
+
+    #[test]
+    fn ringing_glitches_and_missed_pulses_are_filtered() {
+        let mut tach = Tachometer::new(TachConfig::klr650());
+        let pulses = pulse_train(0, 2_000_000, 1.0, |_| 4000.0);
+        for (i, &t) in pulses.iter().enumerate() {
+            // every tenth pulse goes missing
+            if i % 10 == 5 {
+                continue;
+            }
+            tach.pulse(t);
+            // the coil lead rings for a few µs after every spark
+            tach.pulse(t + 40);
+            // and something else's spark lands mid-interval now and then
+            if i % 7 == 3 {
+                tach.pulse(t + 6_000);
+            }
+            if i > 3 {
+                let rpm = tach.rpm(t + 100);
+                assert!((rpm - 4000.0).abs() < 40.0, "{rpm} at pulse {i}");
+            }
+        }
+        assert!(tach.rejected() > pulses.len() as u32);
+    }
+
+    #[test]
+    fn follows_revs_and_drops_to_zero_at_stall() {
+        let mut tach = Tachometer::new(TachConfig::klr650());
+        // 2000 to 6000 rpm over a second, wide open in second gear
+        let revs = |t: u32| 2000.0 + 4000.0 * t as f32 / 1_000_000.0;
+        for t in pulse_train(0, 1_000_000, 1.0, revs) {
+            tach.pulse(t);
+            // a revolution or two behind, the price of the median
+            if t > 50_000 {
+                let rpm = tach.rpm(t);
+                assert!(rpm < revs(t) && rpm > revs(t) - 250.0, "{rpm} at {t}");
+            }
+        }
+
+        // stalled: the reading falls while the next pulse is overdue, then
+        // gives up
+        let last = tach.last.unwrap();
+        assert!(tach.rpm(last + 50_000) < 1300.0);
+        assert_eq!(tach.rpm(last + 500_000), 0.0);
+
+        // restarting needs two pulses again
+        let restart = last + 3_000_000;
+        tach.pulse(restart);
+        assert_eq!(tach.rpm(restart), 0.0);
+        tach.pulse(restart + 50_000);
+        assert!((tach.rpm(restart + 50_000) - 1200.0).abs() < 1.0);
+    }
+}
@@ -0,0 +1,370 @@
+// mcaux-datalogger/src/time.rs
+
+//! Wall-clock time for records stamped with ticks since boot.
+//!
+//! The bike only has a monotonic millisecond counter. When it learns the
+//! real time, from the RTC at boot or from a GPS fix, it logs a sync point:
+//! a record on a `TIME_SYNC_TAG` channel whose timestamp is the tick at
+//! which a UTC second began and whose value is that second (Unix time, as
+//! u32). On the host, the sync points of each boot place every record of
+//! that boot on the calendar, including those logged before the fix.
+
+use std::ops::Range;
+
+use crate::record::{ChannelId, Record};
+
+/// Top four bits of sync point channel ids; the low bits say where the time
+/// came from.
+pub const TIME_SYNC_TAG: u16 = 0xe000;
+
+pub fn is_time_sync(channel: ChannelId) -> bool {
+    channel.0 & 0xf000 == TIME_SYNC_TAG
+}
+
+/// Where a sync point's time came from, worst first: a boot's times are
+/// taken from its best source alone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TimeSource {
+    /// Battery-backed clock chip, set whenever it was last set
+    Rtc,
+    Gps,
+}
+
+impl TimeSource {
+    pub fn name(self) -> &'static str {
+        match self {
+            TimeSource::Rtc => "RTC",
+            TimeSource::Gps => "GPS",
+        }
+    }
+}
+
+/// Tick `tick_ms` was UTC `utc_ms` (ms since the Unix epoch).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SyncPoint {
+    pub tick_ms: u32,
+    pub utc_ms: i64,
+    pub source: TimeSource,
+}
+
+impl SyncPoint {
+    /// As a record: the tick at which the next UTC second begins (this one,
+    /// if it begins at `tick_ms`), valued at that second. That tick may be
+    /// ahead of `tick_ms`, so don't log the record before then; `Clock`
+    /// holds it back for you.
+    ///
+    /// Panics if `utc_ms` is before 1970 or after 2106.
+    pub fn to_record(&self) -> Record {
+        let wait = (1000 - self.utc_ms.rem_euclid(1000)) % 1000;
+        let tick = self
+            .tick_ms
+            .checked_add(wait as u32)
+            .expect("Tick counter wraps before the next UTC second");
+        let second = (self.utc_ms + wait).div_euclid(1000);
+        let second = u32::try_from(second).expect("UTC time out of range for a sync point");
+        let channel = TIME_SYNC_TAG
+            | match self.source {
+                TimeSource::Rtc => 0,
+                TimeSource::Gps => 1,
+            };
+        Record::new(tick, ChannelId(channel), second as i32)
+    }
+
+    /// None if the record isn't a sync point, or is from a newer firmware.
+    pub fn from_record(r: &Record) -> Option<SyncPoint> {
+        if !is_time_sync(r.channel) {
+            return None;
+        }
+        let source = match r.channel.0 & 0x0fff {
+            0 => TimeSource::Rtc,
+            1 => TimeSource::Gps,
+            _ => return None,
+        };
+        Some(SyncPoint {
+            tick_ms: r.timestamp,
+            utc_ms: r.value as u32 as i64 * 1000,
+            source,
+        })
+    }
+}
+
+/// Decides which time readings are worth logging on the bike.
+#[derive(Clone, Debug)]
+pub struct Clock {
+    resync_ms: u32,
+    last: Option<SyncPoint>,
+    /// Logged once a UTC second begins, so time never runs backwards
+    pending: Option<SyncPoint>,
+}
+
+impl Clock {
+    /// Log a fresh sync point at most every `resync_ms` from the same
+    /// source, so the host can correct for the tick counter's drift.
+    pub fn new(resync_ms: u32) -> Self {
+        Clock {
+            resync_ms,
+            last: None,
+            pending: None,
+        }
+    }
+
+    /// A reading of the real time at `tick_ms`, which is worth logging if
+    /// it's the first, from a better source than before, or due. Returns
+    /// the record to log once a UTC second has begun since, as `poll`.
+    pub fn observe(&mut self, tick_ms: u32, utc_ms: i64, source: TimeSource) -> Option<Record> {
+        let point = SyncPoint {
+            tick_ms,
+            utc_ms,
+            source,
+        };
+        let due = match self.last {
+            None => true,
+            Some(last) => {
+                source > last.source
+                    || (source == last.source
+                        && tick_ms.wrapping_sub(last.tick_ms) >= self.resync_ms)
+            }
+        };
+        if due {
+            self.last = Some(point);
+            self.pending = Some(point);
+        }
+        self.poll(tick_ms)
+    }
+
+    /// The sync point waiting to be logged, stamped at the latest UTC second
+    /// to begin by `tick_ms`, or None if there isn't one yet. Call this
+    /// before logging anything else at `tick_ms`.
+    pub fn poll(&mut self, tick_ms: u32) -> Option<Record> {
+        let point = self.pending?;
+        let elapsed = tick_ms.checked_sub(point.tick_ms)?;
+        let utc_ms = point.utc_ms + elapsed as i64;
+        let frac = utc_ms.rem_euclid(1000) as u32;
+        if frac > elapsed {
+            return None;
+        }
+        self.pending = None;
+        let second = SyncPoint {
+            tick_ms: tick_ms - frac,
+            utc_ms: utc_ms - frac as i64,
+            ..point
+        };
+        Some(second.to_record())
+    }
+
+    /// UTC now, if we've been told.
+    pub fn now_utc(&self, tick_ms: u32) -> Option<i64> {
+        self.last
+            .map(|p| p.utc_ms + tick_ms as i64 - p.tick_ms as i64)
+    }
+}
+
+/// Where one boot's ticks fall on the calendar.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TimeBase {
+    /// From the best source logged, by tick
+    points: Vec<SyncPoint>,
+}
+
+impl TimeBase {
+    /// From the sync points among one boot's records.
+    pub fn from_records<'a>(records: impl IntoIterator<Item = &'a Record>) -> TimeBase {
+        let mut points: Vec<SyncPoint> = records
+            .into_iter()
+            .filter_map(SyncPoint::from_record)
+            .collect();
+        if let Some(best) = points.iter().map(|p| p.source).max() {
+            points.retain(|p| p.source == best);
+        }
+        points.sort_by_key(|p| p.tick_ms);
+        TimeBase { points }
+    }
+
+    pub fn is_synced(&self) -> bool {
+        !self.points.is_empty()
+    }
+
+    /// UTC ms at `tick_ms`. Between sync points the tick rate is taken from
+    /// them; before the first and after the last, ticks count as ms.
+    pub fn to_utc(&self, tick_ms: u32) -> Option<i64> {
+        let after = self.points.partition_point(|p| p.tick_ms <= tick_ms);
+        let tick = tick_ms as i64;
+        match (
+            after.checked_sub(1).map(|i| self.points[i]),
+            self.points.get(after).copied(),
+        ) {
+            (Some(a), Some(b)) => {
+                let span = (b.tick_ms - a.tick_ms) as i64;
+                Some(a.utc_ms + (tick - a.tick_ms as i64) * (b.utc_ms - a.utc_ms) / span)
+            }
+            (Some(p), None) | (None, Some(p)) => Some(p.utc_ms + tick - p.tick_ms as i64),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Split records (in logged order) at every reboot, where time goes
+/// backwards.
+pub fn split_boots(records: &[Record]) -> Vec<Range<usize>> {
+    let mut boots = Vec::new();
+    let mut start = 0;
+    for i in 1..records.len() {
+        if records[i].timestamp < records[i - 1].timestamp {
+            boots.push(start..i);
+            start = i;
+        }
+    }
+    if start < records.len() {
+        boots.push(start..records.len());
+    }
+    boots
+}
+
+/// `2026-10-19T08:30:00.250Z`
+pub fn format_utc(utc_ms: i64) -> String {
+    let days = utc_ms.div_euclid(86_400_000);
+    let ms = utc_ms.rem_euclid(86_400_000);
+    // days to civil date, after Howard Hinnant's chrono algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// 2026-10-19T08:30:00Z
+    const MORNING: i64 = 1_792_398_600_000;
+
+    #[test]
+    fn sync_points_round_trip_on_second_boundaries() {
+        let p = SyncPoint {
+            tick_ms: 5_250,
+            utc_ms: MORNING + 400,
+            source: TimeSource::Gps,
+        };
+        let r = p.to_record();
+        assert!(is_time_sync(r.channel));
+        assert_eq!(r.timestamp, 5_850);
+        assert_eq!(
+            SyncPoint::from_record(&r),
+            Some(SyncPoint {
+                tick_ms: 5_850,
+                utc_ms: MORNING + 1000,
+                source: TimeSource::Gps
+            })
+        );
+        let whole = SyncPoint {
+            utc_ms: MORNING,
+            ..p
+        };
+        assert_eq!(whole.to_record().timestamp, 5_250);
+        assert_eq!(format_utc(MORNING + 250), "2026-10-19T08:30:00.250Z");
+        assert_eq!(format_utc(951_782_400_000), "2000-02-29T00:00:00.000Z");
+    }
+
+    #[test]
+    fn clock_logs_first_better_and_due_readings() {
+        let mut clock = Clock::new(60_000);
+        assert!(clock.observe(1_000, MORNING, TimeSource::Rtc).is_some());
+        assert!(
+            clock
+                .observe(2_000, MORNING + 1_000, TimeSource::Rtc)
+                .is_none()
+        );
+        assert!(
+            clock
+                .observe(30_000, MORNING + 29_000, TimeSource::Gps)
+                .is_some()
+        );
+        assert!(
+            clock
+                .observe(31_000, MORNING + 30_000, TimeSource::Rtc)
+                .is_none()
+        );
+        assert!(
+            clock
+                .observe(60_000, MORNING + 59_000, TimeSource::Gps)
+                .is_none()
+        );
+        assert!(
+            clock
+                .observe(90_000, MORNING + 89_000, TimeSource::Gps)
+                .is_some()
+        );
+        assert_eq!(clock.now_utc(90_500), Some(MORNING + 89_500));
+    }
+
+    #[test]
+    fn clock_waits_for_the_second_so_time_runs_forward() {
+        let mut clock = Clock::new(60_000);
+        let mut records = vec![Record::new(5_000, ChannelId(1), 0)];
+        assert_eq!(clock.observe(5_250, MORNING + 400, TimeSource::Gps), None);
+        assert_eq!(clock.poll(5_300), None);
+        records.push(Record::new(5_300, ChannelId(1), 1));
+        // polled late: stamped at the latest second begun, not the first
+        let sync = clock.poll(6_900).unwrap();
+        assert_eq!(clock.poll(7_000), None);
+        records.push(sync);
+        records.push(Record::new(7_000, ChannelId(1), 2));
+
+        let stamps: Vec<u32> = records.iter().map(|r| r.timestamp).collect();
+        assert_eq!(stamps, [5_000, 5_300, 6_850, 7_000]);
+        assert_eq!(
+            SyncPoint::from_record(&sync).unwrap().utc_ms,
+            MORNING + 2000
+        );
+        assert_eq!(split_boots(&records), vec![0..4]);
+        assert_eq!(crate::session::split_sessions(&records), vec![0..4]);
+        // the samples before the fix are placed too
+        let base = TimeBase::from_records(&records);
+        assert_eq!(base.to_utc(5_000), Some(MORNING + 150));
+    }
+
+    #[test]
+    fn time_base_prefers_best_source_and_follows_drift() {
+        let rtc = SyncPoint {
+            tick_ms: 1_000,
+            utc_ms: MORNING - 7_000, // RTC a few seconds slow
+            source: TimeSource::Rtc,
+        };
+        // the tick counter runs 0.1% fast between two GPS fixes
+        let gps = |tick_ms, utc_ms| SyncPoint {
+            tick_ms,
+            utc_ms,
+            source: TimeSource::Gps,
+        };
+        let records = [
+            Record::new(500, ChannelId(1), 0),
+            rtc.to_record(),
+            gps(10_000, MORNING).to_record(),
+            gps(110_100, MORNING + 100_000).to_record(),
+        ];
+        let base = TimeBase::from_records(&records);
+        assert_eq!(base.to_utc(10_000), Some(MORNING));
+        assert_eq!(base.to_utc(60_050), Some(MORNING + 50_000));
+        // extrapolated either side
+        assert_eq!(base.to_utc(500), Some(MORNING - 9_500));
+        assert_eq!(base.to_utc(120_100), Some(MORNING + 110_000));
+
+        assert_eq!(TimeBase::from_records(&records[..1]).to_utc(500), None);
+        assert_eq!(
+            split_boots(&[records[0], records[1], records[0]]),
+            [0..2, 2..3]
+        );
+    }
+}
@@ -0,0 +1,134 @@
+// mcaux-datalogger/src/tmp117.rs
+
+//! The TMP117 digital thermometer, over I2C: ±0.1 °C without calibration,
+//! converting continuously by default.
+
+use embedded_hal::i2c::I2c;
+
+use crate::sensor::{SensorError, TemperatureSensor};
+
+/// I2C address with ADD0 to ground; 0x49 to V+, 0x4a to SDA, 0x4b to SCL.
+pub const TMP117_ADDRESS: u8 = 0x48;
+
+const TEMP_RESULT: u8 = 0x00;
+const DEVICE_ID: u8 = 0x0f;
+
+/// Low twelve bits of the device ID register; the top four are revision.
+const ID: u16 = 0x117;
+
+/// The result register's value before the first conversion completes
+const RESET_RAW: i16 = -0x8000;
+
+/// °C per LSB
+const RESOLUTION: f32 = 0.0078125;
+
+pub struct Tmp117<I> {
+    i2c: I,
+    address: u8,
+}
+
+impl<I: I2c> Tmp117<I> {
+    pub fn new(i2c: I, address: u8) -> Self {
+        Tmp117 { i2c, address }
+    }
+
+    pub fn release(self) -> I {
+        self.i2c
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u16, SensorError<I::Error>> {
+        let mut buf = [0; 2];
+        self.i2c
+            .write_read(self.address, &[register], &mut buf)
+            .map_err(SensorError::Bus)?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    /// Check that a TMP117 is what's at the address.
+    pub fn probe(&mut self) -> Result<(), SensorError<I::Error>> {
+        match self.read_register(DEVICE_ID)? & 0x0fff {
+            ID => Ok(()),
+            _ => Err(SensorError::Missing),
+        }
+    }
+}
+
+impl<I: I2c> TemperatureSensor for Tmp117<I> {
+    type Error = SensorError<I::Error>;
+
+    /// The latest of its continuous conversions.
+    fn read_celsius(&mut self) -> Result<f32, Self::Error> {
+        match self.read_register(TEMP_RESULT)? as i16 {
+            RESET_RAW => Err(SensorError::NotReady),
+            raw => Ok(raw as f32 * RESOLUTION),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::convert::Infallible;
+    use embedded_hal::i2c::{ErrorType, Operation};
+
+    /// Answers register reads from a table.
+    struct MockI2c {
+        address: u8,
+        registers: Vec<(u8, u16)>,
+    }
+
+    impl ErrorType for MockI2c {
+        type Error = Infallible;
+    }
+
+    impl I2c for MockI2c {
+        fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            let mut register = None;
+            for op in operations {
+                match op {
+                    Operation::Write(bytes) => register = bytes.first().copied(),
+                    Operation::Read(buf) => {
+                        // nothing at the address: the bus reads high
+                        let value = register
+                            .filter(|_| address == self.address)
+                            .and_then(|r| self.registers.iter().find(|(a, _)| *a == r))
+                            .map_or(0xffff, |(_, v)| *v);
+                        buf.copy_from_slice(&value.to_be_bytes());
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reads_signed_result_register() {
+        let i2c = MockI2c {
+            address: TMP117_ADDRESS,
+            registers: vec![(DEVICE_ID, 0x1117), (TEMP_RESULT, 0x0c80)],
+        };
+        let mut sensor = Tmp117::new(i2c, TMP117_ADDRESS);
+        assert_eq!(sensor.probe(), Ok(()));
+        assert_eq!(sensor.read_celsius(), Ok(25.0));
+
+        let mut i2c = sensor.release();
+        i2c.registers[1].1 = 0xfe70;
+        assert_eq!(Tmp117::new(i2c, TMP117_ADDRESS).read_celsius(), Ok(-3.125));
+    }
+
+    #[test]
+    fn absent_or_not_yet_converted() {
+        let i2c = MockI2c {
+            address: TMP117_ADDRESS,
+            registers: vec![(DEVICE_ID, 0x0117), (TEMP_RESULT, 0x8000)],
+        };
+        let mut sensor = Tmp117::new(i2c, TMP117_ADDRESS);
+        assert_eq!(sensor.read_celsius(), Err(SensorError::NotReady));
+        let mut elsewhere = Tmp117::new(sensor.release(), 0x49);
+        assert_eq!(elsewhere.probe(), Err(SensorError::Missing));
+    }
+}
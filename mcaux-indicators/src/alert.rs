@@ -0,0 +1,312 @@
+// mcaux-indicators/src/alert.rs
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{Duration, Instant};
+#[cfg(target_arch = "wasm32")]
+use web_time::{Duration, Instant};
+
+const ALERT_KINDS: usize = 7;
+
+/// System conditions worth interrupting the normal level display for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlertKind {
+    /// Battery voltage sagging below what the charging system should hold.
+    LowBattery,
+    /// Voltage regulator running hotter than it should.
+    RegulatorOverTemp,
+    /// A switch has been reported closed far longer than any press.
+    StuckSwitch,
+    /// Charging voltage high enough to boil the battery: the regulator
+    /// isn't shunting the stator's surplus.
+    Overcharging,
+    /// Riding at speed, yet the battery isn't being charged.
+    NotCharging,
+    /// Accessories turned down to let the regulator cool.
+    ShedForHeat,
+    /// Accessories turned down to let the battery voltage recover.
+    ShedForVoltage,
+}
+
+impl AlertKind {
+    pub const ALL: [AlertKind; ALERT_KINDS] = [
+        AlertKind::LowBattery,
+        AlertKind::RegulatorOverTemp,
+        AlertKind::StuckSwitch,
+        AlertKind::Overcharging,
+        AlertKind::NotCharging,
+        AlertKind::ShedForHeat,
+        AlertKind::ShedForVoltage,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Higher wins when several alerts are showing.
+    pub fn priority(self) -> u8 {
+        match self {
+            AlertKind::RegulatorOverTemp => 30,
+            AlertKind::Overcharging => 25,
+            AlertKind::LowBattery => 20,
+            AlertKind::NotCharging => 15,
+            AlertKind::StuckSwitch => 10,
+            AlertKind::ShedForHeat => 6,
+            AlertKind::ShedForVoltage => 5,
+        }
+    }
+
+    pub fn pattern(self) -> Pattern {
+        match self {
+            // Fast red strobe on everything: pull over soon.
+            AlertKind::RegulatorOverTemp => Pattern {
+                color: [255, 0, 0],
+                indicators: [true, true, true],
+                flashes: 1,
+                on: Duration::from_millis(150),
+                off: Duration::from_millis(150),
+                pause: Duration::ZERO,
+            },
+            // Three amber blinks then a rest, on the RGB LED only.
+            AlertKind::LowBattery => Pattern {
+                color: [255, 100, 0],
+                indicators: [false, false, false],
+                flashes: 3,
+                on: Duration::from_millis(250),
+                off: Duration::from_millis(250),
+                pause: Duration::from_millis(1500),
+            },
+            // Red double blink on the RGB LED: the regulator needs looking at.
+            AlertKind::Overcharging => Pattern {
+                color: [255, 0, 0],
+                indicators: [false, false, false],
+                flashes: 2,
+                on: Duration::from_millis(150),
+                off: Duration::from_millis(150),
+                pause: Duration::from_millis(1000),
+            },
+            // One long amber blink then a rest: running on the battery alone.
+            AlertKind::NotCharging => Pattern {
+                color: [255, 100, 0],
+                indicators: [false, false, false],
+                flashes: 1,
+                on: Duration::from_millis(1000),
+                off: Duration::from_millis(250),
+                pause: Duration::from_millis(1500),
+            },
+            // A slow orange blink with the switch indicators: outputs are
+            // capped while the regulator cools.
+            AlertKind::ShedForHeat => Pattern {
+                color: [255, 40, 0],
+                indicators: [true, true, true],
+                flashes: 1,
+                on: Duration::from_millis(500),
+                off: Duration::from_millis(500),
+                pause: Duration::from_millis(2000),
+            },
+            // Two slow yellow blinks with the switch indicators: outputs are
+            // capped while the voltage recovers.
+            AlertKind::ShedForVoltage => Pattern {
+                color: [255, 200, 0],
+                indicators: [true, true, true],
+                flashes: 2,
+                on: Duration::from_millis(500),
+                off: Duration::from_millis(500),
+                pause: Duration::from_millis(2000),
+            },
+            // Quick purple double blink with the switch indicators alongside.
+            AlertKind::StuckSwitch => Pattern {
+                color: [160, 0, 255],
+                indicators: [true, true, true],
+                flashes: 2,
+                on: Duration::from_millis(100),
+                off: Duration::from_millis(200),
+                pause: Duration::from_millis(1000),
+            },
+        }
+    }
+}
+
+/// A blink code: `flashes` on/off pairs, then `pause` dark, repeating.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pattern {
+    /// RGB LED color while lit
+    pub color: [u8; 3],
+    /// Which single-color indicators blink along with the RGB LED
+    pub indicators: [bool; 3],
+    pub flashes: u8,
+    pub on: Duration,
+    pub off: Duration,
+    pub pause: Duration,
+}
+
+impl Pattern {
+    fn period(&self) -> Duration {
+        (self.on + self.off) * self.flashes.max(1) as u32 + self.pause
+    }
+
+    /// Is the pattern lit `elapsed` after it started?
+    pub fn lit_at(&self, elapsed: Duration) -> bool {
+        let period = self.period().as_millis();
+        if period == 0 {
+            return true;
+        }
+        let phase = elapsed.as_millis() % period;
+        let flash = (self.on + self.off).as_millis();
+        if phase >= flash * self.flashes.max(1) as u128 {
+            return false;
+        }
+        phase % flash < self.on.as_millis()
+    }
+
+    /// Duty cycles for all six LEDs, in `IndicatorController` order.
+    pub fn frame_at(&self, elapsed: Duration) -> [u8; 6] {
+        let mut duties = [0; 6];
+        if self.lit_at(elapsed) {
+            for (duty, &lit) in duties.iter_mut().zip(self.indicators.iter()) {
+                *duty = if lit { 255 } else { 0 };
+            }
+            duties[3..].copy_from_slice(&self.color);
+        }
+        duties
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct AlertSlot {
+    raised: Instant,
+    acknowledged: bool,
+}
+
+/// The set of currently raised alerts. The highest-priority raised alert
+/// that hasn't been acknowledged takes over the LEDs; acknowledged alerts
+/// stay raised (and can be queried) but stop preempting until cleared and
+/// raised again.
+#[derive(Default)]
+pub struct AlertLayer {
+    slots: [Option<AlertSlot>; ALERT_KINDS],
+}
+
+impl AlertLayer {
+    /// Raise an alert. Raising one that is already raised changes nothing,
+    /// so callers may simply re-raise on every sample while a condition holds.
+    pub fn raise(&mut self, kind: AlertKind, now: Instant) {
+        let slot = &mut self.slots[kind.index()];
+        if slot.is_none() {
+            *slot = Some(AlertSlot {
+                raised: now,
+                acknowledged: false,
+            });
+        }
+    }
+
+    /// Stop an alert preempting the display while leaving it raised.
+    pub fn acknowledge(&mut self, kind: AlertKind) {
+        if let Some(slot) = self.slots[kind.index()].as_mut() {
+            slot.acknowledged = true;
+        }
+    }
+
+    /// Acknowledge whatever is showing now, if anything.
+    pub fn acknowledge_showing(&mut self) -> Option<AlertKind> {
+        let kind = self.showing();
+        if let Some(kind) = kind {
+            self.acknowledge(kind);
+        }
+        kind
+    }
+
+    /// The condition has gone away.
+    pub fn clear(&mut self, kind: AlertKind) {
+        self.slots[kind.index()] = None;
+    }
+
+    pub fn is_raised(&self, kind: AlertKind) -> bool {
+        self.slots[kind.index()].is_some()
+    }
+
+    /// The alert currently owning the LEDs, if any.
+    pub fn showing(&self) -> Option<AlertKind> {
+        AlertKind::ALL
+            .into_iter()
+            .filter(|k| matches!(self.slots[k.index()], Some(s) if !s.acknowledged))
+            .max_by_key(|k| k.priority())
+    }
+
+    /// Duty cycles of the showing alert, or None to let the normal display through.
+    pub fn frame_at(&self, now: Instant) -> Option<[u8; 6]> {
+        let kind = self.showing()?;
+        let slot = self.slots[kind.index()]?;
+        Some(
+            kind.pattern()
+                .frame_at(now.saturating_duration_since(slot.raised)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn blink_code_timing() {
+        let p = AlertKind::LowBattery.pattern();
+        let ms = Duration::from_millis;
+        assert!(p.lit_at(ms(0)));
+        assert!(!p.lit_at(ms(300)));
+        assert!(p.lit_at(ms(500)));
+        assert!(p.lit_at(ms(1000)));
+        assert!(!p.lit_at(ms(1600)));
+        assert!(p.lit_at(ms(3000)));
+    }
+
+    #[test]
+    fn highest_priority_preempts() {
+        let now = Instant::now();
+        let mut a = AlertLayer::default();
+        assert_eq!(a.showing(), None);
+        a.raise(AlertKind::StuckSwitch, now);
+        a.raise(AlertKind::RegulatorOverTemp, now);
+        a.raise(AlertKind::LowBattery, now);
+        assert_eq!(a.showing(), Some(AlertKind::RegulatorOverTemp));
+        assert_eq!(a.frame_at(now), Some([255, 255, 255, 255, 0, 0]));
+    }
+
+    #[test]
+    fn shedding_gives_way_to_what_caused_it() {
+        // a sag can raise LowBattery and ShedForVoltage together
+        let now = Instant::now();
+        let mut a = AlertLayer::default();
+        a.raise(AlertKind::ShedForVoltage, now);
+        a.raise(AlertKind::ShedForHeat, now);
+        assert_eq!(a.showing(), Some(AlertKind::ShedForHeat));
+        a.raise(AlertKind::LowBattery, now);
+        assert_eq!(a.showing(), Some(AlertKind::LowBattery));
+        a.clear(AlertKind::LowBattery);
+        a.raise(AlertKind::RegulatorOverTemp, now);
+        assert_eq!(a.showing(), Some(AlertKind::RegulatorOverTemp));
+    }
+
+    #[test]
+    fn acknowledge_and_clear_fall_back() {
+        let now = Instant::now();
+        let mut a = AlertLayer::default();
+        a.raise(AlertKind::LowBattery, now);
+        a.raise(AlertKind::RegulatorOverTemp, now);
+        assert_eq!(a.acknowledge_showing(), Some(AlertKind::RegulatorOverTemp));
+        assert_eq!(a.showing(), Some(AlertKind::LowBattery));
+        assert!(a.is_raised(AlertKind::RegulatorOverTemp));
+
+        // re-raising an acknowledged alert doesn't bring it back
+        a.raise(AlertKind::RegulatorOverTemp, now);
+        assert_eq!(a.showing(), Some(AlertKind::LowBattery));
+
+        a.clear(AlertKind::LowBattery);
+        assert_eq!(a.showing(), None);
+        assert_eq!(a.frame_at(now), None);
+
+        // but clearing and raising again does
+        a.clear(AlertKind::RegulatorOverTemp);
+        a.raise(AlertKind::RegulatorOverTemp, now);
+        assert_eq!(a.showing(), Some(AlertKind::RegulatorOverTemp));
+    }
+}
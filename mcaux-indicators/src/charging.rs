@@ -0,0 +1,70 @@
+// mcaux-indicators/src/charging.rs
+
+//! Charging-system alerts, from `mcaux_charging`'s `ChargeClassifier`.
+
+use mcaux_charging::{ChargeClassifier, ChargeState};
+
+use crate::{AlertKind, IndicatorController};
+
+/// The alerts a charge state can raise, one at a time.
+const CHARGE_ALERTS: [AlertKind; 3] = [
+    AlertKind::LowBattery,
+    AlertKind::Overcharging,
+    AlertKind::NotCharging,
+];
+
+impl AlertKind {
+    /// The alert for a charge state, if it has one.
+    pub fn for_charge_state(state: ChargeState) -> Option<AlertKind> {
+        match state {
+            ChargeState::Low => Some(AlertKind::LowBattery),
+            ChargeState::Overcharging => Some(AlertKind::Overcharging),
+            ChargeState::NotCharging => Some(AlertKind::NotCharging),
+            ChargeState::Charging => None,
+        }
+    }
+}
+
+impl IndicatorController {
+    /// Raise the alert the classifier's warning calls for and clear the
+    /// other charging alerts.
+    pub fn show_charge_state(&mut self, classifier: &ChargeClassifier) {
+        let alert = classifier.warning().and_then(AlertKind::for_charge_state);
+        for kind in CHARGE_ALERTS {
+            if alert == Some(kind) {
+                self.raise_alert(kind);
+            } else {
+                self.clear_alert(kind);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mcaux_charging::ChargeLimits;
+
+    #[test]
+    fn one_charging_alert_at_a_time() {
+        let mut classifier = ChargeClassifier::new(ChargeLimits::default());
+        let mut indicators = IndicatorController::default();
+        indicators.raise_alert(AlertKind::LowBattery);
+        for t in (0..=4_000).step_by(500) {
+            classifier.update(t, 15.0, 5_000.0);
+            indicators.show_charge_state(&classifier);
+        }
+        assert_eq!(indicators.alerts().showing(), Some(AlertKind::Overcharging));
+        assert!(!indicators.alerts().is_raised(AlertKind::LowBattery));
+
+        // not charging is only worth a warning at riding speed
+        for t in (5_000..=9_000).step_by(500) {
+            classifier.update(t, 12.6, 1_200.0);
+            indicators.show_charge_state(&classifier);
+        }
+        assert_eq!(indicators.alerts().showing(), None);
+        classifier.update(9_500, 12.6, 3_500.0);
+        indicators.show_charge_state(&classifier);
+        assert_eq!(indicators.alerts().showing(), Some(AlertKind::NotCharging));
+    }
+}
@@ -1,68 +1,286 @@
 // mcaux-indicators/src/lib.rs
 
+pub mod alert;
+pub mod charging;
+pub mod night;
+pub mod output;
+pub mod palette;
+pub mod shedding;
+pub mod transition;
+
+pub use alert::{AlertKind, AlertLayer, Pattern};
+pub use night::{NightMode, NightTrigger};
+pub use output::{IndicatorOutput, MockOutput, Pca9685, PwmChannels};
+pub use palette::{Calibration, ColorSpace, Palette};
+pub use shedding::{LoadShedding, ShedReason, SheddingPolicy};
+pub use transition::{Crossfade, Easing};
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{Duration, Instant};
+#[cfg(target_arch = "wasm32")]
+use web_time::{Duration, Instant};
+
 /// Provide LED levels and level-change animations for mcaux.
-#[derive(Default)]
 pub struct IndicatorController {
     //   ins: [bool; 3],
-    //    outs: [u8; 4],
-    //    animations: [Option<Animation>; 5],
-    //    last_frame: Instant,
+    /// Colors for the RGB heat-level LED
+    palette: Palette,
+
+    /// Per-die correction for the RGB heat-level LED
+    calibration: Calibration,
+
+    /// How many states the heat output cycles through, off included
+    heat_levels: u8,
+
+    /// System conditions that take over the LEDs while raised
+    alerts: AlertLayer,
+
+    /// When the previous frame was computed, None before the first
+    last_frame: Option<Instant>,
+
+    /// Heat LED color fade in progress (or finished, holding its target)
+    fade: Option<Crossfade>,
+
+    /// How long a heat level change takes to fade in
+    fade_duration: Duration,
+
+    fade_easing: Easing,
+
+    /// Dimming and red-shifting for after dark
+    night: NightMode,
+}
+
+impl Default for IndicatorController {
+    fn default() -> Self {
+        IndicatorController {
+            palette: Palette::DEFAULT,
+            calibration: Calibration::default(),
+            heat_levels: 5,
+            alerts: AlertLayer::default(),
+            last_frame: None,
+            fade: None,
+            fade_duration: Duration::from_millis(300),
+            fade_easing: Easing::default(),
+            night: NightMode::default(),
+        }
+    }
 }
 
 /// Provide LED levels and level-change animations for mcaux.
 /// Not much abstraction here, maybe we make it more configurable
 /// and less hard-coded later.
 impl IndicatorController {
-    pub fn get_duty_cycles(&mut self, _new_ins: [bool; 3], new_outs: [u8; 4]) -> [u8; 6] {
-        let mut duties: [u8; 6] = [0; 6];
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
 
-        /*
-        let now = Instant::now();
-        for anim in self.animations.iter_mut() {
-            if let Some(animation) = anim {
-                animation.run(now.duration_since(self.last_frame));
-            }
-        }
-        */
+    pub fn palette(&self) -> Palette {
+        self.palette
+    }
 
-        duties[0] = if new_outs[0] != 0 { 255 } else { 0 };
-        duties[1] = if new_outs[1] != 0 { 255 } else { 0 };
-        duties[2] = if new_outs[2] != 0 { 255 } else { 0 };
-        let rgb = color_for_heat_level(new_outs[2]);
-        duties[3] = rgb[0];
-        duties[4] = rgb[1];
-        duties[5] = rgb[2];
-        duties
+    pub fn set_calibration(&mut self, calibration: Calibration) {
+        self.calibration = calibration;
     }
-}
 
-fn color_for_heat_level(level: u8) -> [u8; 3] {
-    match level {
-        0 => [0, 0, 0],       // off
-        1 => [170, 50, 50],   // dull red
-        2 => [255, 128, 0],   // Orange
-        3 => [255, 255, 0],   // Bright yellow
-        _ => [255, 255, 255], // White
+    /// Match the heat output's cycle count as given to `MomentaryController::add_switch`.
+    pub fn set_heat_levels(&mut self, levels: u8) {
+        self.heat_levels = levels;
+    }
+
+    /// How heat LED color changes fade in. A zero duration switches instantly.
+    pub fn set_fade(&mut self, duration: Duration, easing: Easing) {
+        self.fade_duration = duration;
+        self.fade_easing = easing;
+    }
+
+    pub fn set_night_mode(&mut self, night: NightMode) {
+        self.night = night;
+    }
+
+    pub fn night_mode(&self) -> &NightMode {
+        &self.night
+    }
+
+    pub fn night_mode_mut(&mut self) -> &mut NightMode {
+        &mut self.night
+    }
+
+    /// Feed an ambient light reading to a `NightTrigger::LightSensor` night mode.
+    pub fn report_ambient_light(&mut self, level: u16) {
+        self.night.report_light(level);
     }
-}
 
-/*
-struct Animation {}
+    /// Raise a system alert; it preempts the level display until acknowledged or cleared.
+    pub fn raise_alert(&mut self, kind: AlertKind) {
+        self.alerts.raise(kind, Instant::now());
+    }
+
+    pub fn acknowledge_alert(&mut self, kind: AlertKind) {
+        self.alerts.acknowledge(kind);
+    }
+
+    pub fn clear_alert(&mut self, kind: AlertKind) {
+        self.alerts.clear(kind);
+    }
+
+    pub fn alerts(&self) -> &AlertLayer {
+        &self.alerts
+    }
+
+    pub fn alerts_mut(&mut self) -> &mut AlertLayer {
+        &mut self.alerts
+    }
+
+    pub fn get_duty_cycles(&mut self, new_ins: [bool; 3], new_outs: [u8; 4]) -> [u8; 6] {
+        self.get_duty_cycles_at(Instant::now(), new_ins, new_outs)
+    }
+
+    /// Compute duty cycles and send them straight to the LEDs.
+    pub fn drive<O: IndicatorOutput>(
+        &mut self,
+        output: &mut O,
+        new_ins: [bool; 3],
+        new_outs: [u8; 4],
+    ) -> Result<[u8; 6], O::Error> {
+        let duties = self.get_duty_cycles(new_ins, new_outs);
+        output.write_duties(&duties)?;
+        Ok(duties)
+    }
+
+    /// As `get_duty_cycles`, for a given moment rather than now.
+    pub fn get_duty_cycles_at(
+        &mut self,
+        now: Instant,
+        _new_ins: [bool; 3],
+        new_outs: [u8; 4],
+    ) -> [u8; 6] {
+        self.night.update(&new_outs);
+        let delta = self
+            .last_frame
+            .map_or(Duration::ZERO, |last| now.saturating_duration_since(last));
+        self.last_frame = Some(now);
+        // Keep fading underneath any alert so we come back to the right color.
+        let heat_rgb = self.heat_color(delta, new_outs[2]);
+
+        let duties = if let Some(duties) = self.alerts.frame_at(now) {
+            duties
+        } else {
+            let mut duties: [u8; 6] = [0; 6];
+            duties[0] = if new_outs[0] != 0 { 255 } else { 0 };
+            duties[1] = if new_outs[1] != 0 { 255 } else { 0 };
+            duties[2] = if new_outs[2] != 0 { 255 } else { 0 };
+            duties[3] = heat_rgb[0];
+            duties[4] = heat_rgb[1];
+            duties[5] = heat_rgb[2];
+            duties
+        };
+        self.finish(duties)
+    }
 
-impl Animation {
-    pub fn run(&mut self, delta: Duration) {
-        // Animation logic here
+    /// Night-mode red shift, per-die calibration, then night-mode dimming.
+    fn finish(&self, mut duties: [u8; 6]) -> [u8; 6] {
+        let rgb = self
+            .calibration
+            .apply(self.night.red_shifted([duties[3], duties[4], duties[5]]));
+        duties[3..].copy_from_slice(&rgb);
+        self.night.dimmed(duties)
+    }
+
+    /// Advance the heat LED fade by `delta`, starting a new one from wherever
+    /// we are now if the level (or palette) has changed.
+    fn heat_color(&mut self, delta: Duration, level: u8) -> [u8; 3] {
+        let target = self.palette.color_for_level(level, self.heat_levels);
+        let fade = self.fade.get_or_insert(Crossfade::new(target, target));
+        fade.advance(delta);
+        // Fades are decoration; skip them after dark.
+        let duration = if self.night.is_active() {
+            Duration::ZERO
+        } else {
+            self.fade_duration
+        };
+        let space = self.palette.space();
+        if fade.target() != target {
+            *fade = Crossfade::new(fade.color(duration, self.fade_easing, space), target);
+        }
+        fade.color(duration, self.fade_easing, space)
     }
 }
 
 #[cfg(test)]
-mod tests {
+mod test {
     use super::*;
 
     #[test]
-    fn it_works() {
-        let result = 2 + 2;
-        assert_eq!(result, 4);
+    fn alert_preempts_then_falls_back() {
+        let mut ic: IndicatorController = Default::default();
+        let now = Instant::now();
+        let outs = [1, 0, 2, 0];
+        let normal = ic.get_duty_cycles_at(now, [false; 3], outs);
+        assert_eq!(normal, [255, 0, 255, 255, 128, 0]);
+
+        ic.alerts_mut().raise(AlertKind::RegulatorOverTemp, now);
+        assert_eq!(
+            ic.get_duty_cycles_at(now, [false; 3], outs),
+            [255, 255, 255, 255, 0, 0]
+        );
+
+        ic.acknowledge_alert(AlertKind::RegulatorOverTemp);
+        assert_eq!(ic.get_duty_cycles_at(now, [false; 3], outs), normal);
+    }
+
+    #[test]
+    fn heat_change_crossfades() {
+        let mut ic: IndicatorController = Default::default();
+        ic.set_fade(Duration::from_millis(200), Easing::Linear);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let rgb = |d: [u8; 6]| [d[3], d[4], d[5]];
+
+        assert_eq!(
+            rgb(ic.get_duty_cycles_at(at(0), [false; 3], [0, 0, 3, 0])),
+            [255, 255, 0]
+        );
+        // level change: still showing the old color, then halfway, then there
+        assert_eq!(
+            rgb(ic.get_duty_cycles_at(at(10), [false; 3], [0, 0, 4, 0])),
+            [255, 255, 0]
+        );
+        assert_eq!(
+            rgb(ic.get_duty_cycles_at(at(110), [false; 3], [0, 0, 4, 0])),
+            [255, 255, 128]
+        );
+        assert_eq!(
+            rgb(ic.get_duty_cycles_at(at(210), [false; 3], [0, 0, 4, 0])),
+            [255, 255, 255]
+        );
+
+        // changing mid-fade starts from the color showing at the time
+        ic.get_duty_cycles_at(at(220), [false; 3], [0, 0, 0, 0]);
+        ic.get_duty_cycles_at(at(320), [false; 3], [0, 0, 0, 0]);
+        assert_eq!(
+            rgb(ic.get_duty_cycles_at(at(320), [false; 3], [0, 0, 4, 0])),
+            [128, 128, 128]
+        );
+        assert_eq!(
+            rgb(ic.get_duty_cycles_at(at(420), [false; 3], [0, 0, 4, 0])),
+            [192, 192, 192]
+        );
+    }
+
+    #[test]
+    fn night_mode_dims_red_shifts_and_skips_fades() {
+        let mut ic: IndicatorController = Default::default();
+        ic.set_night_mode(NightMode::new(NightTrigger::Output(3), 51, true));
+        let now = Instant::now();
+        assert_eq!(
+            ic.get_duty_cycles_at(now, [false; 3], [1, 0, 4, 0]),
+            [255, 0, 255, 255, 255, 255]
+        );
+        assert!(!ic.night_mode().is_active());
+        assert_eq!(
+            ic.get_duty_cycles_at(now, [false; 3], [1, 0, 3, 1]),
+            [51, 0, 51, 45, 0, 0]
+        );
+        assert!(ic.night_mode().is_active());
     }
 }
-*/
@@ -0,0 +1,158 @@
+// mcaux-indicators/src/night.rs
+
+/// What turns night mode on and off, apart from a manual override.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NightTrigger {
+    /// Only the manual override does anything.
+    #[default]
+    Manual,
+    /// Follow a `MomentaryController` output: night while it is nonzero.
+    /// Point this at the headlight output, or at a long-press output
+    /// set aside as a night mode switch.
+    Output(usize),
+    /// Follow an ambient light sensor: night once the reading falls to
+    /// `dark`, day again only once it climbs back to `light`. Keep a gap
+    /// between the two so passing under a streetlight doesn't flicker.
+    LightSensor { dark: u16, light: u16 },
+}
+
+/// Dimmed, optionally red-shifted indicators for riding after dark.
+/// While active, decorative effects (crossfades) are skipped as well.
+#[derive(Clone, Copy, Debug)]
+pub struct NightMode {
+    pub trigger: NightTrigger,
+    /// Every duty cycle is scaled so full-on becomes this.
+    pub max_duty: u8,
+    /// Show the RGB LED as red at the brightness the color would have had.
+    pub red_shift: bool,
+    /// Manual setting; None follows the trigger.
+    manual: Option<bool>,
+    /// Latest light-sensor verdict, with hysteresis applied.
+    sensor_dark: bool,
+    active: bool,
+}
+
+impl Default for NightMode {
+    fn default() -> Self {
+        NightMode {
+            trigger: NightTrigger::Manual,
+            max_duty: 40,
+            red_shift: true,
+            manual: None,
+            sensor_dark: false,
+            active: false,
+        }
+    }
+}
+
+impl NightMode {
+    pub fn new(trigger: NightTrigger, max_duty: u8, red_shift: bool) -> Self {
+        NightMode {
+            trigger,
+            max_duty,
+            red_shift,
+            ..Default::default()
+        }
+    }
+
+    /// Force night mode on or off, or hand it back to the trigger with None.
+    pub fn set_manual(&mut self, manual: Option<bool>) {
+        self.manual = manual;
+        if let Some(on) = manual {
+            self.active = on;
+        }
+    }
+
+    pub fn report_light(&mut self, level: u16) {
+        if let NightTrigger::LightSensor { dark, light } = self.trigger {
+            if level <= dark {
+                self.sensor_dark = true;
+            } else if level >= light {
+                self.sensor_dark = false;
+            }
+        }
+    }
+
+    /// Re-evaluate against the latest outputs. Returns whether we're in night mode.
+    pub fn update(&mut self, outs: &[u8]) -> bool {
+        self.active = match (self.manual, self.trigger) {
+            (Some(on), _) => on,
+            (None, NightTrigger::Manual) => false,
+            (None, NightTrigger::Output(idx)) => outs.get(idx).is_some_and(|&o| o != 0),
+            (None, NightTrigger::LightSensor { .. }) => self.sensor_dark,
+        };
+        self.active
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Move an RGB color to pure red of about the same perceived brightness.
+    pub fn red_shifted(&self, rgb: [u8; 3]) -> [u8; 3] {
+        if !(self.active && self.red_shift) {
+            return rgb;
+        }
+        let luma = (rgb[0] as u32 * 299 + rgb[1] as u32 * 587 + rgb[2] as u32 * 114) / 1000;
+        [luma as u8, 0, 0]
+    }
+
+    /// Scale duty cycles down to the night-time ceiling.
+    pub fn dimmed(&self, duties: [u8; 6]) -> [u8; 6] {
+        if !self.active {
+            return duties;
+        }
+        duties.map(|d| ((d as u32 * self.max_duty as u32 + 127) / 255) as u8)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn follows_output() {
+        let mut n = NightMode::new(NightTrigger::Output(3), 40, true);
+        assert!(!n.update(&[1, 1, 4, 0]));
+        assert!(n.update(&[0, 0, 0, 1]));
+        n.set_manual(Some(false));
+        assert!(!n.update(&[0, 0, 0, 1]));
+        n.set_manual(None);
+        assert!(n.update(&[0, 0, 0, 1]));
+    }
+
+    #[test]
+    fn light_sensor_hysteresis() {
+        let mut n = NightMode::new(
+            NightTrigger::LightSensor {
+                dark: 10,
+                light: 50,
+            },
+            40,
+            true,
+        );
+        n.report_light(30);
+        assert!(!n.update(&[]));
+        n.report_light(8);
+        assert!(n.update(&[]));
+        n.report_light(30);
+        assert!(n.update(&[]));
+        n.report_light(60);
+        assert!(!n.update(&[]));
+    }
+
+    #[test]
+    fn dims_and_red_shifts_only_when_active() {
+        let mut n = NightMode::default();
+        assert_eq!(n.dimmed([255; 6]), [255; 6]);
+        assert_eq!(n.red_shifted([255, 255, 255]), [255, 255, 255]);
+        n.set_manual(Some(true));
+        assert_eq!(
+            n.dimmed([255, 0, 128, 255, 255, 255]),
+            [40, 0, 20, 40, 40, 40]
+        );
+        assert_eq!(n.red_shifted([255, 255, 255]), [255, 0, 0]);
+        assert_eq!(n.red_shifted([255, 255, 0]), [225, 0, 0]);
+        assert_eq!(n.red_shifted([170, 50, 50]), [85, 0, 0]);
+    }
+}
@@ -0,0 +1,221 @@
+// mcaux-indicators/src/output.rs
+
+use core::convert::Infallible;
+
+use embedded_hal::i2c::I2c;
+use embedded_hal::pwm::SetDutyCycle;
+
+/// Something that puts the six `IndicatorController` duty cycles onto LEDs:
+/// three single-color indicators, then red, green, blue of the heat LED.
+pub trait IndicatorOutput {
+    type Error;
+
+    fn write_duties(&mut self, duties: &[u8; 6]) -> Result<(), Self::Error>;
+}
+
+/// Six embedded-hal PWM channels, one per LED, in `IndicatorController` order.
+/// Channels on different timers usually have different types; wrap them in
+/// an enum that forwards `SetDutyCycle` to use them here.
+pub struct PwmChannels<P> {
+    channels: [P; 6],
+}
+
+impl<P: SetDutyCycle> PwmChannels<P> {
+    pub fn new(channels: [P; 6]) -> Self {
+        PwmChannels { channels }
+    }
+
+    pub fn release(self) -> [P; 6] {
+        self.channels
+    }
+}
+
+impl<P: SetDutyCycle> IndicatorOutput for PwmChannels<P> {
+    type Error = P::Error;
+
+    fn write_duties(&mut self, duties: &[u8; 6]) -> Result<(), Self::Error> {
+        for (channel, &duty) in self.channels.iter_mut().zip(duties.iter()) {
+            channel.set_duty_cycle_fraction(duty as u16, 255)?;
+        }
+        Ok(())
+    }
+}
+
+/// Default I2C address of a PCA9685 with all address pins low.
+pub const PCA9685_ADDRESS: u8 = 0x40;
+
+const MODE1: u8 = 0x00;
+const MODE2: u8 = 0x01;
+const LED0_ON_L: u8 = 0x06;
+const PRE_SCALE: u8 = 0xFE;
+
+const MODE1_SLEEP: u8 = 0x10;
+const MODE1_AUTO_INCREMENT: u8 = 0x20;
+const MODE2_OUTDRV: u8 = 0x04;
+
+/// Set in an ON_H or OFF_H register to hold the output fully on or off.
+const FULL: u8 = 0x10;
+
+/// The PCA9685 16-channel 12-bit I2C LED driver, with the six indicators
+/// wired to six consecutive channels starting at `first_channel`.
+pub struct Pca9685<I2C> {
+    i2c: I2C,
+    address: u8,
+    first_channel: u8,
+}
+
+impl<I2C: I2c> Pca9685<I2C> {
+    pub fn new(i2c: I2C, address: u8, first_channel: u8) -> Self {
+        if first_channel > 10 {
+            panic!("Six channels starting at {first_channel} runs off the end of the PCA9685");
+        }
+        Pca9685 {
+            i2c,
+            address,
+            first_channel,
+        }
+    }
+
+    /// Set the PWM frequency (24 to 1526 Hz on the internal 25MHz oscillator),
+    /// enable register auto-increment and totem-pole outputs, and wake up.
+    pub fn init(&mut self, pwm_hz: u16) -> Result<(), I2C::Error> {
+        let prescale = (25_000_000 / (4096 * pwm_hz.max(1) as u32)).saturating_sub(1);
+        let prescale = prescale.clamp(3, 255) as u8;
+        // The prescaler can only be written while asleep.
+        self.i2c.write(self.address, &[MODE1, MODE1_SLEEP])?;
+        self.i2c.write(self.address, &[PRE_SCALE, prescale])?;
+        self.i2c.write(self.address, &[MODE2, MODE2_OUTDRV])?;
+        self.i2c.write(self.address, &[MODE1, MODE1_AUTO_INCREMENT])
+    }
+
+    pub fn release(self) -> I2C {
+        self.i2c
+    }
+}
+
+impl<I2C: I2c> IndicatorOutput for Pca9685<I2C> {
+    type Error = I2C::Error;
+
+    /// One auto-incrementing burst covering all six channels.
+    fn write_duties(&mut self, duties: &[u8; 6]) -> Result<(), Self::Error> {
+        let mut buf = [0u8; 1 + 4 * 6];
+        buf[0] = LED0_ON_L + 4 * self.first_channel;
+        for (regs, &duty) in buf[1..].chunks_exact_mut(4).zip(duties.iter()) {
+            // [ON_L, ON_H, OFF_L, OFF_H]
+            match duty {
+                0 => regs.copy_from_slice(&[0, 0, 0, FULL]),
+                255 => regs.copy_from_slice(&[0, FULL, 0, 0]),
+                _ => {
+                    let off = ((duty as u32 * 4095 / 255) as u16).to_le_bytes();
+                    regs.copy_from_slice(&[0, 0, off[0], off[1]]);
+                }
+            }
+        }
+        self.i2c.write(self.address, &buf)
+    }
+}
+
+/// Records every frame written, for host-side tests of code that drives
+/// indicators.
+#[derive(Default)]
+pub struct MockOutput {
+    pub frames: Vec<[u8; 6]>,
+}
+
+impl MockOutput {
+    pub fn last(&self) -> Option<[u8; 6]> {
+        self.frames.last().copied()
+    }
+}
+
+impl IndicatorOutput for MockOutput {
+    type Error = Infallible;
+
+    fn write_duties(&mut self, duties: &[u8; 6]) -> Result<(), Self::Error> {
+        self.frames.push(*duties);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use embedded_hal::i2c::{ErrorType as I2cErrorType, Operation};
+    use embedded_hal::pwm::ErrorType as PwmErrorType;
+
+    #[derive(Default)]
+    struct MockPwm {
+        duty: u16,
+    }
+
+    impl PwmErrorType for MockPwm {
+        type Error = Infallible;
+    }
+
+    impl SetDutyCycle for MockPwm {
+        fn max_duty_cycle(&self) -> u16 {
+            1000
+        }
+
+        fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error> {
+            self.duty = duty;
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct MockI2c {
+        writes: Vec<(u8, Vec<u8>)>,
+    }
+
+    impl I2cErrorType for MockI2c {
+        type Error = Infallible;
+    }
+
+    impl I2c for MockI2c {
+        fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            for op in operations {
+                if let Operation::Write(bytes) = op {
+                    self.writes.push((address, bytes.to_vec()));
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pwm_channels_scale_to_max_duty() {
+        let mut out = PwmChannels::<MockPwm>::new(Default::default());
+        out.write_duties(&[0, 255, 51, 128, 0, 255]).unwrap();
+        let duties: Vec<u16> = out.release().iter().map(|p| p.duty).collect();
+        assert_eq!(duties, [0, 1000, 200, 501, 0, 1000]);
+    }
+
+    #[test]
+    fn pca9685_init_and_burst() {
+        let mut out = Pca9685::new(MockI2c::default(), PCA9685_ADDRESS, 2);
+        out.init(1000).unwrap();
+        out.write_duties(&[0, 255, 128, 0, 0, 0]).unwrap();
+        let writes = out.release().writes;
+        assert_eq!(writes[0], (0x40, vec![MODE1, MODE1_SLEEP]));
+        assert_eq!(writes[1], (0x40, vec![PRE_SCALE, 5]));
+        let (addr, burst) = &writes[4];
+        assert_eq!(*addr, 0x40);
+        assert_eq!(burst.len(), 25);
+        assert_eq!(burst[0], LED0_ON_L + 8);
+        assert_eq!(burst[1..5], [0, 0, 0, FULL]);
+        assert_eq!(burst[5..9], [0, FULL, 0, 0]);
+        assert_eq!(burst[9..13], [0, 0, 0x07, 0x08]);
+    }
+
+    #[test]
+    fn mock_records_frames() {
+        let mut out = MockOutput::default();
+        out.write_duties(&[1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(out.last(), Some([1, 2, 3, 4, 5, 6]));
+    }
+}
@@ -0,0 +1,332 @@
+// mcaux-indicators/src/palette.rs
+
+/// Color space in which we blend between neighbouring palette stops.
+/// RGB blends go muddy through the middle (red to green passes brown),
+/// HSV and HSL walk around the hue wheel instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorSpace {
+    #[default]
+    Rgb,
+    Hsv,
+    Hsl,
+}
+
+/// A named run of colors spread across the levels of an output.
+/// Level 0 is always off; levels 1..levels-1 are spread evenly from the
+/// first stop to the last, interpolating in `space` between stops.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette {
+    name: &'static str,
+    stops: &'static [[u8; 3]],
+    space: ColorSpace,
+}
+
+impl Palette {
+    /// The original heat colors: dull red, orange, bright yellow, white.
+    pub const DEFAULT: Palette = Palette {
+        name: "default",
+        stops: &[[170, 50, 50], [255, 128, 0], [255, 255, 0], [255, 255, 255]],
+        space: ColorSpace::Rgb,
+    };
+
+    /// Blue through yellow, distinguishable without relying on red vs green.
+    pub const COLORBLIND_SAFE: Palette = Palette {
+        name: "colorblind-safe",
+        stops: &[[0, 60, 200], [0, 160, 200], [230, 200, 0], [255, 255, 255]],
+        space: ColorSpace::Rgb,
+    };
+
+    /// Red only, brightness alone tells the level. Kind to dark-adapted eyes.
+    pub const NIGHT_RED_ONLY: Palette = Palette {
+        name: "night-red-only",
+        stops: &[[40, 0, 0], [90, 0, 0], [160, 0, 0], [255, 0, 0]],
+        space: ColorSpace::Rgb,
+    };
+
+    /// All of the built-in palettes, for lookup by name.
+    pub const NAMED: [Palette; 3] = [
+        Palette::DEFAULT,
+        Palette::COLORBLIND_SAFE,
+        Palette::NIGHT_RED_ONLY,
+    ];
+
+    /// A custom palette. Panics on an empty stop list.
+    pub const fn new(name: &'static str, stops: &'static [[u8; 3]], space: ColorSpace) -> Palette {
+        if stops.is_empty() {
+            panic!("A palette needs at least one color stop");
+        }
+        Palette { name, stops, space }
+    }
+
+    /// Find a built-in palette by name.
+    pub fn named(name: &str) -> Option<Palette> {
+        Palette::NAMED.into_iter().find(|p| p.name == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn space(&self) -> ColorSpace {
+        self.space
+    }
+
+    /// Same stops, blended in a different color space.
+    pub fn with_space(mut self, space: ColorSpace) -> Palette {
+        self.space = space;
+        self
+    }
+
+    /// Color for `level` of an output that cycles through `levels` states
+    /// (so `level` runs 0..levels). Out-of-range levels show the last stop.
+    pub fn color_for_level(&self, level: u8, levels: u8) -> [u8; 3] {
+        if level == 0 {
+            return [0, 0, 0];
+        }
+        let top = levels.saturating_sub(1).max(1);
+        let level = level.min(top);
+        if top == 1 {
+            return self.stops[self.stops.len() - 1];
+        }
+        let t = (level - 1) as f32 / (top - 1) as f32;
+        self.color_at(t)
+    }
+
+    /// Color at position `t` (0.0 to 1.0) along the palette.
+    pub fn color_at(&self, t: f32) -> [u8; 3] {
+        let last = self.stops.len() - 1;
+        let pos = t.clamp(0.0, 1.0) * last as f32;
+        let idx = (pos.floor() as usize).min(last);
+        if idx == last {
+            return self.stops[last];
+        }
+        blend(
+            self.stops[idx],
+            self.stops[idx + 1],
+            pos - idx as f32,
+            self.space,
+        )
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::DEFAULT
+    }
+}
+
+/// Per-channel gain applied after palette lookup, to even out the
+/// different efficiencies of the red, green and blue dies of a particular
+/// LED. Measure by eye: turn the channel that looks too strong down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Calibration {
+    gain: [f32; 3],
+}
+
+impl Calibration {
+    pub fn new(red: f32, green: f32, blue: f32) -> Calibration {
+        Calibration {
+            gain: [
+                red.clamp(0.0, 1.0),
+                green.clamp(0.0, 1.0),
+                blue.clamp(0.0, 1.0),
+            ],
+        }
+    }
+
+    pub fn apply(&self, rgb: [u8; 3]) -> [u8; 3] {
+        let mut out = [0; 3];
+        for i in 0..3 {
+            out[i] = (rgb[i] as f32 * self.gain[i]).round() as u8;
+        }
+        out
+    }
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration { gain: [1.0; 3] }
+    }
+}
+
+/// Blend from `a` to `b` by `t` (0.0 to 1.0) in the given color space.
+pub fn blend(a: [u8; 3], b: [u8; 3], t: f32, space: ColorSpace) -> [u8; 3] {
+    match space {
+        ColorSpace::Rgb => {
+            let a = to_unit(a);
+            let b = to_unit(b);
+            from_unit([
+                lerp(a[0], b[0], t),
+                lerp(a[1], b[1], t),
+                lerp(a[2], b[2], t),
+            ])
+        }
+        ColorSpace::Hsv => {
+            let a = rgb_to_hsv(to_unit(a));
+            let b = rgb_to_hsv(to_unit(b));
+            from_unit(hsv_to_rgb(lerp_hue_triple(a, b, t)))
+        }
+        ColorSpace::Hsl => {
+            let a = rgb_to_hsl(to_unit(a));
+            let b = rgb_to_hsl(to_unit(b));
+            from_unit(hsl_to_rgb(lerp_hue_triple(a, b, t)))
+        }
+    }
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Interpolate (hue, saturation, value-or-lightness) triples, taking the
+/// short way around the hue wheel. A gray end (no saturation) has no
+/// meaningful hue, so borrow the other end's.
+fn lerp_hue_triple(mut a: [f32; 3], mut b: [f32; 3], t: f32) -> [f32; 3] {
+    if a[1] == 0.0 {
+        a[0] = b[0];
+    }
+    if b[1] == 0.0 {
+        b[0] = a[0];
+    }
+    let mut dh = b[0] - a[0];
+    if dh > 180.0 {
+        dh -= 360.0;
+    } else if dh < -180.0 {
+        dh += 360.0;
+    }
+    let h = (a[0] + dh * t).rem_euclid(360.0);
+    [h, lerp(a[1], b[1], t), lerp(a[2], b[2], t)]
+}
+
+fn to_unit(rgb: [u8; 3]) -> [f32; 3] {
+    [
+        rgb[0] as f32 / 255.0,
+        rgb[1] as f32 / 255.0,
+        rgb[2] as f32 / 255.0,
+    ]
+}
+
+fn from_unit(rgb: [f32; 3]) -> [u8; 3] {
+    let c = |x: f32| (x.clamp(0.0, 1.0) * 255.0).round() as u8;
+    [c(rgb[0]), c(rgb[1]), c(rgb[2])]
+}
+
+/// Hue in degrees of a unit RGB color, given its max, min and their difference.
+fn hue(rgb: [f32; 3], max: f32, delta: f32) -> f32 {
+    if delta == 0.0 {
+        0.0
+    } else if max == rgb[0] {
+        60.0 * ((rgb[1] - rgb[2]) / delta).rem_euclid(6.0)
+    } else if max == rgb[1] {
+        60.0 * ((rgb[2] - rgb[0]) / delta + 2.0)
+    } else {
+        60.0 * ((rgb[0] - rgb[1]) / delta + 4.0)
+    }
+}
+
+fn rgb_to_hsv(rgb: [f32; 3]) -> [f32; 3] {
+    let max = rgb[0].max(rgb[1]).max(rgb[2]);
+    let min = rgb[0].min(rgb[1]).min(rgb[2]);
+    let delta = max - min;
+    let s = if max == 0.0 { 0.0 } else { delta / max };
+    [hue(rgb, max, delta), s, max]
+}
+
+fn rgb_to_hsl(rgb: [f32; 3]) -> [f32; 3] {
+    let max = rgb[0].max(rgb[1]).max(rgb[2]);
+    let min = rgb[0].min(rgb[1]).min(rgb[2]);
+    let delta = max - min;
+    let l = (max + min) / 2.0;
+    let s = if delta == 0.0 {
+        0.0
+    } else {
+        delta / (1.0 - (2.0 * l - 1.0).abs())
+    };
+    [hue(rgb, max, delta), s, l]
+}
+
+/// Unit RGB from hue, chroma and the amount to add to every channel.
+fn from_hue_chroma(h: f32, c: f32, m: f32) -> [f32; 3] {
+    let hp = h / 60.0;
+    let x = c * (1.0 - (hp.rem_euclid(2.0) - 1.0).abs());
+    let (r, g, b) = match hp as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    [r + m, g + m, b + m]
+}
+
+fn hsv_to_rgb(hsv: [f32; 3]) -> [f32; 3] {
+    let c = hsv[2] * hsv[1];
+    from_hue_chroma(hsv[0], c, hsv[2] - c)
+}
+
+fn hsl_to_rgb(hsl: [f32; 3]) -> [f32; 3] {
+    let c = (1.0 - (2.0 * hsl[2] - 1.0).abs()) * hsl[1];
+    from_hue_chroma(hsl[0], c, hsl[2] - c / 2.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_matches_original_five_levels() {
+        let p = Palette::DEFAULT;
+        assert_eq!(p.color_for_level(0, 5), [0, 0, 0]);
+        assert_eq!(p.color_for_level(1, 5), [170, 50, 50]);
+        assert_eq!(p.color_for_level(2, 5), [255, 128, 0]);
+        assert_eq!(p.color_for_level(3, 5), [255, 255, 0]);
+        assert_eq!(p.color_for_level(4, 5), [255, 255, 255]);
+        assert_eq!(p.color_for_level(9, 5), [255, 255, 255]);
+    }
+
+    #[test]
+    fn any_number_of_levels() {
+        let p = Palette::NIGHT_RED_ONLY;
+        assert_eq!(p.color_for_level(1, 2), [255, 0, 0]);
+        let mut prev = 0;
+        for level in 1..20 {
+            let red = p.color_for_level(level, 20)[0];
+            assert!(red >= prev);
+            prev = red;
+        }
+        assert_eq!(prev, 255);
+    }
+
+    #[test]
+    fn hsv_blend_goes_around_the_wheel() {
+        // red to green through yellow rather than through brown
+        let mid = blend([255, 0, 0], [0, 255, 0], 0.5, ColorSpace::Hsv);
+        assert_eq!(mid, [255, 255, 0]);
+        let mid = blend([255, 0, 0], [0, 255, 0], 0.5, ColorSpace::Rgb);
+        assert_eq!(mid, [128, 128, 0]);
+    }
+
+    #[test]
+    fn hsl_round_trip() {
+        for rgb in [[170, 50, 50], [255, 128, 0], [0, 60, 200], [255, 255, 255]] {
+            assert_eq!(blend(rgb, rgb, 0.3, ColorSpace::Hsl), rgb);
+            assert_eq!(blend(rgb, rgb, 0.7, ColorSpace::Hsv), rgb);
+        }
+    }
+
+    #[test]
+    fn lookup_by_name() {
+        assert_eq!(
+            Palette::named("night-red-only"),
+            Some(Palette::NIGHT_RED_ONLY)
+        );
+        assert_eq!(Palette::named("plaid"), None);
+    }
+
+    #[test]
+    fn calibration_scales_channels() {
+        let cal = Calibration::new(1.0, 0.5, 0.8);
+        assert_eq!(cal.apply([255, 255, 255]), [255, 128, 204]);
+    }
+}
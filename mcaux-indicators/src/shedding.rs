@@ -0,0 +1,283 @@
+// mcaux-indicators/src/shedding.rs
+
+//! Load shedding: turn accessories down while the regulator runs hot or
+//! the battery voltage sags, and back up once things recover.
+//!
+//! The caps apply between the `MomentaryController` and the hardware. The
+//! controller keeps the levels the rider chose, so restoring is just
+//! lifting the caps, and a press while shed still registers. Each trigger
+//! has its own caps, its own thresholds with hysteresis, and hold times in
+//! both directions, so a brief spike doesn't flicker the grips. While a
+//! trigger is active, its indicator alert says why.
+//!
+//! Whether turning loads down cools the regulator depends on the kind. A
+//! series (MOSFET) regulator passes only what the loads draw, so less load
+//! runs it cooler. A shunt regulator burns whatever the loads don't take,
+//! so less load runs it hotter. With a shunt regulator, leave `heat_caps`
+//! empty.
+//!
+//! Feed the capped outputs to the datalogger's `ControllerLog` and the
+//! caps show up in the log.
+
+use momentary::OUTPUTS;
+
+use crate::{AlertKind, IndicatorController};
+
+/// An output index and the highest level it may run at while capped; 0 is
+/// off.
+pub type Cap = (usize, u8);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SheddingPolicy {
+    /// Shed at or above this regulator temperature...
+    pub hot_c: f32,
+    /// ...and restore once it's back below this.
+    pub cool_c: f32,
+    pub heat_caps: Vec<Cap>,
+    /// Shed below this battery voltage...
+    pub low_v: f32,
+    /// ...and restore once it's back at or above this.
+    pub recovered_v: f32,
+    pub voltage_caps: Vec<Cap>,
+    /// How long a condition must hold before shedding.
+    pub shed_after_ms: u32,
+    /// How long it must stay recovered before restoring.
+    pub restore_after_ms: u32,
+}
+
+impl Default for SheddingPolicy {
+    /// Thresholds only; no caps, so nothing is shed until some are given.
+    fn default() -> Self {
+        SheddingPolicy {
+            hot_c: 90.0,
+            cool_c: 80.0,
+            heat_caps: Vec::new(),
+            low_v: 12.2,
+            recovered_v: 12.8,
+            voltage_caps: Vec::new(),
+            shed_after_ms: 5_000,
+            restore_after_ms: 30_000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ShedReason {
+    Heat,
+    Voltage,
+}
+
+impl ShedReason {
+    pub fn alert(self) -> AlertKind {
+        match self {
+            ShedReason::Heat => AlertKind::ShedForHeat,
+            ShedReason::Voltage => AlertKind::ShedForVoltage,
+        }
+    }
+}
+
+/// One trigger's state: shedding or not, and since when the readings have
+/// been saying otherwise.
+#[derive(Clone, Copy, Debug, Default)]
+struct Trigger {
+    active: bool,
+    since: Option<u32>,
+}
+
+impl Trigger {
+    fn update(&mut self, now: u32, bad: bool, recovered: bool, policy: &SheddingPolicy) {
+        let (changing, after) = if self.active {
+            (recovered, policy.restore_after_ms)
+        } else {
+            (bad, policy.shed_after_ms)
+        };
+        if !changing {
+            self.since = None;
+            return;
+        }
+        let since = *self.since.get_or_insert(now);
+        if now.saturating_sub(since) >= after {
+            self.active = !self.active;
+            self.since = None;
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LoadShedding {
+    policy: SheddingPolicy,
+    heat: Trigger,
+    voltage: Trigger,
+}
+
+impl LoadShedding {
+    pub fn new(policy: SheddingPolicy) -> Self {
+        if policy.cool_c > policy.hot_c || policy.recovered_v < policy.low_v {
+            panic!("Load shedding must restore on the safe side of where it sheds");
+        }
+        if let Some((output, _)) = policy
+            .heat_caps
+            .iter()
+            .chain(&policy.voltage_caps)
+            .find(|(output, _)| *output >= OUTPUTS)
+        {
+            panic!("Output {output} doesn't exist to cap");
+        }
+        LoadShedding {
+            policy,
+            heat: Trigger::default(),
+            voltage: Trigger::default(),
+        }
+    }
+
+    /// The latest readings at `now` ms. Either may be None if its sensor
+    /// has nothing new or has failed; that trigger then holds its state.
+    pub fn update(&mut self, now: u32, regulator_c: Option<f32>, battery_v: Option<f32>) {
+        let p = &self.policy;
+        if let Some(c) = regulator_c {
+            self.heat.update(now, c >= p.hot_c, c < p.cool_c, p);
+        }
+        if let Some(v) = battery_v {
+            self.voltage.update(now, v < p.low_v, v >= p.recovered_v, p);
+        }
+    }
+
+    pub fn is_shedding(&self, reason: ShedReason) -> bool {
+        match reason {
+            ShedReason::Heat => self.heat.active,
+            ShedReason::Voltage => self.voltage.active,
+        }
+    }
+
+    /// The controller's outputs, capped by whatever is being shed. Drive
+    /// the hardware with these.
+    pub fn apply(&self, outputs: [u8; OUTPUTS]) -> [u8; OUTPUTS] {
+        let mut capped = outputs;
+        let caps = [
+            (self.heat.active, &self.policy.heat_caps),
+            (self.voltage.active, &self.policy.voltage_caps),
+        ];
+        for (_, caps) in caps.into_iter().filter(|(active, _)| *active) {
+            for &(output, max) in caps {
+                capped[output] = capped[output].min(max);
+            }
+        }
+        capped
+    }
+
+    /// Raise the alert for each reason being shed for, clear the others.
+    pub fn update_indicators(&self, indicators: &mut IndicatorController) {
+        for reason in [ShedReason::Heat, ShedReason::Voltage] {
+            if self.is_shedding(reason) {
+                indicators.raise_alert(reason.alert());
+            } else {
+                indicators.clear_alert(reason.alert());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use momentary::{MomentaryController, SWITCHES};
+
+    const LIGHTS: usize = 0;
+    const GRIPS: usize = 1;
+
+    fn policy() -> SheddingPolicy {
+        SheddingPolicy {
+            heat_caps: vec![(GRIPS, 1)],
+            voltage_caps: vec![(LIGHTS, 0), (GRIPS, 1)],
+            ..SheddingPolicy::default()
+        }
+    }
+
+    /// Lights on, grips on high
+    fn switched_on() -> (MomentaryController, [u8; OUTPUTS]) {
+        let mut controller = MomentaryController::default();
+        controller.add_switch(2);
+        controller.add_switch(4);
+        let mut outputs = [0; OUTPUTS];
+        for (switch, presses) in [(LIGHTS, 1), (GRIPS, 3)] {
+            for _ in 0..presses {
+                let mut switches = [false; SWITCHES];
+                switches[switch] = true;
+                controller.report(switches);
+                outputs = controller.report([false; SWITCHES]).0;
+            }
+        }
+        (controller, outputs)
+    }
+
+    #[test]
+    fn sag_sheds_then_restores() {
+        let (mut controller, outputs) = switched_on();
+        assert_eq!(outputs[..2], [1, 3]);
+        let mut shed = LoadShedding::new(policy());
+        let mut indicators = IndicatorController::default();
+        let mut seen = Vec::new();
+        for (t, volts) in [
+            (0, 13.9),
+            (1_000, 12.0),
+            (5_000, 12.0),
+            (6_000, 12.1),
+            // better, but not yet recovered
+            (10_000, 12.6),
+            (20_000, 13.0),
+            (49_000, 13.0),
+            (50_000, 13.1),
+        ] {
+            shed.update(t, Some(80.0), Some(volts));
+            shed.update_indicators(&mut indicators);
+            let capped = shed.apply(controller.report([false; SWITCHES]).0);
+            seen.push((
+                t,
+                capped[LIGHTS],
+                capped[GRIPS],
+                indicators.alerts().showing(),
+            ));
+        }
+        let voltage = Some(AlertKind::ShedForVoltage);
+        assert_eq!(
+            seen,
+            [
+                (0, 1, 3, None),
+                (1_000, 1, 3, None),
+                (5_000, 1, 3, None),
+                (6_000, 0, 1, voltage),
+                (10_000, 0, 1, voltage),
+                (20_000, 0, 1, voltage),
+                (49_000, 0, 1, voltage),
+                (50_000, 1, 3, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn heat_caps_only_its_outputs_and_blips_do_nothing() {
+        let (_, outputs) = switched_on();
+        let mut shed = LoadShedding::new(policy());
+        // a second over, then under: not long enough to shed
+        shed.update(0, Some(95.0), None);
+        shed.update(1_000, Some(85.0), None);
+        shed.update(6_000, Some(95.0), None);
+        assert!(!shed.is_shedding(ShedReason::Heat));
+
+        shed.update(11_000, Some(95.0), None);
+        assert!(shed.is_shedding(ShedReason::Heat));
+        assert!(!shed.is_shedding(ShedReason::Voltage));
+        assert_eq!(shed.apply(outputs)[..2], [1, 1]);
+        // grips already low stay where they are
+        let mut low = outputs;
+        low[GRIPS] = 0;
+        assert_eq!(shed.apply(low)[..2], [1, 0]);
+
+        // between the thresholds holds; below the cool one restores
+        shed.update(50_000, Some(85.0), None);
+        assert!(shed.is_shedding(ShedReason::Heat));
+        shed.update(60_000, Some(75.0), None);
+        shed.update(90_000, Some(75.0), None);
+        assert_eq!(shed.apply(outputs), outputs);
+    }
+}
@@ -0,0 +1,99 @@
+// mcaux-indicators/src/transition.rs
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+#[cfg(target_arch = "wasm32")]
+use web_time::Duration;
+
+use crate::palette::{ColorSpace, blend};
+
+/// Shape of a fade over its duration.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Easing {
+    Linear,
+    /// Slow start and slow finish (smoothstep).
+    #[default]
+    EaseInOut,
+    /// Fast start that settles gently; reads as even to the eye, which
+    /// is far more sensitive to change at low brightness.
+    Exponential,
+}
+
+impl Easing {
+    /// Map linear progress `t` (0.0 to 1.0) onto eased progress.
+    pub fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::EaseInOut => t * t * (3.0 - 2.0 * t),
+            Easing::Exponential => (1.0 - (-10.0 * t).exp2()) / (1.0 - (-10.0f32).exp2()),
+        }
+    }
+}
+
+/// A crossfade of the RGB LED from one color to another.
+#[derive(Clone, Copy, Debug)]
+pub struct Crossfade {
+    from: [u8; 3],
+    to: [u8; 3],
+    elapsed: Duration,
+}
+
+impl Crossfade {
+    pub fn new(from: [u8; 3], to: [u8; 3]) -> Self {
+        Crossfade {
+            from,
+            to,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    pub fn target(&self) -> [u8; 3] {
+        self.to
+    }
+
+    pub fn advance(&mut self, delta: Duration) {
+        self.elapsed += delta;
+    }
+
+    pub fn is_done(&self, duration: Duration) -> bool {
+        self.elapsed >= duration
+    }
+
+    /// Color shown at this point in the fade.
+    pub fn color(&self, duration: Duration, easing: Easing, space: ColorSpace) -> [u8; 3] {
+        if self.is_done(duration) {
+            return self.to;
+        }
+        let t = self.elapsed.as_secs_f32() / duration.as_secs_f32();
+        blend(self.from, self.to, easing.apply(t), space)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn easings_start_and_end_in_place() {
+        for e in [Easing::Linear, Easing::EaseInOut, Easing::Exponential] {
+            assert_eq!(e.apply(0.0), 0.0);
+            assert!((e.apply(1.0) - 1.0).abs() < 1e-6);
+            assert!(e.apply(0.25) < e.apply(0.75));
+        }
+        assert_eq!(Easing::EaseInOut.apply(0.5), 0.5);
+        assert!(Easing::Exponential.apply(0.5) > 0.9);
+    }
+
+    #[test]
+    fn crossfade_progresses() {
+        let d = Duration::from_millis(200);
+        let mut f = Crossfade::new([0, 0, 0], [200, 100, 0]);
+        assert_eq!(f.color(d, Easing::Linear, ColorSpace::Rgb), [0, 0, 0]);
+        f.advance(Duration::from_millis(100));
+        assert_eq!(f.color(d, Easing::Linear, ColorSpace::Rgb), [100, 50, 0]);
+        f.advance(Duration::from_millis(150));
+        assert!(f.is_done(d));
+        assert_eq!(f.color(d, Easing::Linear, ColorSpace::Rgb), [200, 100, 0]);
+    }
+}
@@ -6,8 +6,10 @@ use std::time::{Duration, Instant};
 #[cfg(target_arch = "wasm32")]
 use web_time::{Duration, Instant};
 
-const SWITCHES: usize = 16;
-const OUTPUTS: usize = 16;
+/// Switch inputs in every `report()`
+pub const SWITCHES: usize = 16;
+/// Outputs in every `report()`, used or not
+pub const OUTPUTS: usize = 16;
 
 fn report_from_none(incoming: [bool; 16]) -> (SwitchState, Option<StateDetail>) {
     if let Some(first_idx) = incoming